@@ -0,0 +1,109 @@
+//! Command-line argument parsing.
+
+use std::{ffi::OsString, path::PathBuf};
+
+pub const USAGE: &str = "\
+Usage: toonmux [OPTIONS]
+
+Options:
+    --config <path>     Use the config file at <path>, instead of the default
+                        one.
+    --profile <name>    Start out using the profile named <name>.
+    --start-collapsed   Start with the UI collapsed.
+    --no-save           Never write to the config file.
+    --socket <path>     Listen for control connections at <path>, instead of
+                        at $XDG_RUNTIME_DIR/toonmux.sock.
+    --no-socket         Don't listen for control connections at all.
+    --dump-config       Print the effective config (after any migrations, and
+                        after applying --profile), and then exit.
+    --check-config      Check that the config file is valid, and then exit.
+                        Exits with a non-zero status if it isn't.
+    -h, --help          Print this help, and then exit.
+    -V, --version       Print the version of toonmux, and then exit.";
+
+#[derive(Debug, Default)]
+pub struct Options {
+    pub config_path: Option<PathBuf>,
+    pub profile: Option<String>,
+    pub start_collapsed: bool,
+    pub no_save: bool,
+    pub socket_path: Option<PathBuf>,
+    pub no_socket: bool,
+    pub mode: Mode,
+}
+
+/// What toonmux should do, as opposed to how it should do it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Actually run the GUI.
+    #[default]
+    Run,
+    DumpConfig,
+    CheckConfig,
+    Help,
+    Version,
+}
+
+impl Options {
+    /// Parses `args`, which should **not** include the name of the program.
+    pub fn parse<I: IntoIterator<Item = OsString>>(
+        args: I,
+    ) -> Result<Self, String> {
+        let mut opts = Self::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let arg = arg.into_string().map_err(|arg| {
+                format!("Invalid argument: {}", arg.to_string_lossy())
+            })?;
+            // Support both `--opt value` and `--opt=value`.
+            let (name, inline_value) = match arg.split_once('=') {
+                Some((name, value)) if name.starts_with("--") => {
+                    (name, Some(value.to_owned()))
+                }
+                _ => (arg.as_str(), None),
+            };
+
+            let mut value = || {
+                inline_value
+                    .clone()
+                    .or_else(|| args.next()?.into_string().ok())
+                    .ok_or_else(|| format!("{} requires a value", name))
+            };
+
+            match name {
+                "--config" => opts.config_path = Some(value()?.into()),
+                "--profile" => opts.profile = Some(value()?),
+                "--start-collapsed" => opts.start_collapsed = true,
+                "--no-save" => opts.no_save = true,
+                "--socket" => opts.socket_path = Some(value()?.into()),
+                "--no-socket" => opts.no_socket = true,
+                "--dump-config" => opts.set_mode(Mode::DumpConfig, name)?,
+                "--check-config" => opts.set_mode(Mode::CheckConfig, name)?,
+                "-h" | "--help" => opts.set_mode(Mode::Help, name)?,
+                "-V" | "--version" => opts.set_mode(Mode::Version, name)?,
+                _ => {
+                    return Err(format!(
+                        "Unrecognized argument: {}\n\n{}",
+                        arg, USAGE,
+                    ))
+                }
+            }
+        }
+
+        Ok(opts)
+    }
+
+    fn set_mode(&mut self, mode: Mode, arg: &str) -> Result<(), String> {
+        if self.mode != Mode::Run && self.mode != mode {
+            return Err(format!(
+                "{} can't be combined with the other options that make \
+                 toonmux exit immediately",
+                arg,
+            ));
+        }
+        self.mode = mode;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,206 @@
+//! Noticing mouse clicks in the windows of controllers that are mirrored, so
+//! that they can be repeated in the windows of the controllers that mirror
+//! them.  Clicks are caught with passive button grabs, and then immediately
+//! replayed, so that the game gets them just like it would have anyways.
+
+use crate::{discover, output::Click};
+use std::{
+    os::{
+        raw::{c_int, c_uint},
+        unix::io::RawFd,
+    },
+    ptr,
+    sync::Mutex,
+};
+use x11::xlib::{self, Window};
+
+/// How long (in microseconds) after we make a click ourselves that we still
+/// expect to catch it.
+const OWN_CLICK_TIMEOUT: i64 = 500_000;
+
+#[derive(Debug)]
+pub struct ClickWatch {
+    display: *mut xlib::Display,
+    /// The windows that we have button grabs on.
+    grabbed: Mutex<Vec<Window>>,
+    /// Clicks that we're making ourselves, in grabbed windows, which are not
+    /// to be caught (see `expect`).  Each is a window & button, along with
+    /// when (in `glib::monotonic_time()` microseconds) it was made.
+    own: Mutex<Vec<(Window, c_uint, i64)>>,
+}
+
+impl ClickWatch {
+    /// Returns `None` iff we cannot open a connection to the X server.
+    pub fn new() -> Option<Self> {
+        let display = unsafe { xlib::XOpenDisplay(ptr::null()) };
+        if display.is_null() {
+            None
+        } else {
+            Some(Self {
+                display,
+                grabbed: Default::default(),
+                own: Default::default(),
+            })
+        }
+    }
+
+    /// The file descriptor of our X connection, which becomes readable
+    /// whenever there are (probably) events waiting for `next_click`.
+    #[inline(always)]
+    pub fn fd(&self) -> RawFd {
+        unsafe { xlib::XConnectionNumber(self.display) }
+    }
+
+    /// Replaces whatever windows currently have their clicks caught with
+    /// `windows`.  Grabbing can fail, e.g. if the window manager already
+    /// grabs the buttons of client windows itself, in which case that window
+    /// just doesn't get its clicks caught.
+    pub fn grab<I: IntoIterator<Item = Window>>(&self, windows: I) {
+        let mut windows: Vec<_> =
+            windows.into_iter().filter(|&w| w != 0).collect();
+        windows.sort_unstable();
+        windows.dedup();
+
+        // Getting a lock on the grabbed windows mutex.
+        let mut grabbed = self.grabbed.lock().unwrap();
+
+        discover::with_errors_ignored(self.display, || unsafe {
+            for window in grabbed.iter().filter(|w| !windows.contains(w)) {
+                xlib::XUngrabButton(
+                    self.display,
+                    xlib::AnyButton as c_uint,
+                    xlib::AnyModifier,
+                    *window,
+                );
+            }
+            for window in windows.iter().filter(|w| !grabbed.contains(w)) {
+                // The pointer is frozen until we replay the click, so that
+                // nothing else can happen in between.
+                xlib::XGrabButton(
+                    self.display,
+                    xlib::AnyButton as c_uint,
+                    xlib::AnyModifier,
+                    *window,
+                    xlib::False,
+                    xlib::ButtonPressMask as c_uint,
+                    xlib::GrabModeSync,
+                    xlib::GrabModeAsync,
+                    0,
+                    0,
+                );
+            }
+        });
+
+        *grabbed = windows;
+
+        // Relinquishing lock on the grabbed windows mutex.
+    }
+
+    /// The width & height of `window`, if it (still) exists.
+    pub fn size(&self, window: Window) -> Option<(i32, i32)> {
+        discover::with_errors_ignored(self.display, || {
+            let mut root = 0;
+            let (mut x, mut y): (c_int, c_int) = (0, 0);
+            let (mut width, mut height): (c_uint, c_uint) = (0, 0);
+            let (mut border, mut depth): (c_uint, c_uint) = (0, 0);
+
+            let status = unsafe {
+                xlib::XGetGeometry(
+                    self.display,
+                    window,
+                    &mut root,
+                    &mut x,
+                    &mut y,
+                    &mut width,
+                    &mut height,
+                    &mut border,
+                    &mut depth,
+                )
+            };
+
+            (status != 0).then_some((width as i32, height as i32))
+        })
+    }
+
+    /// Lets us know that we're about to make `click` ourselves.  Clicks that
+    /// are made with XTEST look just like real ones to the X server, so if
+    /// `click`'s window is grabbed (e.g. because it belongs to a controller
+    /// in the middle of a chain of mirrors), then it would otherwise be
+    /// caught, and mirrored all over again.
+    pub fn expect(&self, click: &Click) {
+        if self.grabbed.lock().unwrap().contains(&click.window) {
+            self.own.lock().unwrap().push((
+                click.window,
+                click.button,
+                glib::monotonic_time(),
+            ));
+        }
+    }
+
+    /// Whether or not a click of `button` in `window` is one that we made
+    /// ourselves (see `expect`).  If it is, then it's no longer expected.
+    fn is_own(&self, window: Window, button: c_uint) -> bool {
+        let now = glib::monotonic_time();
+
+        // Getting a lock on the own clicks mutex.
+        let mut own = self.own.lock().unwrap();
+
+        // Clicks that went somewhere else (e.g. because something was in the
+        // way) are never going to be caught.
+        own.retain(|&(_, _, at)| now - at < OWN_CLICK_TIMEOUT);
+        if let Some(i) =
+            own.iter().position(|&(w, b, _)| w == window && b == button)
+        {
+            own.remove(i);
+
+            true
+        } else {
+            false
+        }
+
+        // Relinquishing lock on the own clicks mutex.
+    }
+
+    pub fn next_click(&self) -> Option<Click> {
+        unsafe {
+            while xlib::XPending(self.display) > 0 {
+                let mut event = xlib::XEvent { pad: [0; 24] };
+                xlib::XNextEvent(self.display, &mut event);
+                if event.get_type() != xlib::ButtonPress {
+                    continue;
+                }
+
+                // Hand the click on to the game (or whoever else would have
+                // gotten it), and unfreeze the pointer.
+                let button = event.button;
+                xlib::XAllowEvents(
+                    self.display,
+                    xlib::ReplayPointer,
+                    button.time,
+                );
+                xlib::XFlush(self.display);
+
+                if self.grabbed.lock().unwrap().contains(&button.window)
+                    && !self.is_own(button.window, button.button)
+                {
+                    return Some(Click {
+                        window: button.window,
+                        x: button.x,
+                        y: button.y,
+                        button: button.button,
+                    });
+                }
+            }
+        }
+
+        None
+    }
+}
+
+impl Drop for ClickWatch {
+    fn drop(&mut self) {
+        unsafe {
+            xlib::XCloseDisplay(self.display);
+        }
+    }
+}
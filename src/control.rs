@@ -0,0 +1,718 @@
+//! A local control socket, so that external tools (scripts, status bars,
+//! stream decks, &c.) can drive toonmux.  The protocol is newline-delimited
+//! JSON: every line that a client sends is a request, which gets exactly one
+//! response line in return, e.g.
+//!
+//! ```text
+//! → {"id": 1, "cmd": "set_mirror", "controller": 1, "mirror": 0}
+//! ← {"id": 1, "ok": true, "result": null}
+//! ```
+//!
+//! Clients that have sent a `subscribe` request additionally get an event
+//! line (`{"event": "state", ...}`) whenever the state changes.
+
+use crate::{
+    key::{Chord, Mods},
+    launch::Process,
+    stagger::Delay,
+    state::{SendMode, State},
+};
+use gdk::keys::Key;
+use glib::{ControlFlow, IOCondition};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    io::{self, Read, Write},
+    net::Shutdown,
+    os::unix::{
+        fs::{DirBuilderExt, PermissionsExt},
+        io::AsRawFd,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    process,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// Handles a single request, returning its result.
+type Handler = dyn Fn(Request) -> Result<Value, String>;
+
+/// Clients that fall this far behind on reading what we send them (in
+/// bytes) get disconnected.
+const MAX_OUTBOX_LEN: usize = 1 << 20;
+
+/// Clients that send a request longer than this (in bytes) get disconnected.
+const MAX_LINE_LEN: usize = 1 << 16;
+
+#[derive(Debug)]
+pub struct Server {
+    /// `None` iff the control socket is disabled (or couldn't be set up).
+    listener: Option<(UnixListener, PathBuf)>,
+    clients: Mutex<Vec<Arc<Client>>>,
+}
+
+#[derive(Debug)]
+struct Client {
+    stream: UnixStream,
+    /// Whatever we've read that doesn't make up a full line yet.
+    buf: Mutex<Vec<u8>>,
+    /// Whatever we haven't managed to write yet.  The stream is
+    /// non-blocking, so that a client that doesn't read can't hold up the
+    /// UI.
+    outbox: Mutex<Vec<u8>>,
+    /// Whether or not we're waiting for the stream to become writable, so
+    /// that the rest of `outbox` can be written.
+    flushing: AtomicBool,
+    subscribed: AtomicBool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum Request {
+    /// Get a `Snapshot` of the current state.
+    List,
+    /// Make `controller` mirror `mirror`, or nothing if `mirror` is `null`.
+    SetMirror {
+        controller: usize,
+        mirror: Option<usize>,
+    },
+    /// Turn mirroring on or off, or toggle it if `on` is `null`.
+    Mirroring { on: Option<bool> },
+    /// Turn transitive mirroring (following chains of mirrors) on or off, or
+    /// toggle it if `on` is `null`.  It can't be turned on while mirrors
+    /// loop.
+    TransitiveMirroring { on: Option<bool> },
+    /// Turn click mirroring on or off for `controller`.
+    MirrorClicks { controller: usize, on: bool },
+    /// Make `controller` copy (or not) one of the actions of its mirror.
+    MirrorAction {
+        controller: usize,
+        action: String,
+        on: bool,
+    },
+    /// Hold back the keys that `controller` gets from its mirror by between
+    /// `min` & `max` milliseconds (just `min` if `max` is `null`).
+    MirrorDelay {
+        controller: usize,
+        min: u64,
+        max: Option<u64>,
+    },
+    /// Bind (or, if `key` is `null`, unbind) one of `controller`'s actions,
+    /// or one of the main bindings if `controller` is `null`.  Only
+    /// controller bindings can have `mods`.
+    Bind {
+        controller: Option<usize>,
+        action: String,
+        key: Option<KeySpec>,
+        #[serde(default)]
+        mods: Mods,
+    },
+    /// Associate `controller` with the X11 window `window` (`0` for none).
+    Attach { controller: usize, window: u64 },
+    /// Attach every game window that isn't attached yet to the controllers
+    /// that have no window, in order.
+    AttachAll,
+    /// Find the windows that controllers had before toonmux was restarted
+    /// again, and reattach them.
+    RestoreWindows,
+    /// (Re)launch `controller`'s game client, using its launch command.
+    Launch { controller: usize },
+    /// Arrange the attached windows using the layout named `name`, or put
+    /// them back where they were before if `name` is `null`.
+    Layout { name: Option<String> },
+    /// Start recording the keys that are routed to `controller`'s window, as
+    /// the macro named `name`.
+    RecordMacro { controller: usize, name: String },
+    /// Stop recording, and save the macro.
+    StopRecording,
+    /// Play the macro named `name` back (unless it's already playing).
+    PlayMacro { name: String },
+    /// Stop whatever macro is playing, releasing any keys that it holds down.
+    StopMacro,
+    /// Bind (or, if `key` is `null`, unbind) the key that plays the macro
+    /// named `name`.
+    BindMacro { name: String, key: Option<KeySpec> },
+    /// Feed a key event into the router, as if it had been typed into
+    /// toonmux.
+    Key {
+        key: KeySpec,
+        /// The modifiers that are held down while `key` is pressed.
+        #[serde(default)]
+        mods: Mods,
+        #[serde(default)]
+        kind: KeyEventKind,
+    },
+    /// Start getting state events on this connection.
+    Subscribe,
+    /// Get (and forget) everything that the "recording" output backend has
+    /// recorded so far.
+    TakeRecorded,
+}
+
+/// Keys can be given either by their X keysym name (e.g. `"w"`, `"F8"`, or
+/// `"Control_L"`) or as raw keysym values.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum KeySpec {
+    Code(u32),
+    Name(String),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyEventKind {
+    Press,
+    Release,
+    /// Press immediately followed by release.
+    #[default]
+    Tap,
+}
+
+/// Everything that a client might want to know about the current state.
+#[derive(Debug, Serialize)]
+pub struct Snapshot {
+    pub profile: String,
+    pub mirroring: bool,
+    pub transitive_mirroring: bool,
+    pub capturing: bool,
+    /// The window layout that was applied last, if any.
+    pub layout: Option<String>,
+    pub macros: Vec<MacroInfo>,
+    /// The name of the macro that is being recorded, if any.
+    pub recording: Option<String>,
+    /// The name of the macro that is being played back, if any.
+    pub playing: Option<String>,
+    /// In the same order as the controllers' bindings are shown in the UI.
+    pub actions: Vec<ActionInfo>,
+    /// The actions' main keys, along with the main bindings that aren't
+    /// actions.
+    pub main_bindings: BTreeMap<String, Option<String>>,
+    pub controllers: Vec<ControllerInfo>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ActionInfo {
+    pub name: String,
+    pub mode: SendMode,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MacroInfo {
+    pub name: String,
+    pub key: Option<String>,
+    pub controller: usize,
+    pub steps: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ControllerInfo {
+    pub index: usize,
+    /// `0` iff there is no window.
+    pub window: u64,
+    pub mirror: Option<usize>,
+    pub talking: bool,
+    pub mirror_clicks: bool,
+    /// The names of the actions that are copied from the mirror.
+    pub mirror_actions: Vec<String>,
+    pub mirror_delay: Delay,
+    pub keep_alive: bool,
+    /// What became of the last game client that this controller launched,
+    /// if any.
+    pub process: Option<String>,
+    /// Keyed by action name.
+    pub bindings: BTreeMap<String, Option<String>>,
+}
+
+impl Server {
+    /// Sets up (but doesn't start accepting connections on) a control socket
+    /// at `path`.  Failing to do so isn't fatal; we just go without one.
+    pub fn new(path: Option<PathBuf>) -> Self {
+        let listener = path.and_then(|path| match listen(&path) {
+            Ok(listener) => Some((listener, path)),
+            Err(e) => {
+                eprintln!(
+                    "Not listening for control connections on {}: {}",
+                    path.display(),
+                    e,
+                );
+
+                None
+            }
+        });
+
+        Self {
+            listener,
+            clients: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Starts accepting connections, each request from which is passed to
+    /// `handler`.
+    pub fn start<H>(self: &Arc<Self>, handler: H)
+    where
+        H: Fn(Request) -> Result<Value, String> + 'static,
+    {
+        let (listener, path) = match self.listener {
+            Some(ref l) => l,
+            None => return,
+        };
+        println!("Listening for control connections on {}...", path.display());
+
+        let handler: Rc<Handler> = Rc::new(handler);
+        let server = Arc::clone(self);
+        glib::source::unix_fd_add_local(
+            listener.as_raw_fd(),
+            IOCondition::IN,
+            move |_, _| {
+                if let Some((ref listener, _)) = server.listener {
+                    match listener.accept() {
+                        Ok((stream, _)) => {
+                            server.add_client(stream, Rc::clone(&handler))
+                        }
+                        Err(ioe) => eprintln!(
+                            "Accepting a control connection failed with: {}",
+                            ioe,
+                        ),
+                    }
+                }
+
+                ControlFlow::Continue
+            },
+        );
+    }
+
+    /// Removes the socket file.
+    pub fn shut_down(&self) {
+        if let Some((_, ref path)) = self.listener {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    /// Sends the current state to every subscriber.
+    pub fn notify(&self, state: &State) {
+        // Getting a lock on the clients mutex.
+        let mut clients = self.clients.lock().unwrap();
+
+        if !clients.iter().any(|c| c.subscribed.load(Ordering::SeqCst)) {
+            return;
+        }
+
+        let mut event = serde_json::to_value(Snapshot::new(state)).unwrap();
+        event["event"] = "state".into();
+        let line = format!("{}\n", event);
+
+        // Subscribers that can't keep up get disconnected.
+        clients.retain(|c| {
+            if !c.subscribed.load(Ordering::SeqCst) || c.send(line.as_bytes())
+            {
+                true
+            } else {
+                let _ = c.stream.shutdown(Shutdown::Both);
+
+                false
+            }
+        });
+
+        // Relinquishing lock on the clients mutex.
+    }
+
+    fn add_client(self: &Arc<Self>, stream: UnixStream, handler: Rc<Handler>) {
+        if let Err(ioe) = stream.set_nonblocking(true) {
+            eprintln!("Setting up a control connection failed with: {}", ioe);
+
+            return;
+        }
+
+        let client = Arc::new(Client {
+            stream,
+            buf: Mutex::new(Vec::new()),
+            outbox: Mutex::new(Vec::new()),
+            flushing: AtomicBool::new(false),
+            subscribed: AtomicBool::new(false),
+        });
+        self.clients.lock().unwrap().push(Arc::clone(&client));
+
+        let server = Arc::clone(self);
+        glib::source::unix_fd_add_local(
+            client.stream.as_raw_fd(),
+            IOCondition::IN | IOCondition::HUP | IOCondition::ERR,
+            move |_, _| {
+                if client.service(&*handler) {
+                    ControlFlow::Continue
+                } else {
+                    // Anything still waiting to be written goes too.
+                    let _ = client.stream.shutdown(Shutdown::Both);
+                    server
+                        .clients
+                        .lock()
+                        .unwrap()
+                        .retain(|c| !Arc::ptr_eq(c, &client));
+
+                    ControlFlow::Break
+                }
+            },
+        );
+    }
+}
+
+impl Client {
+    /// Reads whatever is available, and responds to every complete request.
+    /// Returns `false` iff the connection should be closed.
+    fn service(self: &Arc<Self>, handler: &Handler) -> bool {
+        let mut chunk = [0; 4096];
+        let n = match (&self.stream).read(&mut chunk) {
+            Ok(0) => return false,
+            Ok(n) => n,
+            Err(ioe)
+                if matches!(
+                    ioe.kind(),
+                    io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock,
+                ) =>
+            {
+                return true
+            }
+            Err(_) => return false,
+        };
+
+        let lines = {
+            // Getting a lock on the read buffer mutex.
+            let mut buf = self.buf.lock().unwrap();
+
+            buf.extend_from_slice(&chunk[..n]);
+            let complete =
+                buf.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+            let lines = buf.drain(..complete).collect::<Vec<_>>();
+            // Whatever is left is a line that isn't complete yet.  Lines
+            // that are completed by the read that takes them over the limit
+            // are caught below.
+            if buf.len() > MAX_LINE_LEN {
+                return false;
+            }
+
+            lines
+
+            // Relinquishing lock on the read buffer mutex.
+        };
+
+        lines
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.iter().all(u8::is_ascii_whitespace))
+            .all(|line| {
+                if line.len() > MAX_LINE_LEN {
+                    return false;
+                }
+                let response = self.respond(line, handler);
+
+                self.send(format!("{}\n", response).as_bytes())
+            })
+    }
+
+    /// Writes `bytes` after whatever is still waiting to be written, without
+    /// blocking.  Whatever can't be written right away is written once the
+    /// stream becomes writable.  Returns `false` iff the connection should be
+    /// closed.
+    fn send(self: &Arc<Self>, bytes: &[u8]) -> bool {
+        {
+            // Getting a lock on the outbox mutex.
+            let mut outbox = self.outbox.lock().unwrap();
+
+            if outbox.len() + bytes.len() > MAX_OUTBOX_LEN {
+                return false;
+            }
+            outbox.extend_from_slice(bytes);
+
+            // Relinquishing lock on the outbox mutex.
+        }
+
+        if self.flushing.load(Ordering::SeqCst) {
+            return true;
+        }
+        match self.flush() {
+            Some(true) => true,
+            Some(false) => {
+                self.flushing.store(true, Ordering::SeqCst);
+                let client = Arc::clone(self);
+                glib::source::unix_fd_add_local(
+                    self.stream.as_raw_fd(),
+                    IOCondition::OUT | IOCondition::HUP | IOCondition::ERR,
+                    move |_, _| match client.flush() {
+                        Some(false) => ControlFlow::Continue,
+                        done => {
+                            if done.is_none() {
+                                let _ = client.stream.shutdown(Shutdown::Both);
+                            }
+                            client.flushing.store(false, Ordering::SeqCst);
+
+                            ControlFlow::Break
+                        }
+                    },
+                );
+
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Writes as much of the outbox as can be written without blocking.
+    /// Returns whether or not the outbox is now empty, or `None` if the
+    /// connection is broken.
+    fn flush(&self) -> Option<bool> {
+        // Getting a lock on the outbox mutex.
+        let mut outbox = self.outbox.lock().unwrap();
+
+        let mut written = 0;
+        let result = loop {
+            if written == outbox.len() {
+                break Some(true);
+            }
+
+            match (&self.stream).write(&outbox[written..]) {
+                Ok(0) => break None,
+                Ok(n) => written += n,
+                Err(ioe) if ioe.kind() == io::ErrorKind::Interrupted => (),
+                Err(ioe) if ioe.kind() == io::ErrorKind::WouldBlock => {
+                    break Some(false)
+                }
+                Err(_) => break None,
+            }
+        };
+        outbox.drain(..written);
+
+        result
+
+        // Relinquishing lock on the outbox mutex.
+    }
+
+    fn respond(&self, line: &[u8], handler: &Handler) -> Value {
+        let value: Value = match serde_json::from_slice(line) {
+            Ok(value) => value,
+            Err(e) => return json!({ "ok": false, "error": e.to_string() }),
+        };
+        let id = value.get("id").cloned();
+
+        let result = serde_json::from_value(value)
+            .map_err(|e| e.to_string())
+            .and_then(|request| match request {
+                Request::Subscribe => {
+                    self.subscribed.store(true, Ordering::SeqCst);
+
+                    Ok(Value::Null)
+                }
+                request => handler(request),
+            });
+
+        let mut response = match result {
+            Ok(result) => json!({ "ok": true, "result": result }),
+            Err(e) => json!({ "ok": false, "error": e }),
+        };
+        if let Some(id) = id {
+            response["id"] = id;
+        }
+
+        response
+    }
+}
+
+impl KeySpec {
+    pub fn to_key(&self) -> Result<Key, String> {
+        match self {
+            Self::Code(code) => Ok(Key::from(*code)),
+            Self::Name(name) if name.is_empty() => Ok(Key::from(0)),
+            Self::Name(name) => {
+                let key = Key::from_name(name);
+                if key == gdk::keys::constants::VoidSymbol {
+                    Err(format!("There is no key named \"{}\".", name))
+                } else {
+                    Ok(key)
+                }
+            }
+        }
+    }
+}
+
+impl Snapshot {
+    pub fn new(state: &State) -> Self {
+        // Getting a read lock on the action state reader-writer lock.
+        let actions = state.routing.actions.read().unwrap();
+        // Getting a read lock on the controller state reader-writer lock.
+        let ctls = state.routing.controllers.read().unwrap();
+
+        let controllers = ctls
+            .iter()
+            .enumerate()
+            .map(|(index, ctl)| ControllerInfo {
+                index,
+                window: ctl.window.load(Ordering::SeqCst),
+                mirror: Some(ctl.mirror.load(Ordering::SeqCst))
+                    .filter(|&m| m != usize::MAX),
+                talking: state.routing.talking.iter().any(|i| i == index),
+                mirror_clicks: ctl.mirror_clicks.load(Ordering::SeqCst),
+                mirror_actions: actions
+                    .iter()
+                    .enumerate()
+                    .filter(|&(action_ix, _)| ctl.copies(action_ix))
+                    .map(|(_, a)| a.name.clone())
+                    .collect(),
+                mirror_delay: *ctl.mirror_delay.read().unwrap(),
+                keep_alive: ctl.keep_alive.load(Ordering::SeqCst),
+                process: {
+                    let process = ctl.process.lock().unwrap();
+                    if let Process::None = *process {
+                        None
+                    } else {
+                        Some(process.to_string())
+                    }
+                },
+                bindings: actions
+                    .iter()
+                    .zip(ctl.bindings.iter())
+                    .map(|(a, b)| {
+                        (
+                            a.name.clone(),
+                            chord_json_name(b.load(Ordering::SeqCst)),
+                        )
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        // Relinquishing read lock on the controller state reader-writer lock.
+        drop(ctls);
+
+        let mut main_bindings: BTreeMap<_, _> = actions
+            .iter()
+            .map(|a| (a.name.clone(), key_json_name(a.key())))
+            .collect();
+        main_bindings.insert(
+            "toggle_mirroring".to_owned(),
+            key_json_name(state.routing.main_bindings.toggle_mirroring()),
+        );
+        main_bindings.insert(
+            "cycle_profile".to_owned(),
+            key_json_name(state.routing.main_bindings.cycle_profile()),
+        );
+        main_bindings.insert(
+            "cycle_layout".to_owned(),
+            key_json_name(state.routing.main_bindings.cycle_layout()),
+        );
+
+        Self {
+            profile: state.active_profile_name(),
+            mirroring: state.routing.mirroring.load(Ordering::SeqCst),
+            transitive_mirroring: state
+                .routing
+                .transitive_mirroring
+                .load(Ordering::SeqCst),
+            capturing: state.capturing.load(Ordering::SeqCst),
+            layout: state.active_layout_name(),
+            macros: state
+                .routing
+                .macros
+                .read()
+                .unwrap()
+                .iter()
+                .map(|m| MacroInfo {
+                    name: m.name.clone(),
+                    key: key_json_name(Key::from(m.key)),
+                    controller: m.controller,
+                    steps: m.steps.len(),
+                })
+                .collect(),
+            recording: state.recording_macro_name(),
+            playing: state.playing_macro_name(),
+            actions: actions
+                .iter()
+                .map(|a| ActionInfo {
+                    name: a.name.clone(),
+                    mode: a.mode,
+                })
+                .collect(),
+            main_bindings,
+            controllers,
+        }
+
+        // Relinquishing read lock on the action state reader-writer lock.
+    }
+}
+
+/// The path that the control socket lives at, unless otherwise specified.
+pub fn default_socket_path() -> Option<PathBuf> {
+    env::var_os("XDG_RUNTIME_DIR")
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| Path::new(&dir).join("toonmux.sock"))
+}
+
+/// The name that keys are given in the protocol, which is the same name that
+/// `KeySpec` accepts.
+pub fn key_json_name(key: Key) -> Option<String> {
+    if *key == 0 {
+        None
+    } else {
+        key.name().map(|name| name.to_string())
+    }
+}
+
+/// Like `key_json_name`, but for chords, which are named by their modifiers
+/// and then their key, joined with `+`s (e.g. `"control+w"`).
+pub fn chord_json_name(chord: Chord) -> Option<String> {
+    let key_name = key_json_name(chord.key)?;
+
+    let mut name = String::new();
+    for mod_name in chord.mods.names() {
+        name.push_str(mod_name);
+        name.push('+');
+    }
+    name.push_str(&key_name);
+
+    Some(name)
+}
+
+/// Binds a listening socket to `path`, replacing whatever stale socket might
+/// have been left behind there.
+fn listen(path: &Path) -> Result<UnixListener, String> {
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err("another instance of toonmux is already listening \
+                        there; use --socket to pick a different path"
+                .to_owned());
+        }
+        fs::remove_file(path).map_err(|e| e.to_string())?;
+    }
+
+    // Anyone who can connect can type into our game windows, so the socket
+    // is bound inside of a directory that only we can get into, and only
+    // moved into place once nobody else can connect to it.
+    let file_name = path
+        .file_name()
+        .map_or_else(|| "toonmux.sock".into(), |name| name.to_string_lossy());
+    let private_dir =
+        path.with_file_name(format!(".{}.{}", file_name, process::id()));
+    let _ = fs::remove_dir_all(&private_dir);
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private_dir)
+        .map_err(|e| e.to_string())?;
+
+    let private_path = private_dir.join(&*file_name);
+    let listener = UnixListener::bind(&private_path)
+        .and_then(|listener| {
+            fs::set_permissions(
+                &private_path,
+                fs::Permissions::from_mode(0o600),
+            )?;
+            fs::rename(&private_path, path)?;
+
+            Ok(listener)
+        })
+        .map_err(|e| e.to_string());
+    let _ = fs::remove_dir_all(&private_dir);
+
+    listener
+}
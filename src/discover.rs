@@ -0,0 +1,551 @@
+//! Finding game windows without having to click on each one of them, by
+//! matching top-level X11 windows against their `WM_CLASS` and/or `WM_NAME`,
+//! or against what we remember about windows from a previous session.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::CStr,
+    fs,
+    os::raw::{c_char, c_int, c_long, c_uchar, c_uint, c_ulong},
+    ptr, slice,
+};
+use x11::xlib::{self, Window};
+
+/// The lowest `WindowIdentity::score` that counts as a match.  Matching the
+/// PID alone is enough, but otherwise we need (at least) the class and the
+/// command line, or the class, title & geometry.
+const MIN_SCORE: u32 = 4;
+
+type ErrorHandler =
+    unsafe extern "C" fn(*mut xlib::Display, *mut xlib::XErrorEvent) -> c_int;
+
+/// Matches windows whose `WM_CLASS` and `WM_NAME` both contain the given
+/// strings (ignoring case).  Leaving one of them out matches anything.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WindowPattern {
+    /// Either half (the instance or the class) of `WM_CLASS` may match.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub class: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+}
+
+/// Everything that we know about a window that (unlike its ID) is likely to
+/// survive a restart of toonmux, so that we can find the window again.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WindowIdentity {
+    /// `_NET_WM_PID`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pid: Option<u32>,
+    /// The command line of `pid`, with arguments separated by spaces.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cmdline: Option<String>,
+    /// Both halves of `WM_CLASS`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub class: Vec<String>,
+    /// `WM_NAME`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// `[x, y, width, height]`, relative to the root window.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub geometry: Option<[i32; 4]>,
+}
+
+/// A connection to the X server that ignores errors for as long as it is
+/// open.  Windows can disappear at any moment, and Xlib's default error
+/// handler exits the process.
+struct Connection {
+    display: *mut xlib::Display,
+    root: Window,
+    prev_handler: Option<ErrorHandler>,
+}
+
+/// The game clients that we know about.
+pub fn default_patterns() -> Vec<WindowPattern> {
+    ["Toontown Rewritten", "Corporate Clash"]
+        .iter()
+        .map(|&title| WindowPattern {
+            class: None,
+            title: Some(title.to_owned()),
+        })
+        .collect()
+}
+
+impl WindowPattern {
+    pub fn validate(&self) -> Result<(), String> {
+        let is_empty =
+            |s: &Option<String>| s.as_deref().is_none_or(str::is_empty);
+
+        if is_empty(&self.class) && is_empty(&self.title) {
+            Err("Window patterns must have a class, a title, or both."
+                .to_owned())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn matches(&self, class: &[String], title: Option<&str>) -> bool {
+        let contains = |haystack: &str, needle: &str| {
+            haystack.to_lowercase().contains(&needle.to_lowercase())
+        };
+
+        self.class
+            .as_ref()
+            .is_none_or(|c| class.iter().any(|haystack| contains(haystack, c)))
+            && self
+                .title
+                .as_ref()
+                .is_none_or(|t| title.is_some_and(|h| contains(h, t)))
+    }
+}
+
+impl WindowIdentity {
+    /// How well `live` (the identity of a window that exists right now)
+    /// matches `self`, or `None` if it's definitely a different window.
+    fn score(&self, live: &Self) -> Option<u32> {
+        fn eq<T: PartialEq>(a: &Option<T>, b: &Option<T>) -> Option<bool> {
+            a.as_ref().zip(b.as_ref()).map(|(a, b)| a == b)
+        }
+
+        if !self.class.is_empty()
+            && !live.class.is_empty()
+            && self.class != live.class
+        {
+            return None;
+        }
+        let cmdline = eq(&self.cmdline, &live.cmdline);
+        let pid = eq(&self.pid, &live.pid);
+        // PIDs get reused, so a matching PID running something else doesn't
+        // count.
+        if pid == Some(true) && cmdline == Some(false) {
+            return None;
+        }
+
+        let mut score = 0;
+        if pid == Some(true) {
+            score += 4;
+        }
+        if cmdline == Some(true) {
+            score += 2;
+        }
+        if !self.class.is_empty() && self.class == live.class {
+            score += 2;
+        }
+        if eq(&self.title, &live.title) == Some(true) {
+            score += 1;
+        }
+        if eq(&self.geometry, &live.geometry) == Some(true) {
+            score += 1;
+        }
+
+        Some(score).filter(|&s| s >= MIN_SCORE)
+    }
+}
+
+/// Every top-level window that matches at least one of `patterns`, ordered
+/// by window ID.  IDs are handed out in increasing order, so this is (more or
+/// less) the order in which the windows were opened, which keeps the result
+/// stable from one call to the next.
+pub fn find_windows(
+    patterns: &[WindowPattern],
+) -> Result<Vec<Window>, String> {
+    if patterns.is_empty() {
+        return Ok(Vec::new());
+    }
+    let conn = Connection::open()?;
+
+    Ok(conn
+        .top_level_windows()
+        .into_iter()
+        .filter(|&window| {
+            let class = conn.class_hint(window);
+            let title = conn.title(window);
+
+            patterns.iter().any(|p| p.matches(&class, title.as_deref()))
+        })
+        .collect())
+}
+
+/// The identity of each of `windows`, or `None` for those that no longer
+/// exist (or that are `0`).
+pub fn identify(
+    windows: &[Window],
+) -> Result<Vec<Option<WindowIdentity>>, String> {
+    let conn = Connection::open()?;
+
+    Ok(windows
+        .iter()
+        .map(|&window| {
+            if window == 0 {
+                None
+            } else {
+                conn.identify(window)
+            }
+        })
+        .collect())
+}
+
+/// The first (i.e. oldest) top-level window of each of the processes
+/// `pids`, going by `_NET_WM_PID`.  The result is parallel to `pids`.
+pub fn windows_by_pid(pids: &[u32]) -> Result<Vec<Option<Window>>, String> {
+    let conn = Connection::open()?;
+    let window_pids: Vec<_> = conn
+        .top_level_windows()
+        .into_iter()
+        .filter_map(|w| conn.pid(w).map(|pid| (w, pid)))
+        .collect();
+
+    Ok(pids
+        .iter()
+        .map(|pid| {
+            window_pids
+                .iter()
+                .find(|(_, window_pid)| window_pid == pid)
+                .map(|(w, _)| *w)
+        })
+        .collect())
+}
+
+/// Matches each of `wanted` against the live top-level windows, other than
+/// those in `taken`.  Every live window is matched at most once, best
+/// matches first.  The result is parallel to `wanted`.
+pub fn find_identities(
+    wanted: &[Option<WindowIdentity>],
+    taken: &[Window],
+) -> Result<Vec<Option<Window>>, String> {
+    let mut found = vec![None; wanted.len()];
+    if wanted.iter().all(Option::is_none) {
+        return Ok(found);
+    }
+
+    let conn = Connection::open()?;
+    let live: Vec<_> = conn
+        .top_level_windows()
+        .into_iter()
+        .filter(|w| !taken.contains(w))
+        .filter_map(|w| conn.identify(w).map(|id| (w, id)))
+        .collect();
+
+    // `(score, wanted index, window)`, best first.  Ties go to the earlier
+    // wanted identity, and then to the older window.
+    let mut candidates: Vec<_> = wanted
+        .iter()
+        .enumerate()
+        .filter_map(|(i, id)| id.as_ref().map(|id| (i, id)))
+        .flat_map(|(i, id)| {
+            live.iter().filter_map(move |(w, live_id)| {
+                id.score(live_id).map(|score| (score, i, *w))
+            })
+        })
+        .collect();
+    candidates.sort_unstable_by(|a, b| {
+        b.0.cmp(&a.0).then(a.1.cmp(&b.1)).then(a.2.cmp(&b.2))
+    });
+
+    let mut used = Vec::new();
+    for (_, i, window) in candidates {
+        if found[i].is_none() && !used.contains(&window) {
+            found[i] = Some(window);
+            used.push(window);
+        }
+    }
+
+    Ok(found)
+}
+
+impl Connection {
+    fn open() -> Result<Self, String> {
+        let display = unsafe { xlib::XOpenDisplay(ptr::null()) };
+        if display.is_null() {
+            return Err("Failed to connect to the X server".to_owned());
+        }
+
+        unsafe {
+            Ok(Self {
+                display,
+                root: xlib::XDefaultRootWindow(display),
+                prev_handler: xlib::XSetErrorHandler(Some(ignore_error)),
+            })
+        }
+    }
+
+    /// Every top-level window, ordered by window ID.
+    fn top_level_windows(&self) -> Vec<Window> {
+        let mut windows = self.client_list().unwrap_or_else(|| {
+            let mut windows = Vec::new();
+            self.collect_tree(self.root, &mut windows);
+
+            windows
+        });
+        windows.sort_unstable();
+        windows.dedup();
+
+        windows
+    }
+
+    /// The windows that the window manager is managing, as advertised by
+    /// `_NET_CLIENT_LIST`.  Returns `None` if the window manager doesn't
+    /// advertise them (or if there is no window manager).
+    fn client_list(&self) -> Option<Vec<Window>> {
+        self.property(self.root, c"_NET_CLIENT_LIST", xlib::XA_WINDOW)
+    }
+
+    /// Adds every descendant of `window` to `windows`.  Without a window
+    /// manager to ask, this is the only way to find windows that have been
+    /// reparented into decorations.
+    fn collect_tree(&self, window: Window, windows: &mut Vec<Window>) {
+        let mut root = 0;
+        let mut parent = 0;
+        let mut children: *mut Window = ptr::null_mut();
+        let mut child_count: c_uint = 0;
+
+        let status = unsafe {
+            xlib::XQueryTree(
+                self.display,
+                window,
+                &mut root,
+                &mut parent,
+                &mut children,
+                &mut child_count,
+            )
+        };
+        if status == 0 || children.is_null() {
+            return;
+        }
+
+        let children_vec =
+            unsafe { slice::from_raw_parts(children, child_count as usize) }
+                .to_vec();
+        unsafe {
+            xlib::XFree(children as *mut _);
+        }
+
+        for child in children_vec {
+            windows.push(child);
+            self.collect_tree(child, windows);
+        }
+    }
+
+    /// Returns `None` iff `window` doesn't exist.
+    fn identify(&self, window: Window) -> Option<WindowIdentity> {
+        let geometry = self.geometry(window)?;
+        let pid = self.pid(window);
+
+        Some(WindowIdentity {
+            pid,
+            cmdline: pid.and_then(cmdline),
+            class: self.class_hint(window),
+            title: self.title(window),
+            geometry: Some(geometry),
+        })
+    }
+
+    /// `window`'s `_NET_WM_PID`, if it has one.
+    fn pid(&self, window: Window) -> Option<u32> {
+        self.property(window, c"_NET_WM_PID", xlib::XA_CARDINAL)
+            .and_then(|pids| pids.first().map(|&pid| pid as u32))
+    }
+
+    /// A format-32 property of `window`, if it has one of type `type_`.
+    #[inline(always)]
+    fn property(
+        &self,
+        window: Window,
+        name: &CStr,
+        type_: xlib::Atom,
+    ) -> Option<Vec<c_ulong>> {
+        window_property(self.display, window, name, type_)
+    }
+
+    /// Both halves of `window`'s `WM_CLASS`, if it has one.
+    fn class_hint(&self, window: Window) -> Vec<String> {
+        let mut hint = xlib::XClassHint {
+            res_name: ptr::null_mut(),
+            res_class: ptr::null_mut(),
+        };
+        if unsafe { xlib::XGetClassHint(self.display, window, &mut hint) } == 0
+        {
+            return Vec::new();
+        }
+
+        [hint.res_name, hint.res_class]
+            .iter()
+            .filter(|s| !s.is_null())
+            .map(|&s| unsafe {
+                let owned = CStr::from_ptr(s).to_string_lossy().into_owned();
+                xlib::XFree(s as *mut _);
+
+                owned
+            })
+            .collect()
+    }
+
+    /// `window`'s `WM_NAME`, if it has one.
+    fn title(&self, window: Window) -> Option<String> {
+        let mut name: *mut c_char = ptr::null_mut();
+        if unsafe { xlib::XFetchName(self.display, window, &mut name) } == 0
+            || name.is_null()
+        {
+            return None;
+        }
+
+        let title = unsafe { CStr::from_ptr(name) }
+            .to_string_lossy()
+            .into_owned();
+        unsafe {
+            xlib::XFree(name as *mut _);
+        }
+
+        Some(title)
+    }
+
+    /// `[x, y, width, height]` of `window`, relative to the root window.
+    fn geometry(&self, window: Window) -> Option<[i32; 4]> {
+        let mut root = 0;
+        let (mut x, mut y): (c_int, c_int) = (0, 0);
+        let (mut width, mut height): (c_uint, c_uint) = (0, 0);
+        let (mut border, mut depth): (c_uint, c_uint) = (0, 0);
+        let (mut root_x, mut root_y): (c_int, c_int) = (0, 0);
+        let mut child = 0;
+
+        unsafe {
+            if xlib::XGetGeometry(
+                self.display,
+                window,
+                &mut root,
+                &mut x,
+                &mut y,
+                &mut width,
+                &mut height,
+                &mut border,
+                &mut depth,
+            ) == 0
+                || xlib::XTranslateCoordinates(
+                    self.display,
+                    window,
+                    self.root,
+                    0,
+                    0,
+                    &mut root_x,
+                    &mut root_y,
+                    &mut child,
+                ) == 0
+            {
+                return None;
+            }
+        }
+
+        Some([root_x, root_y, width as i32, height as i32])
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        unsafe {
+            xlib::XSync(self.display, xlib::False);
+            xlib::XSetErrorHandler(self.prev_handler);
+            xlib::XCloseDisplay(self.display);
+        }
+    }
+}
+
+/// A format-32 property of `window`, if it has one of type `type_`.  Errors
+/// (e.g. `window` not existing) must be ignored, see `with_errors_ignored`.
+pub fn window_property(
+    display: *mut xlib::Display,
+    window: Window,
+    name: &CStr,
+    type_: xlib::Atom,
+) -> Option<Vec<c_ulong>> {
+    let mut actual_type = 0;
+    let mut actual_format: c_int = 0;
+    let mut item_count: c_ulong = 0;
+    let mut bytes_after: c_ulong = 0;
+    let mut data: *mut c_uchar = ptr::null_mut();
+
+    let status = unsafe {
+        let atom = xlib::XInternAtom(display, name.as_ptr(), xlib::False);
+
+        xlib::XGetWindowProperty(
+            display,
+            window,
+            atom,
+            0,
+            c_long::MAX,
+            xlib::False,
+            type_,
+            &mut actual_type,
+            &mut actual_format,
+            &mut item_count,
+            &mut bytes_after,
+            &mut data,
+        )
+    };
+    if data.is_null() {
+        return None;
+    }
+
+    // Format-32 properties are handed to us as `c_ulong`s, regardless of
+    // the actual size of a `c_ulong`.
+    let items = if status == xlib::Success as c_int
+        && actual_type == type_
+        && actual_format == 32
+    {
+        Some(
+            unsafe {
+                slice::from_raw_parts(
+                    data as *const c_ulong,
+                    item_count as usize,
+                )
+            }
+            .to_vec(),
+        )
+    } else {
+        None
+    };
+    unsafe {
+        xlib::XFree(data as *mut _);
+    }
+
+    items
+}
+
+/// Runs `f` with errors on `display` being ignored, and then synchronizes
+/// with the X server so that all errors caused by `f` have actually been
+/// reported (and ignored).
+pub fn with_errors_ignored<T, F: FnOnce() -> T>(
+    display: *mut xlib::Display,
+    f: F,
+) -> T {
+    unsafe {
+        let prev_handler = xlib::XSetErrorHandler(Some(ignore_error));
+        let ret = f();
+        xlib::XSync(display, xlib::False);
+        xlib::XSetErrorHandler(prev_handler);
+
+        ret
+    }
+}
+
+/// The command line of the process `pid`, with arguments separated by spaces.
+fn cmdline(pid: u32) -> Option<String> {
+    let raw = fs::read(format!("/proc/{}/cmdline", pid)).ok()?;
+    let args: Vec<_> = raw
+        .split(|&b| b == 0)
+        .filter(|arg| !arg.is_empty())
+        .map(String::from_utf8_lossy)
+        .collect();
+
+    if args.is_empty() {
+        None
+    } else {
+        Some(args.join(" "))
+    }
+}
+
+unsafe extern "C" fn ignore_error(
+    _: *mut xlib::Display,
+    _: *mut xlib::XErrorEvent,
+) -> c_int {
+    0
+}
@@ -0,0 +1,298 @@
+//! Global key capture, via passive key grabs on the X11 root window.  This
+//! lets toonmux receive routed keys regardless of which window currently has
+//! keyboard focus.
+
+use crate::key::{Chord, Mods};
+use gdk::keys::Key;
+use std::{
+    os::{
+        raw::{c_int, c_uint},
+        unix::io::RawFd,
+    },
+    ptr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+use x11::xlib;
+
+/// Set by `record_error` whenever the X server reports an error for a
+/// request that was made while our own error handler was installed.
+static X_ERROR: AtomicBool = AtomicBool::new(false);
+
+/// The X11 modifier masks that correspond to `Mods`.
+const MOD_MASKS: [(Mods, c_uint); 4] = [
+    (Mods::SHIFT, xlib::ShiftMask),
+    (Mods::CONTROL, xlib::ControlMask),
+    (Mods::ALT, xlib::Mod1Mask),
+    (Mods::SUPER, xlib::Mod4Mask),
+];
+
+/// Modifiers that we don't care about, but which the X server does: a grab
+/// for e.g. <kbd>Ctrl</kbd>+<kbd>W</kbd> doesn't fire while Caps Lock or Num
+/// Lock (usually `Mod2`) is on, unless we grab those combinations too.
+const IGNORED_MASKS: [c_uint; 4] = [
+    0,
+    xlib::LockMask,
+    xlib::Mod2Mask,
+    xlib::LockMask | xlib::Mod2Mask,
+];
+
+#[derive(Debug)]
+pub struct KeyGrab {
+    display: *mut xlib::Display,
+    root: xlib::Window,
+    /// Keycodes (and modifier masks) that we currently hold a passive grab
+    /// on.
+    grabbed: Mutex<Vec<(xlib::KeyCode, c_uint)>>,
+    keyboard_grabbed: AtomicBool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyEvent {
+    Press(Key, Mods),
+    Release(Key, Mods),
+}
+
+impl KeyGrab {
+    /// Returns `None` iff we cannot open a connection to the X server.
+    pub fn new() -> Option<Self> {
+        let display = unsafe { xlib::XOpenDisplay(ptr::null()) };
+        if display.is_null() {
+            return None;
+        }
+
+        let root = unsafe {
+            // Without this, holding down a key gets us a stream of
+            // release/press pairs instead of a stream of presses, which is
+            // not what GTK gives us (and not what we want).
+            xlib::XkbSetDetectableAutoRepeat(
+                display,
+                xlib::True,
+                ptr::null_mut(),
+            );
+
+            xlib::XDefaultRootWindow(display)
+        };
+
+        Some(Self {
+            display,
+            root,
+            grabbed: Mutex::new(Vec::new()),
+            keyboard_grabbed: AtomicBool::new(false),
+        })
+    }
+
+    /// The file descriptor of the underlying X connection, which becomes
+    /// readable when there are (potentially) new events to process.
+    #[inline]
+    pub fn fd(&self) -> RawFd {
+        unsafe { xlib::XConnectionNumber(self.display) }
+    }
+
+    /// Replaces whatever keys are currently grabbed with `chords`.  Chords
+    /// without modifiers are grabbed regardless of which modifiers are held
+    /// down, since they are also what chords fall back to.  Returns an error
+    /// iff at least one of the grabs failed, which usually means that some
+    /// other client has already grabbed that key.
+    pub fn grab_keys<I: IntoIterator<Item = Chord>>(
+        &self,
+        chords: I,
+    ) -> Result<(), String> {
+        let mut grabbed = self.grabbed.lock().unwrap();
+
+        let mut grabs: Vec<(xlib::KeyCode, c_uint)> = Vec::new();
+        for chord in chords.into_iter().filter(|c| !c.is_none()) {
+            let keycode = unsafe {
+                xlib::XKeysymToKeycode(
+                    self.display,
+                    *chord.key as xlib::KeySym,
+                )
+            };
+            if keycode == 0 {
+                continue;
+            }
+
+            if chord.mods.is_empty() {
+                grabs.push((keycode, xlib::AnyModifier));
+            } else {
+                let mask = mods_to_x11(chord.mods);
+                grabs.extend(
+                    IGNORED_MASKS
+                        .iter()
+                        .map(|ignored| (keycode, mask | ignored)),
+                );
+            }
+        }
+        grabs.sort_unstable();
+        grabs.dedup();
+        // Grabbing with `AnyModifier` already covers every other grab of the
+        // same keycode.
+        let any_modifier: Vec<_> = grabs
+            .iter()
+            .filter(|(_, mask)| *mask == xlib::AnyModifier)
+            .map(|(keycode, _)| *keycode)
+            .collect();
+        grabs.retain(|(keycode, mask)| {
+            *mask == xlib::AnyModifier || !any_modifier.contains(keycode)
+        });
+
+        if *grabbed == grabs {
+            return Ok(());
+        }
+
+        let res = with_error_trap(self.display, || unsafe {
+            for &(keycode, mask) in grabbed.iter() {
+                xlib::XUngrabKey(
+                    self.display,
+                    keycode as c_int,
+                    mask,
+                    self.root,
+                );
+            }
+
+            for &(keycode, mask) in grabs.iter() {
+                xlib::XGrabKey(
+                    self.display,
+                    keycode as c_int,
+                    mask,
+                    self.root,
+                    xlib::False,
+                    xlib::GrabModeAsync,
+                    xlib::GrabModeAsync,
+                );
+            }
+        });
+        *grabbed = grabs;
+
+        res.map_err(|_| {
+            "Failed to grab one or more keys; is another program already \
+             grabbing them?"
+                .to_owned()
+        })
+    }
+
+    #[inline]
+    pub fn ungrab_keys(&self) {
+        // Ungrabbing can't meaningfully fail.
+        let _ = self.grab_keys(None);
+    }
+
+    /// Actively grabs (or ungrabs) the entire keyboard.  This is used while
+    /// any controller is in the "talking" state, so that keys which aren't
+    /// routed anywhere can still be passed through.
+    pub fn set_keyboard_grabbed(&self, grab: bool) {
+        if self.keyboard_grabbed.swap(grab, Ordering::SeqCst) == grab {
+            return;
+        }
+
+        unsafe {
+            if grab {
+                let res = xlib::XGrabKeyboard(
+                    self.display,
+                    self.root,
+                    xlib::False,
+                    xlib::GrabModeAsync,
+                    xlib::GrabModeAsync,
+                    xlib::CurrentTime,
+                );
+                if res != xlib::GrabSuccess {
+                    eprintln!(
+                        "Grabbing the keyboard failed with code {}.",
+                        res,
+                    );
+                    self.keyboard_grabbed.store(false, Ordering::SeqCst);
+                }
+            } else {
+                xlib::XUngrabKeyboard(self.display, xlib::CurrentTime);
+            }
+
+            xlib::XFlush(self.display);
+        }
+    }
+
+    /// Pops the next pending key event off of the X connection, if any.
+    /// Events that are not key presses/releases are silently discarded.
+    pub fn next_event(&self) -> Option<KeyEvent> {
+        unsafe {
+            while xlib::XPending(self.display) > 0 {
+                let mut event = xlib::XEvent { pad: [0; 24] };
+                xlib::XNextEvent(self.display, &mut event);
+
+                let press = match event.get_type() {
+                    xlib::KeyPress => true,
+                    xlib::KeyRelease => false,
+                    _ => continue,
+                };
+                // Index 0 gets us the keysym without any modifiers applied.
+                let keysym = xlib::XLookupKeysym(&mut event.key, 0);
+                let key = Key::from(keysym as u32);
+                let mods = mods_from_x11(event.key.state);
+
+                return Some(if press {
+                    KeyEvent::Press(key, mods)
+                } else {
+                    KeyEvent::Release(key, mods)
+                });
+            }
+        }
+
+        None
+    }
+}
+
+impl Drop for KeyGrab {
+    fn drop(&mut self) {
+        unsafe {
+            xlib::XCloseDisplay(self.display);
+        }
+    }
+}
+
+fn mods_to_x11(mods: Mods) -> c_uint {
+    MOD_MASKS
+        .iter()
+        .filter(|(m, _)| mods.contains(*m))
+        .fold(0, |mask, (_, m)| mask | m)
+}
+
+fn mods_from_x11(state: c_uint) -> Mods {
+    MOD_MASKS
+        .iter()
+        .filter(|(_, m)| state & m != 0)
+        .fold(Mods::NONE, |mods, (m, _)| mods | *m)
+}
+
+/// Runs `f` with an X error handler installed that records (rather than aborts
+/// on) errors, and then synchronizes with the X server on `display` so that
+/// all errors caused by `f` have actually been reported.
+pub fn with_error_trap<F: FnOnce()>(
+    display: *mut xlib::Display,
+    f: F,
+) -> Result<(), ()> {
+    unsafe {
+        X_ERROR.store(false, Ordering::SeqCst);
+        let prev_handler = xlib::XSetErrorHandler(Some(record_error));
+
+        f();
+        xlib::XSync(display, xlib::False);
+
+        xlib::XSetErrorHandler(prev_handler);
+    }
+
+    if X_ERROR.load(Ordering::SeqCst) {
+        Err(())
+    } else {
+        Ok(())
+    }
+}
+
+unsafe extern "C" fn record_error(
+    _: *mut xlib::Display,
+    _: *mut xlib::XErrorEvent,
+) -> c_int {
+    X_ERROR.store(true, Ordering::SeqCst);
+
+    0
+}
@@ -1,71 +1,777 @@
-use crate::state;
+use crate::{
+    discover::{self, WindowIdentity, WindowPattern},
+    keep_alive,
+    key::Chord,
+    launch::LaunchCommand,
+    layout::{self, LayoutPreset},
+    macros::Macro,
+    output::BackendKind,
+    stagger::Delay,
+    state,
+};
+use gdk::keys;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
 use std::{
+    collections::{BTreeMap, BTreeSet},
     env,
-    io::{Read, Write},
-    path::PathBuf,
+    fs::{self, File},
+    io::BufReader,
+    path::{Path, PathBuf},
     sync::atomic::{AtomicUsize, Ordering},
 };
 
+pub const DEFAULT_PROFILE_NAME: &str = "default";
+
+/// The version of the config file format that this version of toonmux
+/// writes.  Whenever the format changes, this gets bumped, and a migration
+/// from the previous version gets added to `MIGRATIONS`.
+///
+/// Adding a field doesn't count as a format change, as long as the field is
+/// `#[serde(default)]`, and its default keeps things working like they did
+/// before it existed.  Older versions of toonmux just ignore such fields
+/// (and drop them when saving), while newer ones fill them in.  Anything
+/// else (renaming, moving, or removing a field, or changing what one means)
+/// needs a bump, so that older versions refuse the config rather than
+/// misreading it.
+pub const CONFIG_VERSION: u64 = MIGRATIONS.len() as u64;
+
+/// Upgrades a (JSON object) config file from one version to the next.
+type Migration = fn(&mut Map<String, Value>) -> Result<(), String>;
+
+/// `MIGRATIONS[i]` upgrades a config file from version `i` to version `i + 1`.
+const MIGRATIONS: &[Migration] = &[
+    migrate_0_to_1,
+    migrate_1_to_2,
+    migrate_2_to_3,
+    migrate_3_to_4,
+    migrate_4_to_5,
+];
+
 #[derive(Serialize, Deserialize)]
 pub struct State {
+    pub version: u64,
+    pub profiles: Vec<Profile>,
+    /// The name of the profile that is currently in use.
+    #[serde(default)]
+    pub active_profile: String,
+    /// Whether or not routed keys are captured globally.
+    #[serde(default)]
+    pub capture: bool,
+    /// Which backend to send keys to game windows with.
+    #[serde(default)]
+    pub output: BackendKind,
+    /// How idle toons are kept alive.
+    #[serde(default)]
+    pub keep_alive: keep_alive::Settings,
+    /// Which windows "attach all" picks up.
+    #[serde(default = "discover::default_patterns")]
+    pub window_patterns: Vec<WindowPattern>,
+    /// What we know about the window of each controller (by position), so
+    /// that they can be found again after a restart.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub windows: Vec<Option<WindowIdentity>>,
+    /// Whether or not to reattach windows like the ones that went away, as
+    /// soon as they show up.
+    #[serde(default)]
+    pub reattach: bool,
+    /// The ways that game windows can be arranged on screen.
+    #[serde(default = "layout::default_presets")]
+    pub layouts: Vec<LayoutPreset>,
+    /// Recorded sequences of keys, which can be played back.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub macros: Vec<Macro>,
+    /// Whether or not mirroring follows chains of mirrors.
+    #[serde(default)]
+    pub transitive_mirroring: bool,
+    /// The key that switches to the next profile.  This is the same for
+    /// every profile, unlike the rest of the main bindings.
+    #[serde(default)]
+    pub cycle_profile: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Profile {
+    pub name: String,
+    /// The columns of the UI, in order.
+    pub actions: Vec<state::ActionDef>,
     pub main_bindings: state::MainBindings,
     pub controllers: Vec<Controller>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Controller {
     /// We use `mirror = usize::MAX` to represent no mirroring ("none").
     pub mirror: AtomicUsize,
-    pub bindings: state::Bindings,
+    /// Keyed by action name.
+    pub bindings: BTreeMap<String, Chord>,
+    /// Whether or not this controller's toon is kept alive when idle.
+    #[serde(default)]
+    pub keep_alive: bool,
+    /// Whether or not clicks in the window of this controller's mirror are
+    /// repeated in this controller's window.
+    #[serde(default)]
+    pub mirror_clicks: bool,
+    /// The names of the actions that this controller copies from its mirror.
+    /// `None` means every action.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mirror_actions: Option<BTreeSet<String>>,
+    /// How long to hold back the keys that this controller gets from its
+    /// mirror.
+    #[serde(default, skip_serializing_if = "Delay::is_zero")]
+    pub mirror_delay: Delay,
+    /// How to start this controller's game client, if toonmux is supposed to
+    /// do that.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub launch: Option<LaunchCommand>,
 }
 
 impl State {
-    #[inline]
-    pub fn from_reader<R: Read>(reader: R) -> Result<Self, String> {
-        serde_json::from_reader(reader).map_err(|e| e.to_string())
+    /// Reads a config file, first backing it up if it needs to be migrated
+    /// from an older version.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let f = File::open(path).map_err(|e| e.to_string())?;
+        let value: Value = serde_json::from_reader(BufReader::new(f))
+            .map_err(|e| e.to_string())?;
+
+        let version = config_version(&value)?;
+        if version < CONFIG_VERSION {
+            let backup_path = back_up(path, version)?;
+            println!(
+                "Migrating {} from version {} to version {}; the original \
+                 has been backed up to {}.",
+                path.display(),
+                version,
+                CONFIG_VERSION,
+                backup_path.display(),
+            );
+        }
+
+        Self::from_value(value)
     }
 
+    /// Like `from_file`, but for a config that has already been read in, and
+    /// without backing anything up.
     #[inline]
-    pub fn to_writer<W: Write>(&self, writer: W) -> Result<(), String> {
-        serde_json::to_writer_pretty(writer, self).map_err(|e| e.to_string())
+    pub fn parse(s: &str) -> Result<Self, String> {
+        Self::from_value(serde_json::from_str(s).map_err(|e| e.to_string())?)
+    }
+
+    /// Migrates `value` up to the current version (if necessary), and then
+    /// deserializes it.
+    pub fn from_value(mut value: Value) -> Result<Self, String> {
+        let version = config_version(&value)?;
+        if version > CONFIG_VERSION {
+            return Err(format!(
+                "This config is version {}, but this version of toonmux only \
+                 understands versions up to {}. Please upgrade toonmux.",
+                version, CONFIG_VERSION,
+            ));
+        }
+
+        let obj = value
+            .as_object_mut()
+            .ok_or_else(|| "The config must be a JSON object".to_owned())?;
+        for (from_version, migrate) in
+            MIGRATIONS.iter().enumerate().skip(version as usize)
+        {
+            migrate(obj).map_err(|e| {
+                format!(
+                    "Migrating from version {} to version {} failed: {}",
+                    from_version,
+                    from_version + 1,
+                    e,
+                )
+            })?;
+            obj.insert("version".to_owned(), (from_version + 1).into());
+        }
+
+        serde_json::from_value(value).map_err(|e| e.to_string())
+    }
+
+    /// Checks for problems that deserialization alone doesn't catch.  Most of
+    /// these would otherwise be quietly patched over when loading.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.profiles.is_empty() {
+            return Err("There are no profiles.".to_owned());
+        }
+        self.keep_alive.validate()?;
+        for pattern in self.window_patterns.iter() {
+            pattern.validate()?;
+        }
+        for (layout_ix, layout) in self.layouts.iter().enumerate() {
+            layout.validate()?;
+            if self.layouts[..layout_ix]
+                .iter()
+                .any(|l| l.name == layout.name)
+            {
+                return Err(format!(
+                    "There is more than one layout named \"{}\".",
+                    layout.name,
+                ));
+            }
+        }
+        for (macro_ix, mac) in self.macros.iter().enumerate() {
+            mac.validate()?;
+            if self.macros[..macro_ix].iter().any(|m| m.name == mac.name) {
+                return Err(format!(
+                    "There is more than one macro named \"{}\".",
+                    mac.name,
+                ));
+            }
+        }
+
+        for (profile_ix, profile) in self.profiles.iter().enumerate() {
+            if profile.name.trim().is_empty() {
+                return Err(format!(
+                    "Profile number {} has an empty name.",
+                    profile_ix + 1,
+                ));
+            }
+            if self.profiles[..profile_ix]
+                .iter()
+                .any(|p| p.name == profile.name)
+            {
+                return Err(format!(
+                    "There is more than one profile named \"{}\".",
+                    profile.name,
+                ));
+            }
+
+            for (action_ix, action) in profile.actions.iter().enumerate() {
+                if action.name.trim().is_empty() {
+                    return Err(format!(
+                        "Action {} of profile \"{}\" has an empty name.",
+                        action_ix + 1,
+                        profile.name,
+                    ));
+                }
+                if state::RESERVED_ACTION_NAMES.contains(&action.name.as_str())
+                    || profile.actions[..action_ix]
+                        .iter()
+                        .any(|a| a.name == action.name)
+                {
+                    return Err(format!(
+                        "Profile \"{}\" can't have an action named \"{}\", \
+                         as that name is already taken.",
+                        profile.name, action.name,
+                    ));
+                }
+
+                // Only earlier actions are checked against, so that each
+                // overlap is only reported once.
+                let key = action.key();
+                if *key != 0
+                    && state::main_key_conflicts(
+                        &profile.actions[..=action_ix],
+                        action_ix,
+                        &key,
+                    )
+                {
+                    return Err(format!(
+                        "Action \"{}\" of profile \"{}\" sends the same main \
+                         key, in the same way, as an earlier action.",
+                        action.name, profile.name,
+                    ));
+                }
+            }
+
+            let ctl_count = profile.controllers.len();
+            if ctl_count > state::MAX_CONTROLLERS {
+                return Err(format!(
+                    "Profile \"{}\" has {} controllers, but at most {} are \
+                     supported.",
+                    profile.name,
+                    ctl_count,
+                    state::MAX_CONTROLLERS,
+                ));
+            }
+            for (ctl_ix, ctl) in profile.controllers.iter().enumerate() {
+                if let Some(name) = ctl.bindings.keys().find(|name| {
+                    !profile.actions.iter().any(|a| &a.name == *name)
+                }) {
+                    return Err(format!(
+                        "Controller {} of profile \"{}\" binds nonexistent \
+                         action \"{}\".",
+                        ctl_ix + 1,
+                        profile.name,
+                        name,
+                    ));
+                }
+
+                if let Some(name) =
+                    ctl.mirror_actions.iter().flatten().find(|name| {
+                        !profile.actions.iter().any(|a| &a.name == *name)
+                    })
+                {
+                    return Err(format!(
+                        "Controller {} of profile \"{}\" copies nonexistent \
+                         action \"{}\" from its mirror.",
+                        ctl_ix + 1,
+                        profile.name,
+                        name,
+                    ));
+                }
+
+                if let Some(ref launch) = ctl.launch {
+                    launch.validate()?;
+                }
+                ctl.mirror_delay.validate()?;
+
+                let mirror = ctl.mirror.load(Ordering::SeqCst);
+                if mirror == ctl_ix {
+                    return Err(format!(
+                        "Controller {} of profile \"{}\" mirrors itself.",
+                        ctl_ix + 1,
+                        profile.name,
+                    ));
+                } else if mirror != usize::MAX && mirror >= ctl_count {
+                    return Err(format!(
+                        "Controller {} of profile \"{}\" mirrors \
+                         nonexistent controller {}.",
+                        ctl_ix + 1,
+                        profile.name,
+                        mirror + 1,
+                    ));
+                }
+            }
+
+            if self.transitive_mirroring {
+                let mirrors: Vec<_> = profile
+                    .controllers
+                    .iter()
+                    .map(|c| c.mirror.load(Ordering::SeqCst))
+                    .collect();
+                if let Some(ctl_ix) = state::find_mirror_loop(&mirrors) {
+                    return Err(format!(
+                        "The mirrors of profile \"{}\" loop back around to \
+                         controller {}, which transitive mirroring doesn't \
+                         allow.",
+                        profile.name,
+                        ctl_ix + 1,
+                    ));
+                }
+            }
+        }
+
+        if !self.profiles.iter().any(|p| p.name == self.active_profile) {
+            return Err(format!(
+                "The active profile, \"{}\", does not exist.",
+                self.active_profile,
+            ));
+        }
+
+        Ok(())
     }
 
     #[inline]
+    pub fn to_json_string(&self) -> Result<String, String> {
+        serde_json::to_string_pretty(self).map_err(|e| e.to_string())
+    }
+
     pub fn from_state_ref(state_ref: &state::State) -> Self {
+        let active_ix = state_ref.active_profile.load(Ordering::SeqCst);
+        let mut profiles = state_ref.profiles.read().unwrap().clone();
+        let active_profile = profiles[active_ix].name.clone();
+        // The stored copy of the active profile is stale.
+        profiles[active_ix] =
+            Profile::from_state_ref(state_ref, active_profile.clone());
+
         Self {
-            main_bindings: state_ref.main_bindings.clone(),
-            controllers: state_ref
-                .controllers
-                .read()
-                .unwrap()
-                .iter()
-                .map(Controller::from_state_ref)
-                .collect(),
+            version: CONFIG_VERSION,
+            profiles,
+            active_profile,
+            capture: state_ref.capturing.load(Ordering::SeqCst),
+            output: state_ref.output.kind(),
+            keep_alive: state_ref.keep_alive.read().unwrap().clone(),
+            window_patterns: state_ref.window_patterns.read().unwrap().clone(),
+            windows: window_identities(state_ref),
+            reattach: state_ref.reattach.load(Ordering::SeqCst),
+            layouts: state_ref.layouts.read().unwrap().clone(),
+            macros: state_ref.routing.macros.read().unwrap().clone(),
+            transitive_mirroring: state_ref
+                .routing
+                .transitive_mirroring
+                .load(Ordering::SeqCst),
+            cycle_profile: state_ref
+                .routing
+                .main_bindings
+                .cycle_profile
+                .load(Ordering::SeqCst),
+        }
+    }
+}
+
+/// The identity of each controller's window.  Controllers that haven't found
+/// their window from a previous session yet keep remembering that one.
+fn window_identities(state_ref: &state::State) -> Vec<Option<WindowIdentity>> {
+    // Getting a read lock on the controller state reader-writer lock.
+    let ctls = state_ref.routing.controllers.read().unwrap();
+
+    let windows: Vec<_> = ctls
+        .iter()
+        .map(|ctl| ctl.window.load(Ordering::SeqCst))
+        .collect();
+    let live = if windows.iter().any(|&w| w != 0) {
+        discover::identify(&windows).unwrap_or_else(|e| {
+            eprintln!("Failed to identify windows: {}", e);
+
+            vec![None; windows.len()]
+        })
+    } else {
+        vec![None; windows.len()]
+    };
+    let mut identities: Vec<_> = ctls
+        .iter()
+        .zip(live)
+        .map(|(ctl, id)| {
+            id.or_else(|| ctl.saved_window.lock().unwrap().clone())
+        })
+        .collect();
+
+    // Relinquishing read lock on the controller state reader-writer lock.
+
+    while identities.last().is_some_and(Option::is_none) {
+        identities.pop();
+    }
+
+    identities
+}
+
+impl Default for State {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            version: CONFIG_VERSION,
+            profiles: vec![Default::default()],
+            active_profile: DEFAULT_PROFILE_NAME.to_owned(),
+            capture: false,
+            output: Default::default(),
+            keep_alive: Default::default(),
+            window_patterns: discover::default_patterns(),
+            windows: Vec::new(),
+            reattach: false,
+            layouts: layout::default_presets(),
+            macros: Vec::new(),
+            transitive_mirroring: false,
+            cycle_profile: 0,
+        }
+    }
+}
+
+impl Profile {
+    /// Snapshots the currently active bindings & controllers of `state_ref`.
+    #[inline]
+    pub fn from_state_ref(state_ref: &state::State, name: String) -> Self {
+        let actions = state_ref.routing.actions.read().unwrap().clone();
+        let controllers = state_ref
+            .routing
+            .controllers
+            .read()
+            .unwrap()
+            .iter()
+            .map(|c| Controller::from_state_ref(c, &actions))
+            .collect();
+
+        Self {
+            name,
+            actions,
+            main_bindings: state_ref.routing.main_bindings.clone(),
+            controllers,
+        }
+    }
+}
+
+impl Default for Profile {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            name: DEFAULT_PROFILE_NAME.to_owned(),
+            actions: state::default_actions(),
+            main_bindings: Default::default(),
+            controllers: vec![
+                Default::default(),
+                Default::default(),
+                Default::default(),
+            ],
         }
     }
 }
 
 impl Controller {
     #[inline]
-    fn from_state_ref(controller_ref: &state::Controller) -> Self {
+    fn from_state_ref(
+        controller_ref: &state::Controller,
+        actions: &[state::ActionDef],
+    ) -> Self {
         Self {
             mirror: AtomicUsize::new(
                 controller_ref.mirror.load(Ordering::SeqCst),
             ),
-            bindings: controller_ref.bindings.clone(),
+            bindings: actions
+                .iter()
+                .zip(controller_ref.bindings.iter())
+                .map(|(a, b)| (a.name.clone(), b.load(Ordering::SeqCst)))
+                .collect(),
+            keep_alive: controller_ref.keep_alive.load(Ordering::SeqCst),
+            mirror_clicks: controller_ref.mirror_clicks.load(Ordering::SeqCst),
+            mirror_actions: if controller_ref
+                .mirror_actions
+                .iter()
+                .all(|m| m.load(Ordering::SeqCst))
+            {
+                None
+            } else {
+                Some(
+                    actions
+                        .iter()
+                        .zip(controller_ref.mirror_actions.iter())
+                        .filter(|(_, m)| m.load(Ordering::SeqCst))
+                        .map(|(a, _)| a.name.clone())
+                        .collect(),
+                )
+            },
+            mirror_delay: *controller_ref.mirror_delay.read().unwrap(),
+            launch: controller_ref.launch.read().unwrap().clone(),
         }
     }
 }
 
-impl From<state::Controller> for Controller {
-    #[inline(always)]
-    fn from(c: state::Controller) -> Self {
+impl Clone for Controller {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self {
+            mirror: AtomicUsize::new(self.mirror.load(Ordering::SeqCst)),
+            bindings: self.bindings.clone(),
+            keep_alive: self.keep_alive,
+            mirror_clicks: self.mirror_clicks,
+            mirror_actions: self.mirror_actions.clone(),
+            mirror_delay: self.mirror_delay,
+            launch: self.launch.clone(),
+        }
+    }
+}
+
+impl Default for Controller {
+    #[inline]
+    fn default() -> Self {
         Self {
-            mirror: c.mirror,
-            bindings: c.bindings.into(),
+            mirror: AtomicUsize::new(usize::MAX),
+            bindings: state::default_actions()
+                .into_iter()
+                .map(|a| {
+                    let binding = state::default_binding(&a.name);
+
+                    (a.name, binding)
+                })
+                .collect(),
+            keep_alive: false,
+            mirror_clicks: false,
+            mirror_actions: None,
+            mirror_delay: Delay::default(),
+            launch: None,
+        }
+    }
+}
+
+/// Works out which version of the config format `value` is in.  Configs from
+/// before the `version` field existed are either version 0 (no profiles) or
+/// version 1.
+fn config_version(value: &Value) -> Result<u64, String> {
+    match value.get("version") {
+        Some(v) => v.as_u64().ok_or_else(|| {
+            format!("The config version must be a whole number, not {}", v)
+        }),
+        None if value.get("profiles").is_some() => Ok(1),
+        None => Ok(0),
+    }
+}
+
+/// Copies the file at `path` to a backup file next to it, without clobbering
+/// any existing backups.  Returns the path of the backup.
+fn back_up(path: &Path, version: u64) -> Result<PathBuf, String> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| format!("{} is not a file", path.display()))?
+        .to_string_lossy();
+
+    let mut backup_path =
+        path.with_file_name(format!("{}.v{}.bak", file_name, version));
+    let mut n = 1;
+    while backup_path.exists() {
+        backup_path = path
+            .with_file_name(format!("{}.v{}.{}.bak", file_name, version, n));
+        n += 1;
+    }
+
+    fs::copy(path, &backup_path).map_err(|ioe| {
+        format!(
+            "Failed to back up {} to {}: {}",
+            path.display(),
+            backup_path.display(),
+            ioe,
+        )
+    })?;
+
+    Ok(backup_path)
+}
+
+/// Version 0 had a single set of bindings & controllers at the top level;
+/// version 1 moved them into a list of named profiles.
+fn migrate_0_to_1(obj: &mut Map<String, Value>) -> Result<(), String> {
+    let main_bindings = obj
+        .remove("main_bindings")
+        .ok_or_else(|| "missing field `main_bindings`".to_owned())?;
+    let controllers = obj
+        .remove("controllers")
+        .ok_or_else(|| "missing field `controllers`".to_owned())?;
+
+    obj.insert(
+        "profiles".to_owned(),
+        json!([{
+            "name": DEFAULT_PROFILE_NAME,
+            "main_bindings": main_bindings,
+            "controllers": controllers,
+        }]),
+    );
+    obj.insert("active_profile".to_owned(), DEFAULT_PROFILE_NAME.into());
+
+    Ok(())
+}
+
+/// Version 2 allows controller bindings to be chords (`{"key": ..., "mods":
+/// [...]}`) rather than just keys.  Plain keys are still written as plain
+/// numbers, so there's nothing to convert; the version bump only keeps older
+/// versions of toonmux from misreading configs that contain chords.
+fn migrate_1_to_2(_obj: &mut Map<String, Value>) -> Result<(), String> {
+    Ok(())
+}
+
+/// Version 3 made the set of actions configurable: each profile got a list of
+/// `actions` (each with a main key and a send mode), which took over all of
+/// the main bindings except for the ones that control toonmux itself.
+/// Controller bindings were already keyed by action name, so they stay as
+/// they are.
+fn migrate_2_to_3(obj: &mut Map<String, Value>) -> Result<(), String> {
+    const OLD_ACTIONS: [(&str, &str, &str); 9] = [
+        ("forward", "forward", "hold"),
+        ("back", "back", "hold"),
+        ("left", "left", "hold"),
+        ("right", "right", "hold"),
+        ("jump", "jump", "hold"),
+        ("dismount", "dismount", "hold"),
+        ("throw", "throw", "hold"),
+        // Low throws used to share the main key for throwing.
+        ("low_throw", "throw", "tap"),
+        ("talk", "talk", "chat_toggle"),
+    ];
+
+    let profiles = obj
+        .get_mut("profiles")
+        .and_then(Value::as_array_mut)
+        .ok_or_else(|| "missing field `profiles`".to_owned())?;
+    for profile in profiles {
+        let main_bindings = profile
+            .get_mut("main_bindings")
+            .and_then(Value::as_object_mut)
+            .ok_or_else(|| "missing field `main_bindings`".to_owned())?;
+
+        let actions = OLD_ACTIONS
+            .iter()
+            .map(|(name, key_name, mode)| {
+                let key = main_bindings
+                    .get(*key_name)
+                    .cloned()
+                    .ok_or_else(|| format!("missing field `{}`", key_name))?;
+
+                Ok(json!({ "name": name, "key": key, "mode": mode }))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        for (_, key_name, _) in OLD_ACTIONS.iter() {
+            main_bindings.remove(*key_name);
         }
+
+        profile
+            .as_object_mut()
+            .ok_or_else(|| "profiles must be JSON objects".to_owned())?
+            .insert("actions".to_owned(), actions.into());
     }
+
+    Ok(())
+}
+
+/// Version 4 added actions for opening the gags, tasks, map, and book
+/// screens.  These are added to every profile that doesn't already have an
+/// action by the same name, but aren't bound by any controllers.
+fn migrate_3_to_4(obj: &mut Map<String, Value>) -> Result<(), String> {
+    let new_actions = [
+        ("gags", keys::constants::Home),
+        ("tasks", keys::constants::End),
+        ("map", keys::constants::Alt_L),
+        ("book", keys::constants::Escape),
+    ];
+
+    let profiles = obj
+        .get_mut("profiles")
+        .and_then(Value::as_array_mut)
+        .ok_or_else(|| "missing field `profiles`".to_owned())?;
+    for profile in profiles {
+        let actions = profile
+            .get_mut("actions")
+            .and_then(Value::as_array_mut)
+            .ok_or_else(|| "missing field `actions`".to_owned())?;
+
+        for (name, key) in new_actions.iter() {
+            if !actions.iter().any(|a| a["name"] == *name) {
+                actions.push(json!({
+                    "name": name,
+                    "key": **key,
+                    "mode": "tap",
+                }));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Version 5 made the `cycle_profile` main binding global, rather than
+/// per-profile, so that no profile can leave it unbound.  The active
+/// profile's binding (or failing that, the first bound one) is the one that
+/// gets kept.
+fn migrate_4_to_5(obj: &mut Map<String, Value>) -> Result<(), String> {
+    let active_profile = obj.get("active_profile").cloned();
+    let profiles = obj
+        .get_mut("profiles")
+        .and_then(Value::as_array_mut)
+        .ok_or_else(|| "missing field `profiles`".to_owned())?;
+
+    let mut keys = Vec::with_capacity(profiles.len());
+    for profile in profiles {
+        let is_active = active_profile
+            .as_ref()
+            .is_some_and(|name| profile.get("name") == Some(name));
+        let main_bindings = profile
+            .get_mut("main_bindings")
+            .and_then(Value::as_object_mut)
+            .ok_or_else(|| "missing field `main_bindings`".to_owned())?;
+        let key = main_bindings
+            .remove("cycle_profile")
+            .and_then(|key| key.as_u64())
+            .unwrap_or(0);
+
+        keys.push((is_active, key));
+    }
+    let cycle_profile = keys
+        .iter()
+        .find(|&&(is_active, _)| is_active)
+        .filter(|&&(_, key)| key != 0)
+        .or_else(|| keys.iter().find(|&&(_, key)| key != 0))
+        .map_or(0, |&(_, key)| key);
+    obj.insert("cycle_profile".to_owned(), cycle_profile.into());
+
+    Ok(())
 }
 
 pub fn get_config_path() -> Result<PathBuf, String> {
@@ -105,3 +811,238 @@ pub fn get_config_path() -> Result<PathBuf, String> {
         Err("No possible config path".to_owned())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The main bindings from before actions were configurable.
+    fn old_main_bindings() -> Value {
+        json!({
+            "forward": *keys::constants::Up,
+            "back": *keys::constants::Down,
+            "left": *keys::constants::Left,
+            "right": *keys::constants::Right,
+            "jump": *keys::constants::Control_L,
+            "dismount": *keys::constants::Escape,
+            "throw": *keys::constants::Delete,
+            "talk": *keys::constants::Return,
+            "toggle_mirroring": *keys::constants::Shift_L,
+        })
+    }
+
+    fn old_controllers() -> Value {
+        json!([
+            {
+                "mirror": usize::MAX,
+                "bindings": {
+                    "forward": *keys::constants::w,
+                    "jump": *keys::constants::space,
+                    "low_throw": *keys::constants::e,
+                },
+            },
+            {
+                "mirror": 0,
+                "bindings": { "forward": *keys::constants::i },
+            },
+        ])
+    }
+
+    fn action(name: &str, key: gdk::keys::Key, mode: &str) -> Value {
+        json!({ "name": name, "key": *key, "mode": mode })
+    }
+
+    fn old_actions() -> Value {
+        json!([
+            action("forward", keys::constants::Up, "hold"),
+            action("jump", keys::constants::Control_L, "hold"),
+            action("low_throw", keys::constants::Delete, "tap"),
+            action("talk", keys::constants::Return, "chat_toggle"),
+        ])
+    }
+
+    /// A config as it was written by each version, in order.
+    fn fixtures() -> Vec<Value> {
+        let mut v4_actions = old_actions();
+        v4_actions.as_array_mut().unwrap().push(action(
+            "gags",
+            keys::constants::Home,
+            "tap",
+        ));
+
+        vec![
+            json!({
+                "version": 0,
+                "main_bindings": old_main_bindings(),
+                "controllers": old_controllers(),
+            }),
+            json!({
+                "version": 1,
+                "profiles": [{
+                    "name": "default",
+                    "main_bindings": old_main_bindings(),
+                    "controllers": old_controllers(),
+                }],
+                "active_profile": "default",
+            }),
+            json!({
+                "version": 2,
+                "profiles": [{
+                    "name": "default",
+                    "main_bindings": old_main_bindings(),
+                    "controllers": old_controllers(),
+                }],
+                "active_profile": "default",
+            }),
+            json!({
+                "version": 3,
+                "profiles": [{
+                    "name": "default",
+                    "actions": old_actions(),
+                    "main_bindings": {
+                        "toggle_mirroring": *keys::constants::Shift_L,
+                        "cycle_profile": *keys::constants::F12,
+                    },
+                    "controllers": old_controllers(),
+                }],
+                "active_profile": "default",
+            }),
+            json!({
+                "version": 4,
+                "profiles": [{
+                    "name": "default",
+                    "actions": v4_actions,
+                    "main_bindings": {
+                        "toggle_mirroring": *keys::constants::Shift_L,
+                        "cycle_profile": *keys::constants::F12,
+                        "cycle_layout": 0,
+                    },
+                    "controllers": old_controllers(),
+                }],
+                "active_profile": "default",
+            }),
+        ]
+    }
+
+    #[test]
+    fn there_is_a_fixture_for_every_version() {
+        assert_eq!(fixtures().len(), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn every_version_migrates_to_the_current_one() {
+        for (version, fixture) in fixtures().into_iter().enumerate() {
+            let state = State::from_value(fixture).unwrap_or_else(|e| {
+                panic!("version {} failed to migrate: {}", version, e)
+            });
+
+            assert_eq!(state.version, CONFIG_VERSION);
+            assert_eq!(state.active_profile, DEFAULT_PROFILE_NAME);
+            assert!(state.validate().is_ok(), "version {}", version);
+
+            let profile = &state.profiles[0];
+            let action_names: Vec<_> =
+                profile.actions.iter().map(|a| a.name.as_str()).collect();
+            for name in ["forward", "jump", "low_throw", "talk", "gags"] {
+                assert!(
+                    action_names.contains(&name),
+                    "version {} lacks action {}",
+                    version,
+                    name,
+                );
+            }
+            assert_eq!(
+                profile.controllers[0].bindings["forward"],
+                Chord::plain(keys::constants::w),
+            );
+            assert_eq!(
+                profile.controllers[1].mirror.load(Ordering::SeqCst),
+                0
+            );
+            assert_eq!(
+                profile.main_bindings.toggle_mirroring(),
+                keys::constants::Shift_L,
+            );
+        }
+    }
+
+    #[test]
+    fn low_throws_share_the_old_throw_key() {
+        let state = State::from_value(fixtures().remove(0)).unwrap();
+        let actions = &state.profiles[0].actions;
+        let key_of = |name: &str| {
+            actions.iter().find(|a| a.name == name).unwrap().key()
+        };
+
+        assert_eq!(key_of("low_throw"), keys::constants::Delete);
+        assert_eq!(key_of("throw"), keys::constants::Delete);
+        assert_eq!(key_of("talk"), keys::constants::Return);
+    }
+
+    #[test]
+    fn new_actions_are_added_once() {
+        let state = State::from_value(fixtures().remove(3)).unwrap();
+        let actions = &state.profiles[0].actions;
+
+        for name in ["gags", "tasks", "map", "book"] {
+            assert_eq!(actions.iter().filter(|a| a.name == name).count(), 1);
+        }
+    }
+
+    #[test]
+    fn cycle_profile_key_becomes_global() {
+        let mut fixture = fixtures().remove(4);
+        let mut second = fixture["profiles"][0].clone();
+        second["name"] = "second".into();
+        second["main_bindings"]["cycle_profile"] = 0.into();
+        fixture["profiles"].as_array_mut().unwrap().push(second);
+        fixture["active_profile"] = "second".into();
+
+        // The active profile's key is unbound, so the other one is kept.
+        let state = State::from_value(fixture.clone()).unwrap();
+        assert_eq!(state.cycle_profile, *keys::constants::F12);
+
+        fixture["profiles"][1]["main_bindings"]["cycle_profile"] =
+            (*keys::constants::F11).into();
+        let state = State::from_value(fixture).unwrap();
+        assert_eq!(state.cycle_profile, *keys::constants::F11);
+        let serialized = serde_json::to_value(&state).unwrap();
+        for profile in serialized["profiles"].as_array().unwrap() {
+            assert!(profile["main_bindings"].get("cycle_profile").is_none());
+        }
+    }
+
+    #[test]
+    fn overlapping_main_keys_are_refused() {
+        let mut fixture = fixtures().remove(4);
+        let actions =
+            fixture["profiles"][0]["actions"].as_array_mut().unwrap();
+        // Same key as "forward", but sent differently.
+        actions.push(action("walk", keys::constants::Up, "tap"));
+        let state = State::from_value(fixture.clone()).unwrap();
+        assert!(state.validate().is_ok());
+
+        let actions =
+            fixture["profiles"][0]["actions"].as_array_mut().unwrap();
+        actions.push(action("also_forward", keys::constants::Up, "hold"));
+        let state = State::from_value(fixture).unwrap();
+        assert!(state.validate().is_err());
+    }
+
+    #[test]
+    fn newer_versions_are_refused() {
+        let mut fixture = fixtures().remove(4);
+        fixture["version"] = (CONFIG_VERSION + 1).into();
+
+        assert!(State::from_value(fixture).is_err());
+    }
+
+    #[test]
+    fn current_version_round_trips() {
+        let state = State::from_value(fixtures().remove(0)).unwrap();
+        let value = serde_json::to_value(&state).unwrap();
+        let reread = State::from_value(value.clone()).unwrap();
+
+        assert_eq!(serde_json::to_value(&reread).unwrap(), value);
+    }
+}
@@ -0,0 +1,136 @@
+//! Keeping idle toons from being logged out.  Every controller that has
+//! keep-alive enabled gets a deadline, which is pushed back whenever a key is
+//! sent to its window.  If the deadline passes anyways, a harmless key (or
+//! sequence of keys) is tapped in its window.
+
+use crate::{
+    output::{OutputEvent, OutputEventKind},
+    state::State,
+};
+use gdk::keys::{self, Key};
+use serde::{Deserialize, Serialize};
+use std::{sync::atomic::Ordering, time::Duration};
+
+/// How often to check for controllers that need to be kept alive.
+pub const TICK: Duration = Duration::from_secs(1);
+
+const MICROS_PER_SEC: f64 = 1_000_000.0;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// How long (in seconds) a toon may go without any input before it gets
+    /// kept alive.
+    pub interval: u64,
+    /// Each interval is randomly lengthened or shortened by up to this many
+    /// seconds, so that keep-alives don't happen like clockwork.
+    pub jitter: u64,
+    /// The keys to tap, in order.
+    pub keys: Vec<u32>,
+}
+
+impl Default for Settings {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            interval: 240,
+            jitter: 60,
+            keys: vec![*keys::constants::Control_L],
+        }
+    }
+}
+
+impl Settings {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.interval == 0 {
+            Err("The keep-alive interval must be at least one second."
+                .to_owned())
+        } else if self.jitter >= self.interval {
+            Err("The keep-alive jitter must be less than the keep-alive \
+                 interval."
+                .to_owned())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// A (randomized) deadline that is one interval after `now`.  Both are
+    /// in microseconds of `glib::monotonic_time()`.
+    fn next_deadline(&self, now: i64) -> i64 {
+        let jitter = self.jitter as f64;
+        let secs = (self.interval as f64
+            + glib::random_double_range(-jitter, jitter))
+        .max(1.0);
+
+        now + (secs * MICROS_PER_SEC) as i64
+    }
+}
+
+/// Pushes back the deadlines of all controllers associated with `window`,
+/// since they just got some input.
+pub fn touch(state: &State, window: u64) {
+    if window == 0 {
+        return;
+    }
+    let now = glib::monotonic_time();
+
+    // Getting a read lock on the keep-alive settings reader-writer lock.
+    let settings = state.keep_alive.read().unwrap();
+    // Getting a read lock on the controller state reader-writer lock.
+    let ctls = state.routing.controllers.read().unwrap();
+
+    for ctl in ctls
+        .iter()
+        .filter(|ctl| ctl.window.load(Ordering::SeqCst) == window)
+    {
+        ctl.keep_alive_at
+            .store(settings.next_deadline(now), Ordering::SeqCst);
+    }
+
+    // Relinquishing read lock on the controller state reader-writer lock.
+    // Relinquishing read lock on the keep-alive settings reader-writer lock.
+}
+
+/// The key taps that are needed to keep alive every controller whose deadline
+/// has passed.  Controllers that are "talking" are never kept alive (we
+/// would be typing into their chat box), so their deadlines just get pushed
+/// back.
+pub fn due(state: &State) -> Vec<OutputEvent> {
+    let now = glib::monotonic_time();
+    let mut events = Vec::new();
+
+    // Getting a read lock on the keep-alive settings reader-writer lock.
+    let settings = state.keep_alive.read().unwrap();
+    // Getting a read lock on the controller state reader-writer lock.
+    let ctls = state.routing.controllers.read().unwrap();
+
+    for (ctl_ix, ctl) in ctls.iter().enumerate() {
+        let window = ctl.window.load(Ordering::SeqCst);
+        if !ctl.keep_alive.load(Ordering::SeqCst) || window == 0 {
+            continue;
+        }
+
+        // A deadline of `0` means that this controller hasn't been scheduled
+        // yet.
+        let deadline = ctl.keep_alive_at.load(Ordering::SeqCst);
+        if deadline != 0 && now < deadline {
+            continue;
+        }
+
+        if deadline != 0 && !state.routing.talking.iter().any(|i| i == ctl_ix)
+        {
+            events.extend(settings.keys.iter().map(|&key| OutputEvent {
+                window,
+                key: Key::from(key),
+                kind: OutputEventKind::Tap,
+            }));
+        }
+        ctl.keep_alive_at
+            .store(settings.next_deadline(now), Ordering::SeqCst);
+    }
+
+    // Relinquishing read lock on the controller state reader-writer lock.
+    // Relinquishing read lock on the keep-alive settings reader-writer lock.
+
+    events
+}
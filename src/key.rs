@@ -3,6 +3,10 @@ use gdk::{
     keys::{self, Key},
 };
 use glib::GString;
+use serde::{
+    de, ser::SerializeStruct, Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::ops;
 
 pub enum KeyName {
     Static(&'static str),
@@ -91,3 +95,395 @@ pub fn canonicalize_key(key: Key) -> Key {
         _ => key.to_lower(),
     }
 }
+
+/// The canonical key (see `canonicalize_key`) that `event` is for, as if no
+/// modifiers were held down, e.g. <kbd>1</kbd> rather than <kbd>!</kbd> for
+/// <kbd>Shift</kbd>+<kbd>1</kbd>.  This is what the global key grab sees too
+/// (see `KeyGrab::next_event`), so chords mean the same thing either way.
+pub fn unmodified_key(event: &gdk::EventKey) -> Key {
+    event
+        .window()
+        .map(|w| w.display())
+        .or_else(gdk::Display::default)
+        .and_then(|d| gdk::Keymap::for_display(&d))
+        .and_then(|keymap| {
+            keymap.translate_keyboard_state(
+                event.hardware_keycode().into(),
+                gdk::ModifierType::empty(),
+                0,
+            )
+        })
+        .map(|(keyval, ..)| canonicalize_key(Key::from(keyval)))
+        .unwrap_or_else(|| canonicalize_key(event.keyval()))
+}
+
+/// A set of modifiers.  Only <kbd>Shift</kbd>, <kbd>Ctrl</kbd>,
+/// <kbd>Alt</kbd>, and <kbd>Super</kbd> count; everything else (e.g.
+/// <kbd>Num Lock</kbd>) is always ignored.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Mods(u8);
+
+/// A key, along with the modifiers that have to be held down for it to count.
+///
+/// Modifier keys can be bound on their own too, like any other key.  When a
+/// key is pressed while some modifiers are held down, a binding for exactly
+/// that chord wins; failing that, a binding for the key on its own is used.
+/// So with <kbd>Ctrl</kbd> bound to "jump" and <kbd>W</kbd> bound to
+/// "forward", holding <kbd>Ctrl</kbd> and pressing <kbd>W</kbd> jumps _and_
+/// moves forward, unless <kbd>Ctrl</kbd>+<kbd>W</kbd> is bound to something,
+/// in which case that is all that happens (on top of the jump that
+/// <kbd>Ctrl</kbd> already triggered).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Chord {
+    pub key: Key,
+    pub mods: Mods,
+}
+
+/// How chords are represented in the config file: either just a key, or a
+/// key with a list of modifiers.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ChordRepr {
+    Key(u32),
+    Chord {
+        key: u32,
+        #[serde(default)]
+        mods: Mods,
+    },
+}
+
+impl Mods {
+    pub const NONE: Self = Self(0);
+    pub const SHIFT: Self = Self(1 << 0);
+    pub const CONTROL: Self = Self(1 << 1);
+    pub const ALT: Self = Self(1 << 2);
+    pub const SUPER: Self = Self(1 << 3);
+
+    /// Every modifier, along with its name (as used in the config file) and
+    /// its label (as used in the UI).
+    const ALL: [(Self, &'static str, &'static str); 4] = [
+        (Self::SHIFT, "shift", "Shift"),
+        (Self::CONTROL, "control", "Ctrl"),
+        (Self::ALT, "alt", "Alt"),
+        (Self::SUPER, "super", "Sup"),
+    ];
+
+    #[inline(always)]
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    #[inline(always)]
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    #[inline(always)]
+    pub fn without(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    #[inline(always)]
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    #[inline(always)]
+    pub fn from_bits(bits: u8) -> Self {
+        Self(bits & 0b1111)
+    }
+
+    pub fn from_gdk(state: gdk::ModifierType) -> Self {
+        let mut mods = Self::NONE;
+        if state.contains(gdk::ModifierType::SHIFT_MASK) {
+            mods = mods | Self::SHIFT;
+        }
+        if state.contains(gdk::ModifierType::CONTROL_MASK) {
+            mods = mods | Self::CONTROL;
+        }
+        if state.contains(gdk::ModifierType::MOD1_MASK) {
+            mods = mods | Self::ALT;
+        }
+        if state.intersects(
+            gdk::ModifierType::SUPER_MASK | gdk::ModifierType::MOD4_MASK,
+        ) {
+            mods = mods | Self::SUPER;
+        }
+
+        mods
+    }
+
+    /// The modifier that `key` (which must be canonical) itself is, if any.
+    pub fn of_key(key: Key) -> Self {
+        match key {
+            keys::constants::Shift_L => Self::SHIFT,
+            keys::constants::Control_L => Self::CONTROL,
+            keys::constants::Alt_L | keys::constants::Meta_L => Self::ALT,
+            keys::constants::Super_L => Self::SUPER,
+            _ => Self::NONE,
+        }
+    }
+
+    /// The config file names of these modifiers.
+    pub fn names(self) -> impl Iterator<Item = &'static str> {
+        Self::ALL
+            .into_iter()
+            .filter(move |(m, _, _)| self.contains(*m))
+            .map(|(_, name, _)| name)
+    }
+}
+
+impl ops::BitOr for Mods {
+    type Output = Self;
+
+    #[inline(always)]
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl Serialize for Mods {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.names())
+    }
+}
+
+impl<'de> Deserialize<'de> for Mods {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .into_iter()
+            .try_fold(Self::NONE, |mods, name| {
+                Self::ALL
+                    .iter()
+                    .find(|(_, n, _)| *n == name)
+                    .map(|(m, _, _)| mods | *m)
+                    .ok_or_else(|| {
+                        de::Error::custom(format!(
+                            "unknown modifier \"{}\"",
+                            name,
+                        ))
+                    })
+            })
+    }
+}
+
+impl Chord {
+    /// `key` must be canonical.  If `key` is a modifier key, then its own
+    /// modifier is ignored, as it's unclear whether or not it "counts" as
+    /// being held down for its own press/release.
+    #[inline]
+    pub fn new(key: Key, mods: Mods) -> Self {
+        Self {
+            key,
+            mods: mods.without(Mods::of_key(key)),
+        }
+    }
+
+    /// Just `key`, with no modifiers.
+    #[inline(always)]
+    pub fn plain(key: Key) -> Self {
+        Self {
+            key,
+            mods: Mods::NONE,
+        }
+    }
+
+    /// Whether or not this is the empty (unbound) chord.
+    #[inline(always)]
+    pub fn is_none(&self) -> bool {
+        *self.key == 0
+    }
+
+    #[inline(always)]
+    pub fn pack(self) -> u64 {
+        (u64::from(self.mods.bits()) << 32) | u64::from(*self.key)
+    }
+
+    #[inline(always)]
+    pub fn unpack(packed: u64) -> Self {
+        Self {
+            key: Key::from(packed as u32),
+            mods: Mods::from_bits((packed >> 32) as u8),
+        }
+    }
+}
+
+impl Default for Chord {
+    #[inline(always)]
+    fn default() -> Self {
+        Self::plain(Key::from(0))
+    }
+}
+
+impl Serialize for Chord {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        if self.mods.is_empty() {
+            serializer.serialize_u32(*self.key)
+        } else {
+            let mut s = serializer.serialize_struct("Chord", 2)?;
+            s.serialize_field("key", &*self.key)?;
+            s.serialize_field("mods", &self.mods)?;
+
+            s.end()
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Chord {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        Ok(match ChordRepr::deserialize(deserializer)? {
+            ChordRepr::Key(key) => Self::plain(Key::from(key)),
+            ChordRepr::Chord { key, mods } => Self::new(Key::from(key), mods),
+        })
+    }
+}
+
+/// Like `key_name`, but for chords, e.g. "Ctrl+w".
+pub fn chord_name(chord: Chord) -> String {
+    let mut name = String::new();
+    if chord.is_none() {
+        return name;
+    }
+
+    for (_, _, label) in
+        Mods::ALL.iter().filter(|(m, _, _)| chord.mods.contains(*m))
+    {
+        name.push_str(label);
+        name.push('+');
+    }
+    name.push_str(key_name(chord.key).as_str());
+
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn chords() -> Vec<Chord> {
+        vec![
+            Chord::default(),
+            Chord::plain(keys::constants::w),
+            Chord::new(keys::constants::w, Mods::CONTROL),
+            Chord::new(keys::constants::F8, Mods::SHIFT | Mods::SUPER),
+            Chord::new(
+                keys::constants::Delete,
+                Mods::SHIFT | Mods::CONTROL | Mods::ALT | Mods::SUPER,
+            ),
+            // Unicode keysyms use the high bits of the key.
+            Chord::new(Key::from(0x100_20ac), Mods::ALT),
+        ]
+    }
+
+    #[test]
+    fn packing_round_trips() {
+        for chord in chords() {
+            assert_eq!(Chord::unpack(chord.pack()), chord);
+        }
+    }
+
+    #[test]
+    fn plain_keys_pack_to_themselves() {
+        let key = keys::constants::w;
+
+        assert_eq!(Chord::plain(key).pack(), u64::from(*key));
+        assert_eq!(Chord::unpack(u64::from(*key)), Chord::plain(key));
+    }
+
+    #[test]
+    fn unknown_mod_bits_are_ignored() {
+        assert_eq!(Mods::from_bits(0xff), Mods::from_bits(0b1111));
+
+        let chord =
+            Chord::unpack((0xf0 << 32) | u64::from(*keys::constants::w));
+        assert_eq!(chord, Chord::plain(keys::constants::w));
+    }
+
+    #[test]
+    fn modifier_keys_ignore_their_own_modifier() {
+        let chord = Chord::new(
+            keys::constants::Control_L,
+            Mods::CONTROL | Mods::SHIFT,
+        );
+
+        assert_eq!(chord.mods, Mods::SHIFT);
+    }
+
+    #[test]
+    fn shifted_digits_round_trip() {
+        // Shift+1 is bound as "1" plus Shift, not as "!".
+        let chord =
+            Chord::new(canonicalize_key(keys::constants::_1), Mods::SHIFT);
+        assert_eq!(chord.key, keys::constants::_1);
+        assert_eq!(chord.mods, Mods::SHIFT);
+
+        assert_eq!(Chord::unpack(chord.pack()), chord);
+        let value = serde_json::to_value(chord).unwrap();
+        assert_eq!(serde_json::from_value::<Chord>(value).unwrap(), chord);
+    }
+
+    #[test]
+    fn serde_round_trips() {
+        for chord in chords() {
+            let value = serde_json::to_value(chord).unwrap();
+
+            assert_eq!(serde_json::from_value::<Chord>(value).unwrap(), chord);
+        }
+    }
+
+    #[test]
+    fn plain_keys_serialize_like_they_used_to() {
+        let key = keys::constants::w;
+
+        assert_eq!(serde_json::to_value(Chord::plain(key)).unwrap(), *key);
+        assert_eq!(
+            serde_json::from_value::<Chord>(json!(*key)).unwrap(),
+            Chord::plain(key),
+        );
+    }
+
+    #[test]
+    fn chords_serialize_with_mod_names() {
+        let chord = Chord::new(keys::constants::w, Mods::CONTROL | Mods::ALT);
+
+        assert_eq!(
+            serde_json::to_value(chord).unwrap(),
+            json!({ "key": *keys::constants::w, "mods": ["control", "alt"] }),
+        );
+    }
+
+    #[test]
+    fn chords_without_mods_deserialize() {
+        let key = keys::constants::w;
+
+        assert_eq!(
+            serde_json::from_value::<Chord>(json!({ "key": *key })).unwrap(),
+            Chord::plain(key),
+        );
+        assert_eq!(
+            serde_json::from_value::<Chord>(
+                json!({ "key": *key, "mods": [] })
+            )
+            .unwrap(),
+            Chord::plain(key),
+        );
+    }
+
+    #[test]
+    fn unknown_mods_are_refused() {
+        let value = json!({ "key": *keys::constants::w, "mods": ["hyper"] });
+
+        assert!(serde_json::from_value::<Chord>(value).is_err());
+    }
+}
@@ -0,0 +1,140 @@
+//! Starting game clients from toonmux, so that each controller can be
+//! attached to the window of the client that it launched.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    os::unix::process::ExitStatusExt,
+    process::{Child, Command, ExitStatus, Stdio},
+    time::Duration,
+};
+
+/// How often to check up on launched clients.
+pub const POLL: Duration = Duration::from_secs(1);
+
+/// A program to run, along with its arguments & extra environment variables.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LaunchCommand {
+    pub program: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+}
+
+/// What became of the last client that a controller launched.
+#[derive(Debug, Default)]
+pub enum Process {
+    /// Nothing was ever launched.
+    #[default]
+    None,
+    Running(Child),
+    Exited(ExitStatus),
+    /// The program couldn't even be started.
+    Failed(String),
+}
+
+impl LaunchCommand {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.program.trim().is_empty() {
+            Err("Launch commands must have a program.".to_owned())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Starts the program, with nothing on its standard input.
+    pub fn spawn(&self) -> Process {
+        match Command::new(&self.program)
+            .args(&self.args)
+            .envs(&self.env)
+            .stdin(Stdio::null())
+            .spawn()
+        {
+            Ok(child) => Process::Running(child),
+            Err(e) => Process::Failed(format!(
+                "Failed to launch {}: {}",
+                self.program, e,
+            )),
+        }
+    }
+}
+
+impl fmt::Display for LaunchCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.program)?;
+        for arg in self.args.iter() {
+            write!(f, " {}", arg)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Process {
+    /// The PID of the client, if it's still running.
+    #[inline]
+    pub fn pid(&self) -> Option<u32> {
+        match self {
+            Self::Running(child) => Some(child.id()),
+            _ => None,
+        }
+    }
+
+    #[inline(always)]
+    pub fn is_running(&self) -> bool {
+        matches!(self, Self::Running(_))
+    }
+
+    /// Whether or not the client died (or never got started) because
+    /// something went wrong.
+    pub fn failed(&self) -> bool {
+        match self {
+            Self::Exited(status) => !status.success(),
+            Self::Failed(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Reaps the client if it has exited.  Returns `true` iff it exited since
+    /// the last time that this was called.
+    pub fn poll(&mut self) -> bool {
+        let Self::Running(child) = self else {
+            return false;
+        };
+
+        match child.try_wait() {
+            Ok(None) => false,
+            Ok(Some(status)) => {
+                *self = Self::Exited(status);
+
+                true
+            }
+            Err(e) => {
+                *self = Self::Failed(format!(
+                    "Lost track of PID {}: {}",
+                    child.id(),
+                    e,
+                ));
+
+                true
+            }
+        }
+    }
+}
+
+impl fmt::Display for Process {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => f.write_str("not launched"),
+            Self::Running(child) => write!(f, "running (PID {})", child.id()),
+            Self::Exited(status) => match (status.code(), status.signal()) {
+                (Some(code), _) => write!(f, "exited with status {}", code),
+                (_, Some(signal)) => write!(f, "killed by signal {}", signal),
+                _ => f.write_str("exited"),
+            },
+            Self::Failed(e) => f.write_str(e),
+        }
+    }
+}
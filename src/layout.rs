@@ -0,0 +1,323 @@
+//! Moving & resizing the attached game windows, so that they're arranged
+//! neatly on screen.
+
+use crate::discover;
+use gdk::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{os::raw::c_long, ptr};
+use x11::xlib::{self, Window};
+
+/// `[x, y, width, height]`, relative to the root window.
+pub type Rect = [i32; 4];
+
+/// The source indication for requests that come from the user (by way of a
+/// pager or the like).
+const SOURCE_PAGER: c_long = 2;
+/// `_NET_MOVERESIZE_WINDOW` flags: static gravity (so that the position is
+/// that of the window itself, rather than of its decorations), and all of x,
+/// y, width, & height being set.
+const MOVERESIZE_FLAGS: c_long =
+    xlib::StaticGravity as c_long | 0b1111 << 8 | SOURCE_PAGER << 12;
+/// `_NET_WM_STATE`'s action for removing states.
+const WM_STATE_REMOVE: c_long = 0;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LayoutPreset {
+    pub name: String,
+    #[serde(flatten)]
+    pub layout: Layout,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Layout {
+    /// `columns` × `rows` equally sized cells on the primary monitor, filled
+    /// row by row.  Either may be `0`, in which case it's picked based on how
+    /// many windows there are.
+    Grid {
+        #[serde(default)]
+        columns: u32,
+        #[serde(default)]
+        rows: u32,
+    },
+    /// The first window takes up the left `main_ratio` of the primary
+    /// monitor, and the rest are stacked on top of each other to its right.
+    MainStack {
+        #[serde(default = "default_main_ratio")]
+        main_ratio: f64,
+    },
+    /// Each window fills a monitor of its own, starting with the primary
+    /// monitor.  If there are more windows than monitors, they wrap around.
+    PerMonitor,
+}
+
+#[inline(always)]
+fn default_main_ratio() -> f64 {
+    0.6
+}
+
+pub fn default_presets() -> Vec<LayoutPreset> {
+    vec![
+        LayoutPreset {
+            name: "Grid".to_owned(),
+            layout: Layout::Grid {
+                columns: 0,
+                rows: 0,
+            },
+        },
+        LayoutPreset {
+            name: "Main + stack".to_owned(),
+            layout: Layout::MainStack {
+                main_ratio: default_main_ratio(),
+            },
+        },
+        LayoutPreset {
+            name: "Per monitor".to_owned(),
+            layout: Layout::PerMonitor,
+        },
+    ]
+}
+
+impl LayoutPreset {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.trim().is_empty() {
+            return Err("Layouts must have names.".to_owned());
+        }
+
+        match self.layout {
+            Layout::MainStack { main_ratio }
+                if !(main_ratio > 0.0 && main_ratio < 1.0) =>
+            {
+                Err(format!(
+                    "The main ratio of the {:?} layout must be strictly \
+                     between 0 and 1.",
+                    self.name,
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Layout {
+    /// Where each of `count` windows should go.  `monitors` are the work
+    /// areas of the monitors, with the primary monitor first.
+    pub fn arrange(&self, count: usize, monitors: &[Rect]) -> Vec<Rect> {
+        let Some(&primary) = monitors.first() else {
+            return Vec::new();
+        };
+        if count == 0 {
+            return Vec::new();
+        }
+
+        match *self {
+            Self::Grid { columns, rows } => {
+                let (columns, rows) =
+                    grid_size(count, columns as usize, rows as usize);
+
+                (0..count)
+                    .map(|i| {
+                        let i = i % (columns * rows);
+
+                        cell(primary, columns, rows, i % columns, i / columns)
+                    })
+                    .collect()
+            }
+            Self::MainStack { main_ratio } => {
+                if count == 1 {
+                    return vec![primary];
+                }
+
+                let [x, y, width, height] = primary;
+                let main_width = (width as f64 * main_ratio).round() as i32;
+                let stack = [x + main_width, y, width - main_width, height];
+
+                std::iter::once([x, y, main_width, height])
+                    .chain(
+                        (0..count - 1)
+                            .map(|i| cell(stack, 1, count - 1, 0, i)),
+                    )
+                    .collect()
+            }
+            Self::PerMonitor => {
+                (0..count).map(|i| monitors[i % monitors.len()]).collect()
+            }
+        }
+    }
+}
+
+/// The number of columns & rows of a grid that holds `count` cells, filling
+/// in whichever of `columns` & `rows` is `0`.
+fn grid_size(count: usize, columns: usize, rows: usize) -> (usize, usize) {
+    match (columns, rows) {
+        (0, 0) => {
+            let columns = (count as f64).sqrt().ceil() as usize;
+
+            (columns, count.div_ceil(columns))
+        }
+        (0, rows) => (count.div_ceil(rows), rows),
+        (columns, 0) => (columns, count.div_ceil(columns)),
+        (columns, rows) => (columns, rows),
+    }
+}
+
+/// The cell at (`column`, `row`) of `area` split up into `columns` × `rows`
+/// cells.  Rounding is done so that the cells exactly cover `area`.
+fn cell(
+    area: Rect,
+    columns: usize,
+    rows: usize,
+    column: usize,
+    row: usize,
+) -> Rect {
+    let [x, y, width, height] = area;
+    let split = |len: i32, parts: usize, i: usize| {
+        (len as i64 * i as i64 / parts as i64) as i32
+    };
+
+    let left = split(width, columns, column);
+    let right = split(width, columns, column + 1);
+    let top = split(height, rows, row);
+    let bottom = split(height, rows, row + 1);
+
+    [x + left, y + top, right - left, bottom - top]
+}
+
+/// The work areas (i.e. not including panels & docks) of all monitors, in
+/// device pixels, with the primary monitor first.
+pub fn monitor_work_areas() -> Vec<Rect> {
+    let Some(display) = gdk::Display::default() else {
+        return Vec::new();
+    };
+    let primary = display.primary_monitor();
+
+    let mut monitors: Vec<_> = (0..display.n_monitors())
+        .filter_map(|i| display.monitor(i))
+        .collect();
+    if let Some(primary) = primary {
+        if let Some(ix) = monitors.iter().position(|m| *m == primary) {
+            let primary = monitors.remove(ix);
+            monitors.insert(0, primary);
+        }
+    }
+
+    monitors
+        .iter()
+        .map(|monitor| {
+            let area = monitor.workarea();
+            let scale = monitor.scale_factor();
+
+            [
+                area.x() * scale,
+                area.y() * scale,
+                area.width() * scale,
+                area.height() * scale,
+            ]
+        })
+        .collect()
+}
+
+/// Moves & resizes each window to its rectangle.  If there's an EWMH window
+/// manager, it's asked to do so (after un-maximizing the window, since
+/// maximized windows can't be moved); otherwise the windows are just
+/// configured directly.
+pub fn move_windows(moves: &[(Window, Rect)]) -> Result<(), String> {
+    let display = unsafe { xlib::XOpenDisplay(ptr::null()) };
+    if display.is_null() {
+        return Err("Failed to connect to the X server".to_owned());
+    }
+
+    discover::with_errors_ignored(display, || unsafe {
+        let root = xlib::XDefaultRootWindow(display);
+        let atom = |name: &std::ffi::CStr| {
+            xlib::XInternAtom(display, name.as_ptr(), xlib::False)
+        };
+        let has_wm = discover::window_property(
+            display,
+            root,
+            c"_NET_SUPPORTING_WM_CHECK",
+            xlib::XA_WINDOW,
+        )
+        .is_some_and(|checks| !checks.is_empty());
+
+        for &(window, [x, y, width, height]) in moves {
+            if width <= 0 || height <= 0 {
+                continue;
+            }
+
+            if has_wm {
+                send_to_wm(
+                    display,
+                    root,
+                    window,
+                    atom(c"_NET_WM_STATE"),
+                    [
+                        WM_STATE_REMOVE,
+                        atom(c"_NET_WM_STATE_MAXIMIZED_VERT") as c_long,
+                        atom(c"_NET_WM_STATE_MAXIMIZED_HORZ") as c_long,
+                        SOURCE_PAGER,
+                        0,
+                    ],
+                );
+                send_to_wm(
+                    display,
+                    root,
+                    window,
+                    atom(c"_NET_MOVERESIZE_WINDOW"),
+                    [
+                        MOVERESIZE_FLAGS,
+                        x as c_long,
+                        y as c_long,
+                        width as c_long,
+                        height as c_long,
+                    ],
+                );
+            } else {
+                xlib::XMoveResizeWindow(
+                    display,
+                    window,
+                    x,
+                    y,
+                    width as u32,
+                    height as u32,
+                );
+            }
+        }
+    });
+
+    unsafe {
+        xlib::XCloseDisplay(display);
+    }
+
+    Ok(())
+}
+
+/// Sends a client message about `window` to the window manager.
+unsafe fn send_to_wm(
+    display: *mut xlib::Display,
+    root: Window,
+    window: Window,
+    message_type: xlib::Atom,
+    data: [c_long; 5],
+) {
+    let mut event = xlib::XEvent {
+        client_message: xlib::XClientMessageEvent {
+            type_: xlib::ClientMessage,
+            serial: 0,
+            send_event: xlib::True,
+            display,
+            window,
+            message_type,
+            format: 32,
+            data: xlib::ClientMessageData::from(data),
+        },
+    };
+
+    xlib::XSendEvent(
+        display,
+        root,
+        xlib::False,
+        xlib::SubstructureRedirectMask | xlib::SubstructureNotifyMask,
+        &mut event,
+    );
+}
@@ -0,0 +1,192 @@
+//! Recording the keys that get sent to a controller's window (along with when
+//! they were sent), so that they can be played back later, with the same
+//! timing.
+
+use crate::output::{OutputEvent, OutputEventKind};
+use gdk::keys::Key;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use x11::xlib::Window;
+
+const MICROS_PER_MILLI: i64 = 1_000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Macro {
+    pub name: String,
+    /// The key that plays this macro back.  `0` means that it has no key.
+    #[serde(default)]
+    pub key: u32,
+    /// The controller that this macro is played back to, along with the
+    /// controllers that mirror it.
+    pub controller: usize,
+    pub steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Step {
+    /// How long (in milliseconds) to wait after the previous step.
+    #[serde(default)]
+    pub delay: u64,
+    pub key: u32,
+    pub kind: StepKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StepKind {
+    Down,
+    Up,
+}
+
+/// A macro that is being recorded.
+#[derive(Debug)]
+pub struct Recording {
+    pub name: String,
+    pub controller: usize,
+    /// When (in `glib::monotonic_time()` microseconds) the last step was
+    /// recorded, or when recording started if there are no steps yet.
+    last_at: i64,
+    steps: Vec<Step>,
+}
+
+/// A macro that is being played back.
+#[derive(Debug)]
+pub struct Playback {
+    /// Tells this playback apart from any that came before it, so that timers
+    /// that were set for a cancelled playback can't advance a newer one.
+    pub id: u64,
+    pub name: String,
+    /// The controller that this is being played back to (see
+    /// `Macro::controller`).
+    pub controller: usize,
+    steps: Vec<Step>,
+    next_step: usize,
+    windows: Vec<Window>,
+    /// The keys that this playback has pressed, but not yet released.
+    held: Vec<u32>,
+}
+
+impl Macro {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.trim().is_empty() {
+            Err("Macros must have names.".to_owned())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Recording {
+    pub fn new(name: String, controller: usize) -> Self {
+        Self {
+            name,
+            controller,
+            last_at: glib::monotonic_time(),
+            steps: Vec::new(),
+        }
+    }
+
+    /// Records every one of `events` that went to `window`.
+    pub fn record(&mut self, events: &[OutputEvent], window: Window) {
+        let now = glib::monotonic_time();
+        let mut delay = ((now - self.last_at) / MICROS_PER_MILLI) as u64;
+
+        for event in events.iter().filter(|e| e.window == window) {
+            let kinds: &[StepKind] = match event.kind {
+                OutputEventKind::Down => &[StepKind::Down],
+                OutputEventKind::Up => &[StepKind::Up],
+                OutputEventKind::Tap => &[StepKind::Down, StepKind::Up],
+            };
+            for &kind in kinds {
+                self.steps.push(Step {
+                    delay,
+                    key: *event.key,
+                    kind,
+                });
+                delay = 0;
+                self.last_at = now;
+            }
+        }
+    }
+
+    pub fn finish(self) -> Macro {
+        Macro {
+            name: self.name,
+            key: 0,
+            controller: self.controller,
+            steps: self.steps,
+        }
+    }
+}
+
+impl Playback {
+    pub fn new(id: u64, mac: &Macro, windows: Vec<Window>) -> Self {
+        Self {
+            id,
+            name: mac.name.clone(),
+            controller: mac.controller,
+            steps: mac.steps.clone(),
+            next_step: 0,
+            windows,
+            held: Vec::new(),
+        }
+    }
+
+    /// How long to wait before calling `step`, or `None` if there are no
+    /// steps left.
+    pub fn next_delay(&self) -> Option<Duration> {
+        self.steps
+            .get(self.next_step)
+            .map(|step| Duration::from_millis(step.delay))
+    }
+
+    /// Takes the next step, along with every step after it that doesn't
+    /// have to wait.
+    pub fn step(&mut self) -> Vec<OutputEvent> {
+        let mut events = Vec::new();
+
+        while let Some(step) = self.steps.get(self.next_step) {
+            if !events.is_empty() && step.delay != 0 {
+                break;
+            }
+            self.next_step += 1;
+
+            let kind = match step.kind {
+                StepKind::Down => {
+                    if !self.held.contains(&step.key) {
+                        self.held.push(step.key);
+                    }
+
+                    OutputEventKind::Down
+                }
+                StepKind::Up => {
+                    self.held.retain(|&k| k != step.key);
+
+                    OutputEventKind::Up
+                }
+            };
+            events.extend(self.windows.iter().map(|&window| OutputEvent {
+                window,
+                key: Key::from(step.key),
+                kind,
+            }));
+        }
+
+        events
+    }
+
+    /// Stops playing, releasing every key that is still held down, so that no
+    /// toon is left running off into the distance.
+    pub fn stop(self) -> Vec<OutputEvent> {
+        self.held
+            .iter()
+            .flat_map(|&key| {
+                self.windows.iter().map(move |&window| OutputEvent {
+                    window,
+                    key: Key::from(key),
+                    kind: OutputEventKind::Up,
+                })
+            })
+            .collect()
+    }
+}
@@ -1,284 +1,188 @@
 #![deny(clippy::all)]
 #![deny(deprecated)]
 
+mod cli;
+mod clicks;
+mod control;
+mod discover;
+mod grab;
 mod json;
+mod keep_alive;
 mod key;
+mod launch;
+mod layout;
+mod macros;
+mod output;
+mod persist;
+mod router;
+mod stagger;
 mod state;
 mod ui;
+mod watch;
 mod xdo;
-
-use crate::key::{canonicalize_key, key_name};
-use glib::Propagation;
-use gtk::{prelude::*, Dialog, DialogFlags, Label, ResponseType};
-use state::{Action, State};
-use std::sync::{atomic::Ordering, Arc};
+mod xsend;
+mod xtest;
+
+use crate::{
+    grab::KeyEvent,
+    key::{canonicalize_key, chord_name, unmodified_key, Chord, Mods},
+    output::{OutputEvent, OutputEventKind},
+    router::Router,
+    watch::WatchEvent,
+};
+use gdk::keys::Key;
+use glib::{ControlFlow, IOCondition, Propagation};
+use gtk::{gio, prelude::*, Dialog, DialogFlags, Label, ResponseType};
+use state::State;
+use std::{
+    cell::RefCell,
+    env, fs,
+    path::Path,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
 
 fn main() -> Result<(), String> {
+    let opts = cli::Options::parse(env::args_os().skip(1))?;
+    match opts.mode {
+        cli::Mode::Help => {
+            println!("{}", cli::USAGE);
+
+            return Ok(());
+        }
+        cli::Mode::Version => {
+            println!("toonmux {}", env!("CARGO_PKG_VERSION"));
+
+            return Ok(());
+        }
+        _ => (),
+    }
+
+    let config_path = match opts.config_path {
+        Some(ref path) => path.clone(),
+        None => json::get_config_path()?,
+    };
+
+    // These don't need GTK, or even an X server.
+    match opts.mode {
+        cli::Mode::DumpConfig => return dump_config(&opts, &config_path),
+        cli::Mode::CheckConfig => return check_config(&opts, &config_path),
+        _ => (),
+    }
+
     // Initialize GTK.
     if gtk::init().is_err() {
         return Err("Failed to initialize GTK".to_owned());
     }
 
     // Initialize internal state.
-    let config_path = json::get_config_path()?;
     println!("Using {} as the config path...", config_path.display());
-    let state = Arc::new(match State::from_json_file(&config_path) {
-        Ok(s) => s,
-        Err(e) => {
-            eprintln!(
-                "Reading from {} failed with \"{}\"; using default \
-                 configuration.",
+    let state = Arc::new(if config_path.exists() {
+        // If the config is there but we can't use it, we bail out rather
+        // than falling back to the default configuration, since that would
+        // overwrite the user's config on exit.
+        State::from_json_file(&config_path).map_err(|e| {
+            format!(
+                "Reading from {} failed with \"{}\"; refusing to continue, \
+                 so as to leave it untouched.",
                 config_path.display(),
                 e,
-            );
-
-            state::State::new()
-                .ok_or_else(|| "Failed to initialize xdo".to_owned())?
-        }
+            )
+        })?
+    } else {
+        println!(
+            "{} does not exist yet; using default configuration.",
+            config_path.display(),
+        );
+
+        state::State::new().ok_or_else(|| {
+            "Failed to initialize xdo, or to connect to the X server"
+                .to_owned()
+        })?
     });
+    if let Some(ref profile) = opts.profile {
+        state.select_profile(profile)?;
+    }
     // Initialize the UI's state.
-    let toonmux = Arc::new(ui::Toonmux::new(Arc::clone(&state), config_path));
+    let saver =
+        Arc::new(persist::Saver::new(config_path.clone(), opts.no_save));
+    let control = Arc::new(control::Server::new(if opts.no_socket {
+        None
+    } else {
+        opts.socket_path
+            .clone()
+            .or_else(control::default_socket_path)
+    }));
+    let toonmux = Arc::new(ui::Toonmux::new(
+        Arc::clone(&state),
+        saver,
+        Arc::clone(&control),
+    ));
+
+    // Dialog settings for the "set binding" popup UI.
+    let dialog_flags = {
+        let mut dialog_flags = DialogFlags::empty();
+        dialog_flags.set(DialogFlags::MODAL, true);
+        dialog_flags.set(DialogFlags::DESTROY_WITH_PARENT, true);
+        dialog_flags.set(DialogFlags::USE_HEADER_BAR, false);
+
+        dialog_flags
+    };
 
     // Redirect key presses.
     {
         let state = Arc::clone(&state);
         let toonmux_ref = Arc::clone(&toonmux);
         toonmux.main_window.connect_key_press_event(move |_, e| {
-            let event_key = canonicalize_key(e.keyval());
-
-            // Handle controllers that are in the "talking" state.
-            let talking = !state.talking.is_empty();
-            if talking {
-                // Getting a read lock on the controller state reader-writer
-                // lock.
-                let ctls = state.controllers.read().unwrap();
-
-                for window in state
-                    .talking
-                    .iter()
-                    .map(|i| ctls[i].window.load(Ordering::SeqCst))
-                {
-                    if let Err(code) =
-                        state.xdo.send_key_down(window, &event_key)
-                    {
-                        eprintln!(
-                            "xdo: sending key down failed with code {}.",
-                            code,
-                        );
-                    }
-                }
-
-                // Relinquishing read lock on the controller state
-                // reader-writer lock.
-            }
-
-            // Handle mirror toggling.
-            let mirroring = if !talking
-                && event_key == state.main_bindings.toggle_mirroring()
-            {
-                let mirroring =
-                    !state.mirroring.fetch_nand(true, Ordering::SeqCst);
-                toonmux_ref.header.change_mirroring(mirroring);
-
-                mirroring
-            } else {
-                state.mirroring.load(Ordering::SeqCst)
-            };
-
-            // Getting a read lock on the routing state reader-writer lock.
-            let routes_state_lock = state.routes.read().unwrap();
-            let maybe_routes = routes_state_lock.get(&event_key);
-
-            if let Some(routes) = maybe_routes {
-                // Getting a read lock on the controller state reader-writer
-                // lock.
-                let ctls = state.controllers.read().unwrap();
-
-                for (ctl_ix, action) in routes {
-                    let main_controller = &ctls[*ctl_ix];
-
-                    let handle_action =
-                        |(mirrored_or_ctl_ix, controller): (
-                            usize,
-                            &state::Controller,
-                        )| {
-                            let window =
-                                controller.window.load(Ordering::SeqCst);
-
-                            match action {
-                                Action::Simple(key) => {
-                                    if !talking {
-                                        if let Err(code) = state
-                                            .xdo
-                                            .send_key_down(window, key)
-                                        {
-                                            eprintln!(
-                                                "xdo: sending key down failed \
-                                                 with code {}.",
-                                                code,
-                                            );
-                                        }
-                                    }
-                                }
-                                Action::LowThrow(key) => {
-                                    if !talking {
-                                        if let Err(code) =
-                                            state.xdo.send_key(window, key)
-                                        {
-                                            eprintln!(
-                                                "xdo: sending key failed with \
-                                                 code {}.",
-                                                code,
-                                            );
-                                        }
-                                    }
-                                }
-                                Action::Talk(key) => {
-                                    if mirrored_or_ctl_ix != *ctl_ix
-                                        || !controller.has_mirror()
-                                    {
-                                        let was_talking = state
-                                            .talking
-                                            .toggle(mirrored_or_ctl_ix);
-
-                                        // If this controller was in the
-                                        // "talking" state before, then we've
-                                        // already sent a key down.  So we just
-                                        // send the corresponding key up here.
-                                        if was_talking {
-                                            if let Err(code) = state
-                                                .xdo
-                                                .send_key_up(window, key)
-                                            {
-                                                eprintln!(
-                                                    "xdo: sending key up \
-                                                     failed with code {}.",
-                                                    code,
-                                                );
-                                            }
-                                        } else {
-                                            if let Err(code) =
-                                                state.xdo.send_key(window, key)
-                                            {
-                                                eprintln!(
-                                                    "xdo: sending key failed \
-                                                     with code {}.",
-                                                    code,
-                                                );
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        };
-
-                    handle_action((*ctl_ix, main_controller));
-                    if mirroring {
-                        main_controller
-                            .mirrored
-                            .iter()
-                            .map(|i| (i, &ctls[i]))
-                            .for_each(handle_action);
-                    }
-                }
-
-                // Relinquishing read lock on the controller state
-                // reader-writer lock.
-            }
+            route_key_press(
+                &state,
+                &toonmux_ref,
+                dialog_flags,
+                unmodified_key(e),
+                Mods::from_gdk(e.state()),
+            );
 
             Propagation::Stop
-
-            // Relinquishing read lock on the routing state reader-writer lock.
         });
     }
     {
         let state = Arc::clone(&state);
         toonmux.main_window.connect_key_release_event(move |_, e| {
-            let event_key = canonicalize_key(e.keyval());
-
-            if !state.talking.is_empty() {
-                // Handle controllers that are in the "talking" state.
+            route_key_release(&state, unmodified_key(e));
 
-                // Getting a read lock on the controller state reader-writer
-                // lock.
-                let ctls = state.controllers.read().unwrap();
-
-                for window in state
-                    .talking
-                    .iter()
-                    .map(|i| ctls[i].window.load(Ordering::SeqCst))
-                {
-                    if let Err(code) =
-                        state.xdo.send_key_up(window, &event_key)
-                    {
-                        eprintln!(
-                            "xdo: sending key up failed with code {}.",
-                            code,
-                        );
-                    }
-                }
+            Propagation::Stop
+        });
+    }
 
-            // Relinquishing read lock on the controller state reader-writer
-            // lock.
-            } else {
-                // Handle mirror toggling.
-                let mirroring = state.mirroring.load(Ordering::SeqCst);
-
-                // Getting a read lock on the routing state reader-writer lock.
-                let routes_state_lock = state.routes.read().unwrap();
-                let maybe_routes = routes_state_lock.get(&event_key);
-
-                if let Some(routes) = maybe_routes {
-                    // Getting a read lock on the controller state
-                    // reader-writer lock.
-                    let ctls = state.controllers.read().unwrap();
-
-                    for (ctl_ix, action) in routes {
-                        let main_controller = &ctls[*ctl_ix];
-
-                        let handle_action =
-                            |controller: &state::Controller| {
-                                let window =
-                                    controller.window.load(Ordering::SeqCst);
-
-                                match action {
-                                    Action::Simple(key) => {
-                                        if let Err(code) =
-                                            state.xdo.send_key_up(window, key)
-                                        {
-                                            eprintln!(
-                                                "xdo: sending key up failed \
-                                                 with code {}",
-                                                code,
-                                            );
-                                        }
-                                    }
-                                    Action::LowThrow(_) => (),
-                                    Action::Talk(_) => (),
-                                }
-                            };
-
-                        handle_action(main_controller);
-                        if mirroring {
-                            main_controller
-                                .mirrored
-                                .iter()
-                                .map(|i| &ctls[i])
-                                .for_each(handle_action);
+    // Redirect globally captured key presses, which arrive on a separate X
+    // connection.
+    {
+        let state = Arc::clone(&state);
+        let toonmux_ref = Arc::clone(&toonmux);
+        glib::source::unix_fd_add_local(
+            state.key_grab.fd(),
+            IOCondition::IN,
+            move |_, _| {
+                while let Some(event) = state.key_grab.next_event() {
+                    match event {
+                        KeyEvent::Press(key, mods) => route_key_press(
+                            &state,
+                            &toonmux_ref,
+                            dialog_flags,
+                            canonicalize_key(key),
+                            mods,
+                        ),
+                        KeyEvent::Release(key, _) => {
+                            route_key_release(&state, canonicalize_key(key))
                         }
                     }
-
-                    // Relinquishing read lock on the controller state
-                    // reader-writer lock.
                 }
 
-                // Relinquishing read lock on the routing state reader-writer
-                // lock.
-            }
-
-            Propagation::Stop
-        });
+                ControlFlow::Continue
+            },
+        );
     }
+    state.regrab();
 
     // Hook up expand/contract button.
     {
@@ -306,44 +210,65 @@ fn main() -> Result<(), String> {
         let toonmux_ref = Arc::clone(&toonmux);
         toonmux.header.mirroring.connect_clicked(move |_| {
             toonmux_ref.header.change_mirroring(
-                !state.mirroring.fetch_nand(true, Ordering::SeqCst),
+                !state.routing.mirroring.fetch_nand(true, Ordering::SeqCst),
             );
+            toonmux_ref.control.notify(&state);
         });
     }
 
-    // Dialog settings for the "set binding" popup UI.
-    let dialog_flags = {
-        let mut dialog_flags = DialogFlags::empty();
-        dialog_flags.set(DialogFlags::MODAL, true);
-        dialog_flags.set(DialogFlags::DESTROY_WITH_PARENT, true);
-        dialog_flags.set(DialogFlags::USE_HEADER_BAR, false);
+    // Hook up global capture toggling button.
+    {
+        let state = Arc::clone(&state);
+        let toonmux_ref = Arc::clone(&toonmux);
+        toonmux.header.capture.connect_clicked(move |_| {
+            let on = !state.capturing.load(Ordering::SeqCst);
+            if let Err(e) = state.set_capturing(on) {
+                show_error(&toonmux_ref, dialog_flags, &e);
 
-        dialog_flags
-    };
+                return;
+            }
+            toonmux_ref.header.change_capturing(on);
+            toonmux_ref.state_changed(&state);
+        });
+    }
 
-    // Hook up add-a-controller button.
+    // Hook up attach-all-windows button.
     {
         let state = Arc::clone(&state);
         let toonmux_ref = Arc::clone(&toonmux);
-        toonmux.header.add.connect_clicked(move |_| {
+        toonmux.header.attach_all.connect_clicked(move |_| {
+            // Windows from the previous session go back where they were
+            // before anything else gets attached.
+            if let Err(e) =
+                attach_windows(&state, &toonmux_ref, State::restore_windows)
+                    .and_then(|_| {
+                        attach_windows(&state, &toonmux_ref, State::attach_all)
+                    })
             {
-                // Getting a write lock on the controller state reader-writer
-                // lock.
-                let mut ctls_state = state.controllers.write().unwrap();
+                eprintln!("{}", e);
+            }
+        });
+    }
 
-                // Update state.
-                let new_ctl_state = ctls_state
-                    .last()
-                    .map(|cs| state::Controller::from_template(cs))
-                    .unwrap_or_default();
-                ctls_state.push(new_ctl_state);
+    // Hook up add-a-controller button.
+    {
+        let state = Arc::clone(&state);
+        let toonmux_ref = Arc::clone(&toonmux);
+        toonmux.header.add.connect_clicked(move |_| {
+            // Update state.
+            let new_ctl_ix = match state.add_controller() {
+                Ok(ix) => ix,
+                Err(e) => {
+                    show_error(&toonmux_ref, dialog_flags, &e);
 
-                // Relinquishing write lock on the controller state
-                // reader-writer lock.
-            }
+                    return;
+                }
+            };
 
+            // Getting a read lock on the action state reader-writer lock.
+            let actions = state.routing.actions.read().unwrap();
             // Getting a read lock on the controller state reader-writer lock.
-            let ctls_state = state.controllers.read().unwrap();
+            let ctls_state = state.routing.controllers.read().unwrap();
 
             // Update other controller UIs to have new mirror menu item.
             for (ctl_ix, ctl_ui) in toonmux_ref
@@ -357,35 +282,35 @@ fn main() -> Result<(), String> {
                 hook_up_mirror_menu_item(
                     &state,
                     &toonmux_ref,
+                    dialog_flags,
                     ctl_ix,
-                    ctls_state.len() - 1,
+                    new_ctl_ix + 1,
                     ctl_ui.mirror.add_menu_item(),
                 );
             }
 
             // Update UI to have new controller.
-            let new_ctl_state = ctls_state.last().unwrap();
-            toonmux_ref
-                .interface
-                .add_controller(new_ctl_state, ctls_state.len());
+            toonmux_ref.interface.add_controller(
+                &ctls_state[new_ctl_ix],
+                &actions,
+                ctls_state.len(),
+            );
 
             // Hook up new controller UI.
             hook_up_controller_ui(
                 &state,
                 &toonmux_ref,
                 dialog_flags,
-                ctls_state.len() - 1,
-                toonmux_ref
-                    .interface
-                    .controller_uis
-                    .read()
-                    .unwrap()
-                    .last()
-                    .unwrap(),
+                new_ctl_ix,
+                &toonmux_ref.interface.controller_uis.read().unwrap()
+                    [new_ctl_ix],
             );
 
+            toonmux_ref.state_changed(&state);
+
             // Relinquishing read lock on the controller state reader-writer
             // lock.
+            // Relinquishing read lock on the action state reader-writer lock.
         });
     }
 
@@ -394,428 +319,1767 @@ fn main() -> Result<(), String> {
         let state = Arc::clone(&state);
         let toonmux_ref = Arc::clone(&toonmux);
         toonmux.header.remove.connect_clicked(move |_| {
-            state.remove_controller(&toonmux_ref.interface);
-            toonmux_ref.interface.remove_controller();
-            toonmux_ref.main_window.resize(1, 1);
+            let ctl_count = state.routing.controllers.read().unwrap().len();
+            if ctl_count > 0 {
+                remove_controller(
+                    &state,
+                    &toonmux_ref,
+                    dialog_flags,
+                    ctl_count - 1,
+                );
+            }
         });
     }
-
-    // Hook up main binding buttons.
-    macro_rules! connect_main_key_binder {
-        ( $key_id:ident, $key_name:expr, $needs_reroute:expr ) => {{
-            let state = Arc::clone(&state);
-            let toonmux_ref = Arc::clone(&toonmux);
-            toonmux.interface.main_bindings_row.$key_id.connect_clicked(
-                move |this| {
-                    let key_choose_dialog = Dialog::with_buttons(
-                        Some(concat!(
-                            "Binding main \u{201c}",
-                            $key_name,
-                            "\u{201d} key",
-                        )),
-                        Some(&toonmux_ref.main_window),
+    {
+        let state = Arc::clone(&state);
+        let toonmux_ref = Arc::clone(&toonmux);
+        toonmux.header.remove.connect_drag_data_received(
+            move |_, _, _, _, sel, _, _| {
+                if let Some(ctl_ix) = dragged_controller(sel) {
+                    remove_controller(
+                        &state,
+                        &toonmux_ref,
                         dialog_flags,
-                        &[
-                            ("Clear", ResponseType::Other(0)),
-                            ("Cancel", ResponseType::Cancel),
-                        ],
-                    );
-                    key_choose_dialog.content_area().pack_start(
-                        &Label::new(Some(concat!(
-                            "Press a key to be bound to \u{201c}",
-                            $key_name,
-                            "\u{201d}.",
-                        ))),
-                        true,
-                        false,
-                        4,
+                        ctl_ix,
                     );
+                }
+            },
+        );
+    }
 
-                    {
-                        let state = Arc::clone(&state);
-                        key_choose_dialog.connect_key_press_event(
-                            move |kcd, e| {
-                                let old_key = state
-                                    .main_bindings
-                                    .$key_id
-                                    .load(Ordering::SeqCst);
-                                let new_key = canonicalize_key(e.keyval());
-
-                                // If the user remaps the key to the same key,
-                                // we don't need to do anything.
-                                if old_key == *new_key {
-                                    kcd.response(ResponseType::Cancel);
-
-                                    return Propagation::Proceed;
-                                }
-
-                                // Make sure we aren't registering a duplicate
-                                // main binding.
-                                if state.is_bound_main(&new_key) {
-                                    eprintln!(
-                                        "Main bindings may not overlap.",
-                                    );
-                                    kcd.response(ResponseType::Cancel);
-
-                                    return Propagation::Proceed;
-                                }
-
-                                // Store the new binding.
-                                state
-                                    .main_bindings
-                                    .$key_id
-                                    .store(*new_key, Ordering::SeqCst);
-
-                                // Perform rerouting.
-                                if $needs_reroute {
-                                    state.reroute_main(
-                                        &old_key.into(),
-                                        &new_key,
-                                    );
-                                }
-
-                                // Relinquish control to main window.
-                                kcd.response(ResponseType::Accept);
-
-                                Propagation::Proceed
-                            },
-                        );
-                    }
+    // Hook up main binding buttons.
+    hook_up_main_key_binder(
+        &state,
+        &toonmux,
+        dialog_flags,
+        &toonmux.interface.main_bindings_row.toggle_mirroring,
+        "toggle_mirroring",
+    );
+    hook_up_main_key_binder(
+        &state,
+        &toonmux,
+        dialog_flags,
+        &toonmux.interface.main_bindings_row.cycle_profile,
+        "cycle_profile",
+    );
+    hook_up_main_key_binder(
+        &state,
+        &toonmux,
+        dialog_flags,
+        &toonmux.interface.main_bindings_row.cycle_layout,
+        "cycle_layout",
+    );
+    hook_up_action_uis(&state, &toonmux, dialog_flags);
+
+    // Hook up profile menu.
+    rebuild_profile_menu(&state, &toonmux, dialog_flags);
+
+    // Hook up layout menu.
+    rebuild_layout_menu(&state, &toonmux);
+
+    // Hook up macros menu.
+    rebuild_macros_menu(&state, &toonmux, dialog_flags);
 
-                    key_choose_dialog.show_all();
-                    let resp = key_choose_dialog.run();
-                    // Unfortunately this method is now considered `unsafe`,
-                    // but that's only because it's considered UB to call any
-                    // methods on the object after destroying it, as you would
-                    // expect.  We _do_ want to destroy the dialog here, rather
-                    // than making it unresponsive until the user manually
-                    // presses the 'X' button.
-                    unsafe {
-                        key_choose_dialog.destroy();
-                    }
+    // Hook up controller UI buttons.
+    hook_up_controller_uis(&state, &toonmux, dialog_flags);
 
-                    match resp {
-                        // User pressed a key. State manipulation is already
-                        // done by that handler so we just need to update what
-                        // is displayed in the UI.
-                        ResponseType::Accept => this.set_label(
-                            key_name(
-                                state
-                                    .main_bindings
-                                    .$key_id
-                                    .load(Ordering::SeqCst)
-                                    .into(),
-                            )
-                            .as_str(),
-                        ),
-                        // User pressed "Clear" button. We have to do state
-                        // manipulation here in addition to updating the UI
-                        // because this is effectively the "handler" for the
-                        // "pressing the Clear button" event.
-                        ResponseType::Other(0) => {
-                            let new_key = 0;
-                            // Store new (and empty) binding.
-                            let old_key = state
-                                .main_bindings
-                                .$key_id
-                                .swap(new_key, Ordering::SeqCst);
-
-                            // Perform rerouting.
-                            if $needs_reroute {
-                                state.reroute_main(
-                                    &old_key.into(),
-                                    &new_key.into(),
-                                );
-                            }
-
-                            this.set_label("");
+    // Reattach the windows from the previous session, if they're still
+    // around.
+    if let Err(e) = attach_windows(&state, &toonmux, State::restore_windows) {
+        eprintln!("{}", e);
+    }
+
+    // Notice when attached windows go away, and when windows that could
+    // replace them show up.
+    {
+        let state = Arc::clone(&state);
+        let toonmux_ref = Arc::clone(&toonmux);
+        glib::source::unix_fd_add_local(
+            state.window_watch.fd(),
+            IOCondition::IN,
+            move |_, _| {
+                while let Some(event) = state.window_watch.next_event() {
+                    handle_watch_event(&state, &toonmux_ref, event);
+                }
+
+                ControlFlow::Continue
+            },
+        );
+    }
+
+    // Repeat clicks in mirrored windows for the controllers that mirror them.
+    {
+        let state = Arc::clone(&state);
+        glib::source::unix_fd_add_local(
+            state.click_watch.fd(),
+            IOCondition::IN,
+            move |_, _| {
+                while let Some(click) = state.click_watch.next_click() {
+                    for mirrored in state.mirror_click(&click) {
+                        if let Err(e) = state.send_mirrored_click(&mirrored) {
+                            eprintln!("{}", e);
                         }
-                        // The user has cancelled.
-                        _ => (),
+                        keep_alive::touch(&state, mirrored.window);
                     }
-                },
-            );
-        }};
+                }
+
+                ControlFlow::Continue
+            },
+        );
     }
 
-    connect_main_key_binder!(forward, "forward", true);
-    connect_main_key_binder!(back, "back", true);
-    connect_main_key_binder!(left, "left", true);
-    connect_main_key_binder!(right, "right", true);
-    connect_main_key_binder!(jump, "jump", true);
-    connect_main_key_binder!(dismount, "dismount", true);
-    connect_main_key_binder!(throw, "throw", true);
-    connect_main_key_binder!(toggle_mirroring, "toggle mirroring", false);
+    // Keep track of the game clients that we launched.
+    {
+        let state = Arc::clone(&state);
+        let toonmux_ref = Arc::clone(&toonmux);
+        glib::timeout_add_local(launch::POLL, move || {
+            let changed = state.poll_processes();
+            if !changed.is_empty() {
+                refresh_controller_status(&state, &toonmux_ref, &changed);
+            }
 
-    // Hook up controller UI buttons.
-    hook_up_controller_uis(&state, &toonmux, dialog_flags);
+            ControlFlow::Continue
+        });
+    }
+
+    // Keep idle toons alive.
+    {
+        let state = Arc::clone(&state);
+        glib::timeout_add_local(keep_alive::TICK, move || {
+            perform(&state, &keep_alive::due(&state));
+
+            ControlFlow::Continue
+        });
+    }
+
+    // Let external tools drive us.
+    {
+        let state = Arc::clone(&state);
+        let toonmux_ref = Arc::clone(&toonmux);
+        control.start(move |request| {
+            handle_control_request(&state, &toonmux_ref, dialog_flags, request)
+        });
+    }
+
+    // Apply changes that are made to the config file while we're running.
+    let _config_monitor =
+        watch_config(&state, &toonmux, dialog_flags, &config_path);
 
     // Make all the widgets within the UI visible.
     toonmux.main_window.show_all();
+    if opts.start_collapsed {
+        toonmux.header.expand.clicked();
+    }
 
     // Start the GTK main event loop.
     gtk::main();
 
+    // Don't leave any keys held down in the game windows.
+    perform(&state, &state.routing.stagger.take_all());
+    perform(&state, &state.stop_macro());
+    control.shut_down();
+
     Ok(())
 }
 
-#[inline]
-fn hook_up_controller_uis(
+fn handle_control_request(
     state: &Arc<State>,
     toonmux: &Arc<ui::Toonmux>,
     dialog_flags: DialogFlags,
-) {
-    // Getting a read lock on controller UIs' reader-writer lock.
-    let ctl_uis = toonmux.interface.controller_uis.read().unwrap();
-
-    for (ctl_ix, ctl_ui) in ctl_uis.iter().enumerate() {
-        hook_up_controller_ui(state, toonmux, dialog_flags, ctl_ix, ctl_ui);
-    }
+    request: control::Request,
+) -> Result<serde_json::Value, String> {
+    use control::Request;
+
+    match request {
+        Request::List => serde_json::to_value(control::Snapshot::new(state))
+            .map_err(|e| e.to_string()),
+        Request::SetMirror { controller, mirror } => {
+            state.set_mirror(controller, mirror.unwrap_or(usize::MAX))?;
+            rebuild_controller_uis(state, toonmux, dialog_flags);
+
+            Ok(serde_json::Value::Null)
+        }
+        Request::MirrorClicks { controller, on } => {
+            state.set_mirror_clicks(controller, on)?;
+            rebuild_controller_uis(state, toonmux, dialog_flags);
 
-    // Relinquishing read lock on controller UIs' reader-writer lock.
-}
+            Ok(serde_json::Value::Null)
+        }
+        Request::MirrorAction {
+            controller,
+            action,
+            on,
+        } => {
+            let action_ix =
+                state.routing.action_index(&action).ok_or_else(|| {
+                    format!("There is no action named \"{}\".", action)
+                })?;
+            state.routing.set_mirror_action(controller, action_ix, on)?;
+            rebuild_controller_uis(state, toonmux, dialog_flags);
+
+            Ok(serde_json::Value::Null)
+        }
+        Request::MirrorDelay {
+            controller,
+            min,
+            max,
+        } => {
+            state.routing.set_mirror_delay(
+                controller,
+                stagger::Delay {
+                    min,
+                    max: max.unwrap_or(min),
+                },
+            )?;
+            rebuild_controller_uis(state, toonmux, dialog_flags);
 
-fn hook_up_controller_ui(
-    state: &Arc<State>,
-    toonmux: &Arc<ui::Toonmux>,
-    dialog_flags: DialogFlags,
-    ctl_ix: usize,
-    ctl_ui: &ui::ControllerUi,
-) {
-    // Hook up the pick-a-window button.
-    {
-        let state = Arc::clone(state);
-        ctl_ui.pick_window.connect_clicked(move |pw| {
-            if let Some(new_window) = state.xdo.select_window_with_click() {
-                // Getting a read lock on the controller state reader-writer
-                // lock.
-                let ctls = state.controllers.read().unwrap();
+            Ok(serde_json::Value::Null)
+        }
+        Request::TransitiveMirroring { on } => {
+            let on = on.unwrap_or_else(|| {
+                !state.routing.transitive_mirroring.load(Ordering::SeqCst)
+            });
+            state.set_transitive_mirroring(on)?;
+            toonmux.control.notify(state);
 
-                let ctl = &ctls[ctl_ix];
-                let old_window = ctl.window.swap(new_window, Ordering::SeqCst);
+            Ok(on.into())
+        }
+        Request::Mirroring { on } => {
+            let on = on.unwrap_or_else(|| {
+                !state.routing.mirroring.load(Ordering::SeqCst)
+            });
+            state.routing.mirroring.store(on, Ordering::SeqCst);
+            toonmux.header.change_mirroring(on);
+            toonmux.control.notify(state);
 
-                if new_window != old_window {
-                    if old_window == 0 {
-                        pw.set_label("\u{2213}"); // 00b1
-                        let pw_style_ctx = pw.style_context();
-                        pw_style_ctx.remove_class("suggested-action");
-                        pw_style_ctx.add_class("destructive-action");
-                    } else if new_window == 0 {
-                        pw.set_label("+");
-                        let pw_style_ctx = pw.style_context();
-                        pw_style_ctx.remove_class("destructive-action");
-                        pw_style_ctx.add_class("suggested-action");
-                    }
+            Ok(on.into())
+        }
+        Request::Bind {
+            controller,
+            action,
+            key,
+            mods,
+        } => {
+            let key = match key {
+                Some(key) => canonicalize_key(key.to_key()?),
+                None => Key::from(0),
+            };
+            match controller {
+                Some(ctl_ix) => {
+                    state.bind(ctl_ix, &action, Chord::new(key, mods))?;
+                    rebuild_controller_uis(state, toonmux, dialog_flags);
+                }
+                None if !mods.is_empty() => {
+                    return Err(
+                        "Main bindings can't have modifiers.".to_owned()
+                    );
+                }
+                None => {
+                    state.bind_main(&action, key)?;
+                    toonmux.interface.main_bindings_row.refresh(state);
+                    toonmux.state_changed(state);
                 }
+            }
 
-                // Relinquishing read lock on the controller state
-                // reader-writer lock.
+            Ok(serde_json::Value::Null)
+        }
+        Request::Attach { controller, window } => {
+            state.attach(controller, window)?;
+            if let Some(ctl_ui) = toonmux
+                .interface
+                .controller_uis
+                .read()
+                .unwrap()
+                .get(controller)
+            {
+                ui::set_window_attached(&ctl_ui.pick_window, window != 0);
             }
-        });
-    }
+            toonmux.control.notify(state);
 
-    // Hook up the mirror menu.
-    hook_up_mirror_menu(state, toonmux, ctl_ix, ctl_ui);
+            Ok(serde_json::Value::Null)
+        }
+        Request::AttachAll => {
+            attach_windows(state, toonmux, State::attach_all).map(Into::into)
+        }
+        Request::Launch { controller } => {
+            state.launch(controller)?;
+            refresh_controller_status(state, toonmux, &[controller]);
 
-    macro_rules! connect_key_binder {
-        ( $key_id:ident, $key_name:expr, $action_ty:ident ) => {{
-            let state = Arc::clone(state);
-            let toonmux = Arc::clone(toonmux);
-            ctl_ui.$key_id.connect_clicked(move |this| {
-                let key_choose_dialog = Dialog::with_buttons(
-                    Some(concat!(
-                        "Binding \u{201c}",
-                        $key_name,
-                        "\u{201d} key",
-                    )),
-                    Some(&toonmux.main_window),
-                    dialog_flags,
-                    &[
-                        ("Clear", ResponseType::Other(0)),
-                        ("Cancel", ResponseType::Cancel),
-                    ],
-                );
-                key_choose_dialog.content_area().pack_start(
-                    &Label::new(Some(concat!(
-                        "Press a key to be bound to \u{201c}",
-                        $key_name,
-                        "\u{201d}.",
-                    ))),
-                    true,
-                    false,
-                    4,
-                );
+            Ok(serde_json::Value::Null)
+        }
+        Request::Layout { name } => arrange_windows(state, toonmux, |s| {
+            s.select_layout(name.as_deref())
+        })
+        .map(|()| serde_json::Value::Null),
+        Request::RecordMacro { controller, name } => {
+            state.start_recording(controller, name)?;
+            rebuild_macros_menu(state, toonmux, dialog_flags);
+            toonmux.control.notify(state);
 
-                {
-                    let state = Arc::clone(&state);
-                    key_choose_dialog.connect_key_press_event(
-                        move |kcd, e| {
-                            let new_key = canonicalize_key(e.keyval());
-                            // Store the new binding.
-                            let old_key = state.controllers.read().unwrap()
-                                [ctl_ix]
-                                .bindings
-                                .$key_id
-                                .swap(*new_key, Ordering::SeqCst);
-
-                            // If the user remaps the key to the same key, we
-                            // don't need to do anything.
-                            if old_key == *new_key {
-                                kcd.response(ResponseType::Cancel);
-
-                                return Propagation::Proceed;
-                            }
-
-                            // Get the main key binding that this key maps to
-                            // (i.e. the one that actually gets sent to
-                            // clients).
-                            let main_key = state.main_bindings.$key_id();
-
-                            // Perform rerouting.
-                            state.reroute(
-                                ctl_ix,
-                                &old_key.into(),
-                                &new_key,
-                                &main_key,
-                                Some(Action::$action_ty(main_key.clone())),
-                            );
+            Ok(serde_json::Value::Null)
+        }
+        Request::StopRecording => {
+            let name = state.stop_recording()?;
+            toonmux.state_changed(state);
+            rebuild_macros_menu(state, toonmux, dialog_flags);
 
-                            // Relinquish control to main window.
-                            kcd.response(ResponseType::Accept);
+            Ok(name.into())
+        }
+        Request::PlayMacro { name } => {
+            let macro_ix = state.macro_index(&name).ok_or_else(|| {
+                format!("There is no macro named \"{}\".", name)
+            })?;
+            if state.playing_macro_name().as_ref() != Some(&name) {
+                toggle_macro(state, toonmux, dialog_flags, macro_ix)?;
+            }
 
-                            Propagation::Proceed
-                        },
-                    );
-                }
+            Ok(serde_json::Value::Null)
+        }
+        Request::StopMacro => {
+            perform(state, &state.stop_macro());
+            rebuild_macros_menu(state, toonmux, dialog_flags);
+            toonmux.control.notify(state);
 
-                key_choose_dialog.show_all();
-                let resp = key_choose_dialog.run();
-                // Unfortunately this method is now considered `unsafe`, but
-                // that's only because it's considered UB to call any methods
-                // on the object after destroying it, as you would expect.  We
-                // _do_want to destroy the dialog here, rather than making it
-                // unresponsive until the user manually presses the 'X' button.
-                unsafe {
-                    key_choose_dialog.destroy();
-                }
+            Ok(serde_json::Value::Null)
+        }
+        Request::BindMacro { name, key } => {
+            let key = match key {
+                Some(key) => canonicalize_key(key.to_key()?),
+                None => Key::from(0),
+            };
+            state.bind_macro(&name, key)?;
+            toonmux.state_changed(state);
 
-                match resp {
-                    // User pressed a key. State manipulation is already done
-                    // by that handler so we just need to update what is
-                    // displayed in the UI.
-                    ResponseType::Accept => this.set_label(
-                        key_name(
-                            state.controllers.read().unwrap()[ctl_ix]
-                                .bindings
-                                .$key_id
-                                .load(Ordering::SeqCst)
-                                .into(),
-                        )
-                        .as_str(),
-                    ),
-                    // User pressed "Clear" button. We have to do state
-                    // manipulation here in addition to updating the UI because
-                    // this is effectively the "handler" for the "pressing the
-                    // Clear button" event.
-                    ResponseType::Other(0) => {
-                        let new_key = 0;
-                        // Store new (and empty) binding.
-                        let old_key = state.controllers.read().unwrap()
-                            [ctl_ix]
-                            .bindings
-                            .$key_id
-                            .swap(new_key, Ordering::SeqCst);
-
-                        // Get the main key binding that this key maps to (i.e.
-                        // the one that actually gets sent to clients).
-                        let main_key = state.main_bindings.$key_id();
-
-                        // Perform rerouting.
-                        state.reroute(
-                            ctl_ix,
-                            &old_key.into(),
-                            &new_key.into(),
-                            &main_key,
-                            None,
-                        );
+            Ok(serde_json::Value::Null)
+        }
+        Request::RestoreWindows => {
+            attach_windows(state, toonmux, State::restore_windows)
+                .map(Into::into)
+        }
+        Request::Key { key, mods, kind } => {
+            let key = canonicalize_key(key.to_key()?);
+            if kind != control::KeyEventKind::Release {
+                route_key_press(state, toonmux, dialog_flags, key, mods);
+            }
+            if kind != control::KeyEventKind::Press {
+                route_key_release(state, key);
+            }
 
-                        this.set_label("");
-                    }
-                    // The user has cancelled.
-                    _ => (),
-                }
+            Ok(serde_json::Value::Null)
+        }
+        // Subscriptions are handled by the server itself.
+        Request::Subscribe => Ok(serde_json::Value::Null),
+        Request::TakeRecorded => {
+            let recorder = state.output.as_recorder().ok_or_else(|| {
+                format!(
+                    "The {} output backend doesn't record anything.",
+                    state.output.kind(),
+                )
+            })?;
+
+            Ok(recorder
+                .take_events()
+                .into_iter()
+                .map(|event| {
+                    serde_json::json!({
+                        "window": event.window,
+                        "key": control::key_json_name(event.key),
+                        "kind": match event.kind {
+                            OutputEventKind::Down => "down",
+                            OutputEventKind::Up => "up",
+                            OutputEventKind::Tap => "tap",
+                        },
+                    })
+                })
+                .chain(recorder.take_clicks().into_iter().map(|click| {
+                    serde_json::json!({
+                        "window": click.window,
+                        "kind": "click",
+                        "x": click.x,
+                        "y": click.y,
+                        "button": click.button,
+                    })
+                }))
+                .collect())
+        }
+    }
+}
+
+/// Prints the config at `config_path` (or the default config, if there's
+/// nothing there), after any migrations and after applying `--profile`.
+fn dump_config(opts: &cli::Options, config_path: &Path) -> Result<(), String> {
+    let mut json_state = if config_path.exists() {
+        json::State::parse(
+            &fs::read_to_string(config_path).map_err(|e| e.to_string())?,
+        )?
+    } else {
+        Default::default()
+    };
+    if let Some(ref profile) = opts.profile {
+        if !json_state.profiles.iter().any(|p| &p.name == profile) {
+            return Err(format!("There is no profile named \"{}\".", profile));
+        }
+        json_state.active_profile = profile.clone();
+    }
+
+    println!("{}", json_state.to_json_string()?);
+
+    Ok(())
+}
+
+/// Checks that the config at `config_path` can be used as-is, i.e. without
+/// anything being quietly patched over at load time.
+fn check_config(
+    opts: &cli::Options,
+    config_path: &Path,
+) -> Result<(), String> {
+    let check = || -> Result<(), String> {
+        let mut json_state = json::State::parse(
+            &fs::read_to_string(config_path).map_err(|e| e.to_string())?,
+        )?;
+        if let Some(ref profile) = opts.profile {
+            json_state.active_profile = profile.clone();
+        }
+
+        json_state.validate()
+    };
+
+    check()
+        .map_err(|e| format!("{} is invalid: {}", config_path.display(), e))?;
+    println!("{} is valid.", config_path.display());
+
+    Ok(())
+}
+
+/// Watches the config file for changes made by anyone other than us, and
+/// applies them to the running state.  The returned monitor has to be kept
+/// alive for as long as the config file should be watched.
+fn watch_config(
+    state: &Arc<State>,
+    toonmux: &Arc<ui::Toonmux>,
+    dialog_flags: DialogFlags,
+    config_path: &Path,
+) -> Option<gio::FileMonitor> {
+    let monitor = match gio::File::for_path(config_path)
+        .monitor_file(gio::FileMonitorFlags::NONE, gio::Cancellable::NONE)
+    {
+        Ok(monitor) => monitor,
+        Err(e) => {
+            eprintln!(
+                "Watching {} for changes failed with: {}",
+                config_path.display(),
+                e,
+            );
+
+            return None;
+        }
+    };
+
+    let state = Arc::clone(state);
+    let toonmux = Arc::clone(toonmux);
+    let config_path = config_path.to_owned();
+    // A single write can get reported more than once, so we remember what we
+    // last looked at.
+    let last_seen = RefCell::new(String::new());
+    monitor.connect_changed(move |_, _, _, event| {
+        match event {
+            gio::FileMonitorEvent::ChangesDoneHint
+            | gio::FileMonitorEvent::Created => (),
+            _ => return,
+        }
+
+        let contents = match fs::read_to_string(&config_path) {
+            Ok(contents) => contents,
+            Err(ioe) => {
+                eprintln!(
+                    "Reading from {} failed with: {}",
+                    config_path.display(),
+                    ioe,
+                );
+
+                return;
+            }
+        };
+        if *last_seen.borrow() == contents || toonmux.saver.is_saved(&contents)
+        {
+            return;
+        }
+        last_seen.replace(contents);
+
+        match json::State::parse(&last_seen.borrow()).and_then(|new_state| {
+            // Hand-edited configs can be wrong in ways that deserialization
+            // doesn't catch, and the live state is better off untouched
+            // than patched over.
+            new_state.validate()?;
+            state.reload(new_state)
+        }) {
+            Ok(()) => {
+                println!("Reloaded {}.", config_path.display());
+
+                // There's no need to write back what we just read.
+                toonmux.saver.mark_saved(&state);
+                toonmux
+                    .header
+                    .change_capturing(state.capturing.load(Ordering::SeqCst));
+                refresh_profile_ui(&state, &toonmux, dialog_flags);
+                rebuild_layout_menu(&state, &toonmux);
+                rebuild_macros_menu(&state, &toonmux, dialog_flags);
+            }
+            Err(e) => eprintln!(
+                "Not reloading {}, as reading it failed with: {}",
+                config_path.display(),
+                e,
+            ),
+        }
+    });
+
+    Some(monitor)
+}
+
+fn route_key_press(
+    state: &Arc<State>,
+    toonmux: &Arc<ui::Toonmux>,
+    dialog_flags: DialogFlags,
+    event_key: Key,
+    mods: Mods,
+) {
+    let routed = Router::new(&state.routing).press(&event_key, mods);
+    if let Some(mirroring) = routed.mirroring {
+        toonmux.header.change_mirroring(mirroring);
+    }
+    perform(state, &routed.events);
+    state.record(&routed.events);
+    schedule_staggered(state, routed.staggered);
+
+    if let Some(macro_ix) = routed.play_macro {
+        if let Err(e) = toggle_macro(state, toonmux, dialog_flags, macro_ix) {
+            eprintln!("{}", e);
+        }
+    }
+
+    if routed.cycle_layout {
+        if let Err(e) = arrange_windows(state, toonmux, State::cycle_layout) {
+            eprintln!("{}", e);
+        }
+    }
+    if routed.cycle_profile {
+        state.cycle_profile();
+        refresh_profile_ui(state, toonmux, dialog_flags);
+    } else if routed.mirroring.is_some() || routed.talking_changed {
+        toonmux.control.notify(state);
+    }
+
+    // While anyone is talking, we need to see every key, not just the routed
+    // ones.
+    if state.capturing.load(Ordering::SeqCst) {
+        state
+            .key_grab
+            .set_keyboard_grabbed(!state.routing.talking.is_empty());
+    }
+}
+
+/// Attaches windows using `attach` (e.g. `State::attach_all`), and brings
+/// the pick-a-window buttons up to date.  Returns the indices of the
+/// controllers that got a window.
+fn attach_windows<F: FnOnce(&State) -> Result<Vec<usize>, String>>(
+    state: &State,
+    toonmux: &ui::Toonmux,
+    attach: F,
+) -> Result<Vec<usize>, String> {
+    let newly_attached = attach(state)?;
+    if newly_attached.is_empty() {
+        return Ok(newly_attached);
+    }
+
+    // Getting a read lock on controller UIs' reader-writer lock.
+    let ctl_uis = toonmux.interface.controller_uis.read().unwrap();
+
+    for ctl_ui in newly_attached.iter().filter_map(|&i| ctl_uis.get(i)) {
+        ui::set_window_attached(&ctl_ui.pick_window, true);
+    }
+
+    // Relinquishing read lock on controller UIs' reader-writer lock.
+    drop(ctl_uis);
+
+    toonmux.control.notify(state);
+
+    Ok(newly_attached)
+}
+
+/// Plays the macro at `macro_ix`, or stops it if it's already playing.
+fn toggle_macro(
+    state: &Arc<State>,
+    toonmux: &Arc<ui::Toonmux>,
+    dialog_flags: DialogFlags,
+    macro_ix: usize,
+) -> Result<(), String> {
+    let playing = state.playing_macro_name();
+    if playing.is_some() && playing == state.macro_name(macro_ix) {
+        perform(state, &state.stop_macro());
+    } else {
+        let (id, stopped) = state.play_macro(macro_ix)?;
+        perform(state, &stopped);
+        schedule_playback(state, toonmux, dialog_flags, id);
+    }
+    rebuild_macros_menu(state, toonmux, dialog_flags);
+    toonmux.control.notify(state);
+
+    Ok(())
+}
+
+/// Sets a timer for the next step of the playback with ID `id`, which keeps
+/// setting timers for the steps after that, until the playback finishes or
+/// gets stopped.
+fn schedule_playback(
+    state: &Arc<State>,
+    toonmux: &Arc<ui::Toonmux>,
+    dialog_flags: DialogFlags,
+    id: u64,
+) {
+    let Some(delay) = state.playback_delay(id) else {
+        return;
+    };
+
+    let state = Arc::clone(state);
+    let toonmux = Arc::clone(toonmux);
+    glib::timeout_add_local_once(delay, move || {
+        let Some(events) = state.playback_step(id) else {
+            // The playback was stopped in the meantime.
+            return;
+        };
+        perform(&state, &events);
+
+        if state.playback_delay(id).is_some() {
+            schedule_playback(&state, &toonmux, dialog_flags, id);
+        } else {
+            rebuild_macros_menu(&state, &toonmux, dialog_flags);
+            toonmux.control.notify(&state);
+        }
+    });
+}
+
+/// Arranges the windows using `arrange` (e.g. `State::cycle_layout`), and
+/// brings the layout menu up to date.
+fn arrange_windows<F: FnOnce(&State) -> Result<(), String>>(
+    state: &Arc<State>,
+    toonmux: &Arc<ui::Toonmux>,
+    arrange: F,
+) -> Result<(), String> {
+    let result = arrange(state);
+    rebuild_layout_menu(state, toonmux);
+    toonmux.control.notify(state);
+
+    result
+}
+
+fn handle_watch_event(
+    state: &Arc<State>,
+    toonmux: &Arc<ui::Toonmux>,
+    event: WatchEvent,
+) {
+    match event {
+        WatchEvent::Destroyed(window) => detach_gone(state, toonmux, window),
+        WatchEvent::Unmapped(window) => {
+            // Minimizing a window can unmap it too, so we give the window
+            // manager a moment to make up its mind about what happened.
+            let state = Arc::clone(state);
+            let toonmux = Arc::clone(toonmux);
+            glib::timeout_add_local_once(watch::SETTLE, move || {
+                if state.window_watch.is_withdrawn(window) {
+                    detach_gone(&state, &toonmux, window);
+                }
+            });
+        }
+        WatchEvent::WindowsChanged => {
+            if !state.reattach.load(Ordering::SeqCst)
+                || !state.awaiting_windows()
+            {
+                return;
+            }
+
+            // New windows don't get their titles & such right away.
+            let state = Arc::clone(state);
+            let toonmux = Arc::clone(toonmux);
+            glib::timeout_add_local_once(watch::SETTLE, move || {
+                match attach_windows(&state, &toonmux, State::restore_windows)
+                {
+                    Ok(newly_attached) if !newly_attached.is_empty() => {
+                        toonmux.state_changed(&state)
+                    }
+                    Ok(_) => (),
+                    Err(e) => eprintln!("{}", e),
+                }
+            });
+        }
+    }
+}
+
+/// Brings the pick-a-window & launch buttons of the controllers at
+/// `ctl_ixs` back in line with the state.
+fn refresh_controller_status(
+    state: &State,
+    toonmux: &ui::Toonmux,
+    ctl_ixs: &[usize],
+) {
+    // Getting a read lock on the controller state reader-writer lock.
+    let ctls = state.routing.controllers.read().unwrap();
+    // Getting a read lock on controller UIs' reader-writer lock.
+    let ctl_uis = toonmux.interface.controller_uis.read().unwrap();
+
+    for &ctl_ix in ctl_ixs {
+        if let Some((ctl, ctl_ui)) = ctls.get(ctl_ix).zip(ctl_uis.get(ctl_ix))
+        {
+            ui::set_window_attached(
+                &ctl_ui.pick_window,
+                ctl.window.load(Ordering::SeqCst) != 0,
+            );
+            ui::set_process(&ctl_ui.launch, ctl);
+        }
+    }
+
+    // Relinquishing read lock on controller UIs' reader-writer lock.
+    drop(ctl_uis);
+    // Relinquishing read lock on the controller state reader-writer lock.
+    drop(ctls);
+
+    toonmux.control.notify(state);
+}
+
+/// Detaches the controllers whose window was `window`, which has gone away.
+fn detach_gone(state: &Arc<State>, toonmux: &Arc<ui::Toonmux>, window: u64) {
+    let detached = state.window_gone(window);
+    if detached.is_empty() {
+        return;
+    }
+
+    // Getting a read lock on controller UIs' reader-writer lock.
+    let ctl_uis = toonmux.interface.controller_uis.read().unwrap();
+
+    for ctl_ui in detached.iter().filter_map(|&i| ctl_uis.get(i)) {
+        ui::set_window_attached(&ctl_ui.pick_window, false);
+    }
+
+    // Relinquishing read lock on controller UIs' reader-writer lock.
+    drop(ctl_uis);
+
+    toonmux.state_changed(state);
+}
+
+#[inline]
+fn route_key_release(state: &Arc<State>, event_key: Key) {
+    let routed = Router::new(&state.routing).release(&event_key);
+    perform(state, &routed.events);
+    state.record(&routed.events);
+    schedule_staggered(state, routed.staggered);
+}
+
+/// Queues up operations that are to be held back (see `Routed::staggered`),
+/// and sets a timer for each one, which performs whatever is due by then.
+fn schedule_staggered(
+    state: &Arc<State>,
+    staggered: Vec<(Duration, OutputEvent)>,
+) {
+    for (delay, event) in staggered {
+        let due_in = state.routing.stagger.push(event, delay);
+
+        let state = Arc::clone(state);
+        glib::timeout_add_local_once(due_in, move || {
+            let events = state.routing.stagger.take_due();
+            perform(&state, &events);
+            state.record(&events);
+        });
+    }
+}
+
+fn perform(state: &State, events: &[OutputEvent]) {
+    let mut windows = Vec::new();
+    for event in events {
+        if let Err(e) = state.output.perform(event) {
+            eprintln!(
+                "{}: sending {} failed with: {}",
+                state.output.kind(),
+                event.kind,
+                e,
+            );
+        }
+
+        if !windows.contains(&event.window) {
+            windows.push(event.window);
+        }
+    }
+
+    // Any input at all counts as activity, as far as keeping toons alive is
+    // concerned.
+    for window in windows {
+        keep_alive::touch(state, window);
+    }
+}
+
+/// Brings everything that depends on which profile is active back in line
+/// with the state, after switching profiles.
+fn refresh_profile_ui(
+    state: &Arc<State>,
+    toonmux: &Arc<ui::Toonmux>,
+    dialog_flags: DialogFlags,
+) {
+    toonmux
+        .header
+        .set_profile_name(&state.active_profile_name());
+    rebuild_action_uis(state, toonmux, dialog_flags);
+    rebuild_profile_menu(state, toonmux, dialog_flags);
+    rebuild_controller_uis(state, toonmux, dialog_flags);
+}
+
+/// Brings the action columns' labels & main key buttons back in line with
+/// the state, as different profiles can have different actions.  Like
+/// `rebuild_controller_uis`, this is deferred until the main loop is idle.
+fn rebuild_action_uis(
+    state: &Arc<State>,
+    toonmux: &Arc<ui::Toonmux>,
+    dialog_flags: DialogFlags,
+) {
+    let state = Arc::clone(state);
+    let toonmux = Arc::clone(toonmux);
+    glib::idle_add_local_once(move || {
+        toonmux.interface.rebuild_actions(&state);
+        hook_up_action_uis(&state, &toonmux, dialog_flags);
+    });
+}
+
+/// Fills the header's profile menu with one item per profile, followed by the
+/// profile management items.  Like `rebuild_controller_uis`, this is deferred
+/// until the main loop is idle, since it may be called from one of the menu's
+/// own items.
+fn rebuild_profile_menu(
+    state: &Arc<State>,
+    toonmux: &Arc<ui::Toonmux>,
+    dialog_flags: DialogFlags,
+) {
+    let state = Arc::clone(state);
+    let toonmux = Arc::clone(toonmux);
+    glib::idle_add_local_once(move || {
+        let menu = &toonmux.header.profile_menu;
+        for child in menu.children() {
+            menu.remove(&child);
+        }
+
+        let active_ix = state.active_profile.load(Ordering::SeqCst);
+        let profile_names: Vec<_> = state
+            .profiles
+            .read()
+            .unwrap()
+            .iter()
+            .map(|p| p.name.clone())
+            .collect();
+        for (profile_ix, name) in profile_names.into_iter().enumerate() {
+            let item = if profile_ix == active_ix {
+                gtk::MenuItem::with_label(&format!("\u{2713} {}", name))
+            } else {
+                gtk::MenuItem::with_label(&name)
+            };
+
+            let state = Arc::clone(&state);
+            let toonmux_ref = Arc::clone(&toonmux);
+            item.connect_activate(move |_| {
+                if profile_ix != state.active_profile.load(Ordering::SeqCst) {
+                    state.switch_profile(profile_ix);
+                    refresh_profile_ui(&state, &toonmux_ref, dialog_flags);
+                }
+            });
+            menu.append(&item);
+        }
+
+        menu.append(&gtk::SeparatorMenuItem::new());
+
+        let new_item = gtk::MenuItem::with_label("new profile\u{2026}");
+        {
+            let state = Arc::clone(&state);
+            let toonmux_ref = Arc::clone(&toonmux);
+            new_item.connect_activate(move |_| {
+                if let Some(name) =
+                    prompt_name(&toonmux_ref, dialog_flags, "New profile", "")
+                {
+                    match state.add_profile(name) {
+                        Ok(()) => refresh_profile_ui(
+                            &state,
+                            &toonmux_ref,
+                            dialog_flags,
+                        ),
+                        Err(e) => show_error(&toonmux_ref, dialog_flags, &e),
+                    }
+                }
+            });
+        }
+        menu.append(&new_item);
+
+        let rename_item = gtk::MenuItem::with_label("rename profile\u{2026}");
+        {
+            let state = Arc::clone(&state);
+            let toonmux_ref = Arc::clone(&toonmux);
+            rename_item.connect_activate(move |_| {
+                if let Some(name) = prompt_name(
+                    &toonmux_ref,
+                    dialog_flags,
+                    "Rename profile",
+                    &state.active_profile_name(),
+                ) {
+                    match state.rename_profile(
+                        state.active_profile.load(Ordering::SeqCst),
+                        name,
+                    ) {
+                        Ok(()) => {
+                            toonmux_ref.header.set_profile_name(
+                                &state.active_profile_name(),
+                            );
+                            rebuild_profile_menu(
+                                &state,
+                                &toonmux_ref,
+                                dialog_flags,
+                            );
+                            toonmux_ref.state_changed(&state);
+                        }
+                        Err(e) => show_error(&toonmux_ref, dialog_flags, &e),
+                    }
+                }
+            });
+        }
+        menu.append(&rename_item);
+
+        let remove_item = gtk::MenuItem::with_label("remove profile");
+        {
+            let state = Arc::clone(&state);
+            let toonmux_ref = Arc::clone(&toonmux);
+            remove_item.connect_activate(move |_| {
+                match state.remove_active_profile() {
+                    Ok(()) => {
+                        refresh_profile_ui(&state, &toonmux_ref, dialog_flags)
+                    }
+                    Err(e) => show_error(&toonmux_ref, dialog_flags, &e),
+                }
+            });
+        }
+        menu.append(&remove_item);
+
+        menu.show_all();
+    });
+}
+
+/// Fills the header's layout menu with one item per layout, followed by an
+/// item for putting the windows back where they were.  Like
+/// `rebuild_profile_menu`, this is deferred until the main loop is idle.
+fn rebuild_layout_menu(state: &Arc<State>, toonmux: &Arc<ui::Toonmux>) {
+    let state = Arc::clone(state);
+    let toonmux = Arc::clone(toonmux);
+    glib::idle_add_local_once(move || {
+        toonmux
+            .header
+            .set_layout_name(state.active_layout_name().as_deref());
+
+        let menu = &toonmux.header.layout_menu;
+        for child in menu.children() {
+            menu.remove(&child);
+        }
+
+        let active_ix = state.active_layout.load(Ordering::SeqCst);
+        let layout_names: Vec<_> = state
+            .layouts
+            .read()
+            .unwrap()
+            .iter()
+            .map(|l| l.name.clone())
+            .collect();
+        for (layout_ix, name) in layout_names.into_iter().enumerate() {
+            let item = if layout_ix == active_ix {
+                gtk::MenuItem::with_label(&format!("\u{2713} {}", name))
+            } else {
+                gtk::MenuItem::with_label(&name)
+            };
+
+            let state = Arc::clone(&state);
+            let toonmux_ref = Arc::clone(&toonmux);
+            item.connect_activate(move |_| {
+                if let Err(e) = arrange_windows(&state, &toonmux_ref, |s| {
+                    s.apply_layout(layout_ix)
+                }) {
+                    eprintln!("{}", e);
+                }
+            });
+            menu.append(&item);
+        }
+
+        menu.append(&gtk::SeparatorMenuItem::new());
+
+        let restore_item = gtk::MenuItem::with_label("restore");
+        {
+            let state = Arc::clone(&state);
+            let toonmux_ref = Arc::clone(&toonmux);
+            restore_item.connect_activate(move |_| {
+                if let Err(e) = arrange_windows(
+                    &state,
+                    &toonmux_ref,
+                    State::restore_layout,
+                ) {
+                    eprintln!("{}", e);
+                }
+            });
+        }
+        restore_item.set_sensitive(
+            !state.original_geometries.lock().unwrap().is_empty(),
+        );
+        menu.append(&restore_item);
+
+        menu.show_all();
+    });
+}
+
+/// Fills the header's macros menu with one item per macro (for playing or
+/// stopping it), followed by items for recording new macros.  Like
+/// `rebuild_profile_menu`, this is deferred until the main loop is idle.
+fn rebuild_macros_menu(
+    state: &Arc<State>,
+    toonmux: &Arc<ui::Toonmux>,
+    dialog_flags: DialogFlags,
+) {
+    let state = Arc::clone(state);
+    let toonmux = Arc::clone(toonmux);
+    glib::idle_add_local_once(move || {
+        let recording = state.recording_macro_name();
+        let playing = state.playing_macro_name();
+        toonmux
+            .header
+            .set_macro_status(recording.as_deref(), playing.as_deref());
+
+        let menu = &toonmux.header.macros_menu;
+        for child in menu.children() {
+            menu.remove(&child);
+        }
+
+        let macro_names: Vec<_> = state
+            .routing
+            .macros
+            .read()
+            .unwrap()
+            .iter()
+            .map(|m| m.name.clone())
+            .collect();
+        for (macro_ix, name) in macro_names.into_iter().enumerate() {
+            let item = if playing.as_ref() == Some(&name) {
+                gtk::MenuItem::with_label(&format!("\u{25a0} {}", name))
+            } else {
+                gtk::MenuItem::with_label(&format!("\u{25b6} {}", name))
+            };
+
+            let state = Arc::clone(&state);
+            let toonmux_ref = Arc::clone(&toonmux);
+            item.connect_activate(move |_| {
+                if let Err(e) =
+                    toggle_macro(&state, &toonmux_ref, dialog_flags, macro_ix)
+                {
+                    show_error(&toonmux_ref, dialog_flags, &e);
+                }
+            });
+            menu.append(&item);
+        }
+
+        menu.append(&gtk::SeparatorMenuItem::new());
+
+        if let Some(name) = recording {
+            let stop_item = gtk::MenuItem::with_label(&format!(
+                "stop recording \u{201c}{}\u{201d}",
+                name,
+            ));
+            let state = Arc::clone(&state);
+            let toonmux_ref = Arc::clone(&toonmux);
+            stop_item.connect_activate(move |_| {
+                if let Err(e) = state.stop_recording() {
+                    show_error(&toonmux_ref, dialog_flags, &e);
+                }
+                toonmux_ref.state_changed(&state);
+                rebuild_macros_menu(&state, &toonmux_ref, dialog_flags);
             });
-        }};
+            menu.append(&stop_item);
+        } else {
+            let ctl_count = state.routing.controllers.read().unwrap().len();
+            for ctl_ix in 0..ctl_count {
+                let record_item = gtk::MenuItem::with_label(&format!(
+                    "record for controller {}\u{2026}",
+                    ctl_ix + 1,
+                ));
+                let state = Arc::clone(&state);
+                let toonmux_ref = Arc::clone(&toonmux);
+                record_item.connect_activate(move |_| {
+                    if let Some(name) = prompt_name(
+                        &toonmux_ref,
+                        dialog_flags,
+                        "Record macro",
+                        "",
+                    ) {
+                        if let Err(e) = state.start_recording(ctl_ix, name) {
+                            show_error(&toonmux_ref, dialog_flags, &e);
+                        }
+                        rebuild_macros_menu(
+                            &state,
+                            &toonmux_ref,
+                            dialog_flags,
+                        );
+                    }
+                });
+                menu.append(&record_item);
+            }
+        }
+
+        menu.show_all();
+    });
+}
+
+/// Asks the user for a name (e.g. of a profile), with a dialog.  Returns
+/// `None` if the user cancelled.
+fn prompt_name(
+    toonmux: &ui::Toonmux,
+    dialog_flags: DialogFlags,
+    title: &str,
+    initial_name: &str,
+) -> Option<String> {
+    let name_dialog = Dialog::with_buttons(
+        Some(title),
+        Some(&toonmux.main_window),
+        dialog_flags,
+        &[
+            ("OK", ResponseType::Accept),
+            ("Cancel", ResponseType::Cancel),
+        ],
+    );
+    name_dialog.set_default_response(ResponseType::Accept);
+
+    let entry = gtk::Entry::new();
+    entry.set_text(initial_name);
+    entry.set_activates_default(true);
+    name_dialog
+        .content_area()
+        .pack_start(&entry, true, false, 4);
+
+    name_dialog.show_all();
+    let resp = name_dialog.run();
+    let name = entry.text().to_string();
+    // See the comment on `key_choose_dialog.destroy()`.
+    unsafe {
+        name_dialog.destroy();
     }
 
-    // Hook up keybinding buttons.
-    connect_key_binder!(forward, "forward", Simple);
-    connect_key_binder!(back, "back", Simple);
-    connect_key_binder!(left, "left", Simple);
-    connect_key_binder!(right, "right", Simple);
-    connect_key_binder!(jump, "jump", Simple);
-    connect_key_binder!(dismount, "dismount", Simple);
-    connect_key_binder!(throw, "throw", Simple);
-    connect_key_binder!(low_throw, "low throw", LowThrow);
-    connect_key_binder!(talk, "talk", Talk);
+    if resp == ResponseType::Accept {
+        Some(name)
+    } else {
+        None
+    }
 }
 
-fn hook_up_mirror_menu(
+/// Asks the user for the range that the controller at `ctl_ix` holds the keys
+/// from its mirror back by, starting out at `initial`, with a dialog.  Returns
+/// `None` if the user cancelled.  The dialog only goes up to a minute, which
+/// is plenty for toons that are supposed to keep up with each other.
+fn prompt_delay(
+    toonmux: &ui::Toonmux,
+    dialog_flags: DialogFlags,
+    ctl_ix: usize,
+    initial: stagger::Delay,
+) -> Option<stagger::Delay> {
+    let delay_dialog = Dialog::with_buttons(
+        Some(&format!("Mirror delay of controller {}", ctl_ix + 1)),
+        Some(&toonmux.main_window),
+        dialog_flags,
+        &[
+            ("OK", ResponseType::Accept),
+            ("Cancel", ResponseType::Cancel),
+        ],
+    );
+    delay_dialog.set_default_response(ResponseType::Accept);
+
+    let grid = gtk::Grid::new();
+    grid.set_row_spacing(4);
+    grid.set_column_spacing(8);
+    let spin_buttons: Vec<_> =
+        [("Minimum (ms)", initial.min), ("Maximum (ms)", initial.max)]
+            .into_iter()
+            .enumerate()
+            .map(|(row, (label, millis))| {
+                let spin_button =
+                    gtk::SpinButton::with_range(0.0, 60_000.0, 10.0);
+                spin_button.set_value(millis as f64);
+                spin_button.set_activates_default(true);
+                grid.attach(&Label::new(Some(label)), 0, row as i32, 1, 1);
+                grid.attach(&spin_button, 1, row as i32, 1, 1);
+
+                spin_button
+            })
+            .collect();
+    delay_dialog
+        .content_area()
+        .pack_start(&grid, true, false, 4);
+
+    delay_dialog.show_all();
+    let resp = delay_dialog.run();
+    let delay = stagger::Delay {
+        min: spin_buttons[0].value_as_int() as u64,
+        max: spin_buttons[1].value_as_int() as u64,
+    };
+    // See the comment on `key_choose_dialog.destroy()`.
+    unsafe {
+        delay_dialog.destroy();
+    }
+
+    if resp == ResponseType::Accept {
+        Some(delay)
+    } else {
+        None
+    }
+}
+
+/// Tells the user about `message` (e.g. why something they asked for can't
+/// be done), with a dialog.
+fn show_error(
+    toonmux: &ui::Toonmux,
+    dialog_flags: DialogFlags,
+    message: &str,
+) {
+    eprintln!("{}", message);
+
+    let error_dialog = Dialog::with_buttons(
+        Some("toonmux"),
+        Some(&toonmux.main_window),
+        dialog_flags,
+        &[("OK", ResponseType::Accept)],
+    );
+    error_dialog.set_default_response(ResponseType::Accept);
+
+    let label = Label::new(Some(message));
+    label.set_line_wrap(true);
+    label.set_max_width_chars(60);
+    error_dialog
+        .content_area()
+        .pack_start(&label, true, false, 8);
+
+    error_dialog.show_all();
+    error_dialog.run();
+    // See the comment on `key_choose_dialog.destroy()`.
+    unsafe {
+        error_dialog.destroy();
+    }
+}
+
+fn remove_controller(
+    state: &Arc<State>,
+    toonmux: &Arc<ui::Toonmux>,
+    dialog_flags: DialogFlags,
+    ctl_ix: usize,
+) {
+    state.remove_controller(ctl_ix);
+    rebuild_controller_uis(state, toonmux, dialog_flags);
+}
+
+/// Brings the controller UIs back in line with the controller state, after
+/// controllers have been removed or rearranged.  This is deferred until the
+/// main loop is idle, as we are often being called from a signal handler of
+/// one of the very widgets that is about to be destroyed.
+fn rebuild_controller_uis(
+    state: &Arc<State>,
+    toonmux: &Arc<ui::Toonmux>,
+    dialog_flags: DialogFlags,
+) {
+    toonmux.state_changed(state);
+    // The macros menu has an item per controller.
+    rebuild_macros_menu(state, toonmux, dialog_flags);
+
+    let state = Arc::clone(state);
+    let toonmux = Arc::clone(toonmux);
+    glib::idle_add_local_once(move || {
+        toonmux.interface.rebuild_controllers(&state);
+        hook_up_controller_uis(&state, &toonmux, dialog_flags);
+        toonmux.main_window.resize(1, 1);
+    });
+}
+
+/// Gets the index of the controller being dragged, from the drag-and-drop
+/// payload set up in `hook_up_controller_ui`.
+#[inline]
+fn dragged_controller(sel: &gtk::SelectionData) -> Option<usize> {
+    String::from_utf8(sel.data()).ok()?.parse().ok()
+}
+
+#[inline]
+fn hook_up_controller_uis(
+    state: &Arc<State>,
+    toonmux: &Arc<ui::Toonmux>,
+    dialog_flags: DialogFlags,
+) {
+    // Getting a read lock on controller UIs' reader-writer lock.
+    let ctl_uis = toonmux.interface.controller_uis.read().unwrap();
+
+    for (ctl_ix, ctl_ui) in ctl_uis.iter().enumerate() {
+        hook_up_controller_ui(state, toonmux, dialog_flags, ctl_ix, ctl_ui);
+    }
+
+    // Relinquishing read lock on controller UIs' reader-writer lock.
+}
+
+fn hook_up_controller_ui(
     state: &Arc<State>,
     toonmux: &Arc<ui::Toonmux>,
+    dialog_flags: DialogFlags,
     ctl_ix: usize,
     ctl_ui: &ui::ControllerUi,
 ) {
-    for (i, mirror_menu_item) in ctl_ui
-        .mirror
-        .menu
-        .children()
-        .into_iter()
-        .map(|c| c.downcast::<gtk::MenuItem>().unwrap())
-        .enumerate()
-        .filter(|(_, mmi)| {
-            mmi.downcast_ref::<gtk::SeparatorMenuItem>().is_none()
-        })
+    // Hook up the pick-a-window button.
+    {
+        let state = Arc::clone(state);
+        let toonmux = Arc::clone(toonmux);
+        ctl_ui.pick_window.connect_clicked(move |pw| {
+            if let Some(new_window) = state.xdo.select_window_with_click() {
+                // Getting a read lock on the controller state reader-writer
+                // lock.
+                let ctls = state.routing.controllers.read().unwrap();
+
+                let old_window = ctls[ctl_ix].set_window(new_window);
+
+                if new_window != old_window {
+                    ui::set_window_attached(pw, new_window != 0);
+                }
+
+                // Relinquishing read lock on the controller state
+                // reader-writer lock.
+                drop(ctls);
+
+                state.rewatch();
+                toonmux.control.notify(&state);
+            }
+        });
+    }
+
+    // Hook up the drag handle, which can be used to move this controller to
+    // another controller's position.
+    ctl_ui.handle.connect_drag_data_get(move |_, _, sel, _, _| {
+        sel.set(&sel.target(), 8, ctl_ix.to_string().as_bytes());
+    });
+    {
+        let state = Arc::clone(state);
+        let toonmux = Arc::clone(toonmux);
+        ctl_ui.handle.connect_drag_data_received(
+            move |_, _, _, _, sel, _, _| {
+                if let Some(from) = dragged_controller(sel) {
+                    if from != ctl_ix {
+                        state.move_controller(from, ctl_ix);
+                        rebuild_controller_uis(&state, &toonmux, dialog_flags);
+                    }
+                }
+            },
+        );
+    }
+
+    // Hook up the mirror menu.
+    hook_up_mirror_menu(state, toonmux, dialog_flags, ctl_ix, ctl_ui);
+
+    // Hook up the launch button.
+    {
+        let state = Arc::clone(state);
+        let toonmux = Arc::clone(toonmux);
+        ctl_ui.launch.connect_clicked(move |_| {
+            if let Err(e) = state.launch(ctl_ix) {
+                eprintln!("{}", e);
+            }
+            refresh_controller_status(&state, &toonmux, &[ctl_ix]);
+        });
+    }
+
+    // Hook up the click mirroring toggle.
+    {
+        let state = Arc::clone(state);
+        let toonmux = Arc::clone(toonmux);
+        ctl_ui.mirror_clicks.connect_toggled(move |mc| {
+            let on = mc.is_active();
+            if let Err(e) = state.set_mirror_clicks(ctl_ix, on) {
+                eprintln!("{}", e);
+
+                return;
+            }
+            ui::set_mirror_clicks(mc, on);
+
+            toonmux.state_changed(&state);
+        });
+    }
+
+    // Hook up the keep-alive toggle.
     {
-        hook_up_mirror_menu_item(state, toonmux, ctl_ix, i, mirror_menu_item);
+        let state = Arc::clone(state);
+        let toonmux = Arc::clone(toonmux);
+        ctl_ui.keep_alive.connect_toggled(move |ka| {
+            let on = ka.is_active();
+            if let Err(e) = state.set_keep_alive(ctl_ix, on) {
+                eprintln!("{}", e);
+
+                return;
+            }
+            ui::set_keep_alive(ka, on);
+
+            toonmux.state_changed(&state);
+        });
+    }
+
+    // Hook up keybinding buttons.
+    let actions: Vec<_> = state
+        .routing
+        .actions
+        .read()
+        .unwrap()
+        .iter()
+        .map(|a| a.name.clone())
+        .collect();
+    for (action, binding) in actions.into_iter().zip(ctl_ui.bindings.iter()) {
+        hook_up_key_binder(
+            state,
+            toonmux,
+            dialog_flags,
+            ctl_ix,
+            binding,
+            action,
+        );
     }
 }
 
-/// `i` is the index of `mirror_menu_item`.
-fn hook_up_mirror_menu_item(
+/// Makes `button` pop up a dialog for binding the action named `action` of
+/// the controller at `ctl_ix`.
+fn hook_up_key_binder(
     state: &Arc<State>,
     toonmux: &Arc<ui::Toonmux>,
+    dialog_flags: DialogFlags,
     ctl_ix: usize,
-    i: usize,
-    mirror_menu_item: gtk::MenuItem,
+    button: &gtk::Button,
+    action: String,
 ) {
     let state = Arc::clone(state);
     let toonmux = Arc::clone(toonmux);
-    mirror_menu_item.connect_activate(move |_| {
+    let label = state::binding_label(&action);
+    button.connect_clicked(move |this| {
+        let key_choose_dialog = Dialog::with_buttons(
+            Some(&format!("Binding \u{201c}{}\u{201d} key", label)),
+            Some(&toonmux.main_window),
+            dialog_flags,
+            &[
+                ("Clear", ResponseType::Other(0)),
+                ("Cancel", ResponseType::Cancel),
+            ],
+        );
+        key_choose_dialog.content_area().pack_start(
+            &Label::new(Some(&format!(
+                "Press a key to be bound to \u{201c}{}\u{201d}.",
+                label,
+            ))),
+            true,
+            false,
+            4,
+        );
+
+        // Chords are bound as soon as their non-modifier key is pressed.
+        // Modifier keys are only bound on their release, so that pressing one
+        // on its way to a chord doesn't bind it.
+        let bind_chord = {
+            let state = Arc::clone(&state);
+            let action = action.clone();
+            move |kcd: &Dialog, e: &gdk::EventKey| {
+                let chord =
+                    Chord::new(unmodified_key(e), Mods::from_gdk(e.state()));
+                match state.bind(ctl_ix, &action, chord) {
+                    // Relinquish control to main window.
+                    Ok(()) => kcd.response(ResponseType::Accept),
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        kcd.response(ResponseType::Cancel);
+                    }
+                }
+            }
+        };
         {
-            // Getting a read lock on the controller state reader-writer
-            // lock.
-            let ctls = state.controllers.read().unwrap();
+            let bind_chord = bind_chord.clone();
+            key_choose_dialog.connect_key_press_event(move |kcd, e| {
+                if Mods::of_key(unmodified_key(e)).is_empty() {
+                    bind_chord(kcd, e);
+                }
+
+                Propagation::Proceed
+            });
+        }
+        key_choose_dialog.connect_key_release_event(move |kcd, e| {
+            if !Mods::of_key(unmodified_key(e)).is_empty() {
+                bind_chord(kcd, e);
+            }
+
+            Propagation::Proceed
+        });
+
+        key_choose_dialog.show_all();
+        let resp = key_choose_dialog.run();
+        // Unfortunately this method is now considered `unsafe`, but that's
+        // only because it's considered UB to call any methods on the object
+        // after destroying it, as you would expect.  We _do_ want to destroy
+        // the dialog here, rather than making it unresponsive until the user
+        // manually presses the 'X' button.
+        unsafe {
+            key_choose_dialog.destroy();
+        }
+
+        match resp {
+            // User pressed a key. State manipulation is already done by that
+            // handler so we just need to update what is displayed in the UI.
+            ResponseType::Accept => {
+                if let Some(action_ix) = state.routing.action_index(&action) {
+                    this.set_label(&chord_name(
+                        state.routing.controllers.read().unwrap()[ctl_ix]
+                            .bindings[action_ix]
+                            .load(Ordering::SeqCst),
+                    ));
+                }
+            }
+            // User pressed "Clear" button. We have to do state manipulation
+            // here in addition to updating the UI because this is effectively
+            // the "handler" for the "pressing the Clear button" event.
+            ResponseType::Other(0) => {
+                // Store new (and empty) binding, and perform rerouting.
+                if let Err(e) = state.bind(ctl_ix, &action, Chord::default()) {
+                    eprintln!("{}", e);
+                }
+
+                this.set_label("");
+            }
+            // The user has cancelled.
+            _ => (),
+        }
+
+        toonmux.state_changed(&state);
+    });
+}
+
+/// Hooks up the main key buttons of the actions, which have to be hooked up
+/// again whenever they get rebuilt.
+fn hook_up_action_uis(
+    state: &Arc<State>,
+    toonmux: &Arc<ui::Toonmux>,
+    dialog_flags: DialogFlags,
+) {
+    let actions: Vec<_> = state
+        .routing
+        .actions
+        .read()
+        .unwrap()
+        .iter()
+        .map(|a| a.name.clone())
+        .collect();
+
+    // Getting a read lock on the main key UIs' reader-writer lock.
+    let buttons = toonmux.interface.main_bindings_row.actions.read().unwrap();
+
+    for (action, button) in actions.iter().zip(buttons.iter()) {
+        hook_up_main_key_binder(state, toonmux, dialog_flags, button, action);
+    }
+
+    // Relinquishing read lock on the main key UIs' reader-writer lock.
+}
+
+/// Makes `button` pop up a dialog for binding the main binding named
+/// `binding` (see `State::bind_main`).
+fn hook_up_main_key_binder(
+    state: &Arc<State>,
+    toonmux: &Arc<ui::Toonmux>,
+    dialog_flags: DialogFlags,
+    button: &gtk::Button,
+    binding: &str,
+) {
+    let state = Arc::clone(state);
+    let toonmux_ref = Arc::clone(toonmux);
+    let binding = binding.to_owned();
+    let label = state::binding_label(&binding);
+    button.connect_clicked(move |_| {
+        let key_choose_dialog = Dialog::with_buttons(
+            Some(&format!("Binding main \u{201c}{}\u{201d} key", label)),
+            Some(&toonmux_ref.main_window),
+            dialog_flags,
+            &[
+                ("Clear", ResponseType::Other(0)),
+                ("Cancel", ResponseType::Cancel),
+            ],
+        );
+        key_choose_dialog.content_area().pack_start(
+            &Label::new(Some(&format!(
+                "Press a key to be bound to \u{201c}{}\u{201d}.",
+                label,
+            ))),
+            true,
+            false,
+            4,
+        );
+
+        {
+            let state = Arc::clone(&state);
+            let binding = binding.clone();
+            key_choose_dialog.connect_key_press_event(move |kcd, e| {
+                // Store the new binding.  This also makes sure that we aren't
+                // registering a duplicate main binding.
+                match state.bind_main(&binding, unmodified_key(e)) {
+                    // Relinquish control to main window.
+                    Ok(()) => kcd.response(ResponseType::Accept),
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        kcd.response(ResponseType::Cancel);
+                    }
+                }
+
+                Propagation::Proceed
+            });
+        }
+
+        key_choose_dialog.show_all();
+        let resp = key_choose_dialog.run();
+        // See the comment on `key_choose_dialog.destroy()` in
+        // `hook_up_key_binder`.
+        unsafe {
+            key_choose_dialog.destroy();
+        }
+
+        match resp {
+            // User pressed a key. State manipulation is already done by that
+            // handler so we just need to update what is displayed in the UI.
+            ResponseType::Accept => (),
+            // User pressed "Clear" button.
+            ResponseType::Other(0) => {
+                if let Err(e) = state.bind_main(&binding, Key::from(0)) {
+                    eprintln!("{}", e);
+                }
+            }
+            // The user has cancelled.
+            _ => return,
+        }
+
+        toonmux_ref.interface.main_bindings_row.refresh(&state);
+        toonmux_ref.state_changed(&state);
+    });
+}
+
+fn hook_up_mirror_menu(
+    state: &Arc<State>,
+    toonmux: &Arc<ui::Toonmux>,
+    dialog_flags: DialogFlags,
+    ctl_ix: usize,
+    ctl_ui: &ui::ControllerUi,
+) {
+    for (i, mirror_menu_item) in ctl_ui.mirror.targets() {
+        hook_up_mirror_menu_item(
+            state,
+            toonmux,
+            dialog_flags,
+            ctl_ix,
+            i,
+            mirror_menu_item,
+        );
+    }
+
+    // Other controllers' mirrors may have changed since the menu was last
+    // shown.
+    {
+        let state = Arc::clone(state);
+        let toonmux = Arc::clone(toonmux);
+        ctl_ui.mirror.menu.connect_show(move |_| {
+            if let Some(ctl_ui) =
+                toonmux.interface.controller_uis.read().unwrap().get(ctl_ix)
+            {
+                ctl_ui.mirror.refresh(&state, ctl_ix);
+            }
+        });
+    }
+    {
+        let state = Arc::clone(state);
+        let toonmux = Arc::clone(toonmux);
+        ctl_ui.mirror.delay.connect_activate(move |_| {
+            let Some(old_delay) = state
+                .routing
+                .controllers
+                .read()
+                .unwrap()
+                .get(ctl_ix)
+                .map(|ctl| *ctl.mirror_delay.read().unwrap())
+            else {
+                return;
+            };
+            let Some(delay) =
+                prompt_delay(&toonmux, dialog_flags, ctl_ix, old_delay)
+            else {
+                return;
+            };
+            if let Err(e) = state.routing.set_mirror_delay(ctl_ix, delay) {
+                show_error(&toonmux, dialog_flags, &e);
 
-            let ctl = &ctls[ctl_ix];
-            let new_mirror = i.wrapping_sub(1);
+                return;
+            }
 
-            // Store the new `mirror` value.
-            let old_mirror = ctl.mirror.swap(new_mirror, Ordering::SeqCst);
+            if let Some(ctl_ui) =
+                toonmux.interface.controller_uis.read().unwrap().get(ctl_ix)
+            {
+                ui::set_mirror_delay(&ctl_ui.mirror.button, delay);
+            }
+            toonmux.state_changed(&state);
+        });
+    }
+    {
+        let state = Arc::clone(state);
+        let toonmux = Arc::clone(toonmux);
+        ctl_ui.mirror.follow_chains.connect_toggled(move |fc| {
+            let on = fc.is_active();
+            if on == state.routing.transitive_mirroring.load(Ordering::SeqCst)
+            {
+                return;
+            }
+            if let Err(e) = state.set_transitive_mirroring(on) {
+                fc.set_active(!on);
+                show_error(&toonmux, dialog_flags, &e);
 
-            // Get rid of the `mirrored` entry for this controller, if any.
-            if old_mirror != ::std::usize::MAX {
-                ctls[old_mirror].mirrored.remove(ctl_ix);
+                return;
             }
 
-            // If we do set a mirror (i.e. not "none"), then update the
-            // mirror's `mirrored` set to contain this controller.
-            if new_mirror != ::std::usize::MAX {
-                ctls[new_mirror].mirrored.insert(ctl_ix);
+            toonmux.state_changed(&state);
+        });
+    }
+
+    for (action_ix, copies_item) in ctl_ui.mirror.copies.iter().enumerate() {
+        let state = Arc::clone(state);
+        let toonmux = Arc::clone(toonmux);
+        copies_item.connect_toggled(move |ci| {
+            if let Err(e) = state.routing.set_mirror_action(
+                ctl_ix,
+                action_ix,
+                ci.is_active(),
+            ) {
+                show_error(&toonmux, dialog_flags, &e);
+
+                return;
             }
 
-            // Relinquishing read lock on the controller state
-            // reader-writer lock.
+            toonmux.state_changed(&state);
+        });
+    }
+}
+
+/// `i` is the index of `mirror_menu_item`.
+fn hook_up_mirror_menu_item(
+    state: &Arc<State>,
+    toonmux: &Arc<ui::Toonmux>,
+    dialog_flags: DialogFlags,
+    ctl_ix: usize,
+    i: usize,
+    mirror_menu_item: gtk::MenuItem,
+) {
+    let state = Arc::clone(state);
+    let toonmux = Arc::clone(toonmux);
+    mirror_menu_item.connect_activate(move |_| {
+        if let Err(e) = state.set_mirror(ctl_ix, i.wrapping_sub(1)) {
+            show_error(&toonmux, dialog_flags, &e);
+
+            return;
         }
 
         // Getting a read lock on controller UIs' reader-writer lock.
@@ -827,5 +2091,8 @@ fn hook_up_mirror_menu_item(
         }
 
         // Relinquishing read lock on controller UIs' reader-writer lock.
+        drop(ctl_uis);
+
+        toonmux.state_changed(&state);
     });
 }
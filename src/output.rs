@@ -0,0 +1,206 @@
+//! Backends that actually deliver keys to game windows.  Which one gets used
+//! is chosen by the `output` field of the config file.
+
+use crate::{xdo::Xdo, xsend::XSendEvent, xtest::XTest};
+use gdk::keys::Key;
+use serde::{Deserialize, Serialize};
+use std::{fmt, sync::Mutex};
+use x11::xlib::Window;
+
+pub trait OutputBackend: fmt::Debug {
+    fn kind(&self) -> BackendKind;
+
+    fn send_key_down(&self, window: Window, key: &Key) -> Result<(), String>;
+
+    fn send_key_up(&self, window: Window, key: &Key) -> Result<(), String>;
+
+    /// Presses & releases a mouse button at a position within a window.
+    fn send_click(&self, click: &Click) -> Result<(), String>;
+
+    /// Sends a key down that is immediately followed by a key up.
+    #[inline]
+    fn send_key(&self, window: Window, key: &Key) -> Result<(), String> {
+        self.send_key_down(window, key)?;
+        self.send_key_up(window, key)
+    }
+
+    /// Performs a single event, e.g. one that was produced by the router.
+    #[inline]
+    fn perform(&self, event: &OutputEvent) -> Result<(), String> {
+        match event.kind {
+            OutputEventKind::Down => {
+                self.send_key_down(event.window, &event.key)
+            }
+            OutputEventKind::Up => self.send_key_up(event.window, &event.key),
+            OutputEventKind::Tap => self.send_key(event.window, &event.key),
+        }
+    }
+
+    /// `Some` iff this is the "recording" backend.
+    #[inline(always)]
+    fn as_recorder(&self) -> Option<&Recorder> {
+        None
+    }
+}
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    /// libxdo, which is what toonmux has always used.
+    #[default]
+    Xdo,
+    /// Plain Xlib `XSendEvent`s, sent straight to the target window.
+    XSendEvent,
+    /// The XTEST extension.  Because XTEST events always go to whatever
+    /// window has keyboard focus, this backend moves focus around as needed.
+    XTest,
+    /// Doesn't send anything anywhere; just records what _would_ have been
+    /// sent.
+    Recording,
+}
+
+/// A backend that stores every event in memory instead of sending it.
+#[derive(Debug, Default)]
+pub struct Recorder {
+    events: Mutex<Vec<OutputEvent>>,
+    clicks: Mutex<Vec<Click>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputEvent {
+    pub window: Window,
+    pub key: Key,
+    pub kind: OutputEventKind,
+}
+
+/// A mouse click, at `x` & `y` relative to the top-left corner of `window`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Click {
+    pub window: Window,
+    pub x: i32,
+    pub y: i32,
+    /// `1` for the left button, `2` for the middle, `3` for the right, and
+    /// so on.
+    pub button: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputEventKind {
+    Down,
+    Up,
+    /// Down immediately followed by up.
+    Tap,
+}
+
+impl BackendKind {
+    pub fn instantiate(self) -> Result<Box<dyn OutputBackend>, String> {
+        match self {
+            Self::Xdo => Xdo::new()
+                .map(|xdo| Box::new(xdo) as Box<dyn OutputBackend>)
+                .ok_or_else(|| "Failed to initialize xdo".to_owned()),
+            Self::XSendEvent => XSendEvent::new()
+                .map(|xse| Box::new(xse) as Box<dyn OutputBackend>)
+                .ok_or_else(|| "Failed to connect to the X server".to_owned()),
+            Self::XTest => XTest::new()
+                .map(|xt| Box::new(xt) as Box<dyn OutputBackend>)
+                .ok_or_else(|| {
+                    "Failed to connect to the X server, or the XTEST \
+                     extension is unavailable"
+                        .to_owned()
+                }),
+            Self::Recording => Ok(Box::<Recorder>::default()),
+        }
+    }
+}
+
+impl fmt::Display for BackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Xdo => "xdo",
+            Self::XSendEvent => "xsendevent",
+            Self::XTest => "xtest",
+            Self::Recording => "recording",
+        })
+    }
+}
+
+impl fmt::Display for OutputEventKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Down => "key down",
+            Self::Up => "key up",
+            Self::Tap => "key",
+        })
+    }
+}
+
+impl Recorder {
+    /// Removes, and returns, everything that has been recorded so far.
+    pub fn take_events(&self) -> Vec<OutputEvent> {
+        std::mem::take(&mut self.events.lock().unwrap())
+    }
+
+    /// Removes, and returns, every click that has been recorded so far.
+    pub fn take_clicks(&self) -> Vec<Click> {
+        std::mem::take(&mut self.clicks.lock().unwrap())
+    }
+
+    #[inline]
+    fn record(&self, window: Window, key: &Key, kind: OutputEventKind) {
+        // Just like every other backend, we don't send anything when there's
+        // no window.
+        if window == 0 {
+            return;
+        }
+
+        self.events.lock().unwrap().push(OutputEvent {
+            window,
+            key: *key,
+            kind,
+        });
+    }
+}
+
+impl OutputBackend for Recorder {
+    #[inline(always)]
+    fn kind(&self) -> BackendKind {
+        BackendKind::Recording
+    }
+
+    #[inline]
+    fn send_key_down(&self, window: Window, key: &Key) -> Result<(), String> {
+        self.record(window, key, OutputEventKind::Down);
+
+        Ok(())
+    }
+
+    #[inline]
+    fn send_key_up(&self, window: Window, key: &Key) -> Result<(), String> {
+        self.record(window, key, OutputEventKind::Up);
+
+        Ok(())
+    }
+
+    #[inline]
+    fn send_key(&self, window: Window, key: &Key) -> Result<(), String> {
+        self.record(window, key, OutputEventKind::Tap);
+
+        Ok(())
+    }
+
+    #[inline]
+    fn send_click(&self, click: &Click) -> Result<(), String> {
+        if click.window != 0 {
+            self.clicks.lock().unwrap().push(*click);
+        }
+
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn as_recorder(&self) -> Option<&Recorder> {
+        Some(self)
+    }
+}
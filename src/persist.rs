@@ -0,0 +1,200 @@
+//! Saving the config file.  Saves are done atomically (write to a temporary
+//! file, fsync, then rename over the old file), and the previous few configs
+//! are kept around as rotating backups (`config.json.1` being the most
+//! recent).
+
+use crate::{json, state::State};
+use glib::ControlFlow;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// How many previous configs to keep around.
+const BACKUP_COUNT: usize = 5;
+
+/// How long to wait after a change before saving, so that a flurry of changes
+/// only results in a single save.
+const DEBOUNCE: Duration = Duration::from_millis(1_000);
+
+/// How often to save regardless, in order to catch changes that don't
+/// explicitly schedule a save.  Nothing actually gets written unless
+/// something changed.
+const PERIOD: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub struct Saver {
+    config_path: PathBuf,
+    /// If set, nothing is ever actually written.
+    read_only: bool,
+    /// What was last written to (or read from) `config_path`.
+    last_saved: Mutex<String>,
+    /// Bumped every time that a save is scheduled, so that only the most
+    /// recently scheduled save actually happens.
+    generation: AtomicUsize,
+}
+
+impl Saver {
+    pub fn new(config_path: PathBuf, read_only: bool) -> Self {
+        let last_saved = fs::read_to_string(&config_path).unwrap_or_default();
+
+        Self {
+            config_path,
+            read_only,
+            last_saved: Mutex::new(last_saved),
+            generation: AtomicUsize::new(0),
+        }
+    }
+
+    /// Saves `state` some time soon, unless another save gets scheduled
+    /// before then, in which case only that one happens.
+    pub fn schedule(self: &Arc<Self>, state: &Arc<State>) {
+        if self.read_only {
+            return;
+        }
+
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+
+        let saver = Arc::clone(self);
+        let state = Arc::clone(state);
+        glib::timeout_add_local_once(DEBOUNCE, move || {
+            if saver.generation.load(Ordering::SeqCst) == generation {
+                saver.save(&state);
+            }
+        });
+    }
+
+    /// Starts saving `state` periodically.
+    pub fn start_periodic(self: &Arc<Self>, state: &Arc<State>) {
+        if self.read_only {
+            return;
+        }
+
+        let saver = Arc::clone(self);
+        let state = Arc::clone(state);
+        glib::timeout_add_local(PERIOD, move || {
+            saver.save(&state);
+
+            ControlFlow::Continue
+        });
+    }
+
+    /// Whether or not `contents` is exactly what we last saved, i.e. whether
+    /// or not a config file with these contents is one of our own writes.
+    #[inline]
+    pub fn is_saved(&self, contents: &str) -> bool {
+        *self.last_saved.lock().unwrap() == contents
+    }
+
+    /// Considers `state` saved, without writing anything.  This is used when
+    /// `state` was just read from the config file in the first place.
+    pub fn mark_saved(&self, state: &State) {
+        if let Some(serialized) = serialize(state) {
+            *self.last_saved.lock().unwrap() = serialized;
+        }
+    }
+
+    /// Saves `state` right now, if it's changed since the last save.
+    pub fn save(&self, state: &State) {
+        if self.read_only {
+            return;
+        }
+
+        let serialized = match serialize(state) {
+            Some(s) => s,
+            None => return,
+        };
+
+        let mut last_saved = self.last_saved.lock().unwrap();
+        if *last_saved == serialized {
+            return;
+        }
+
+        if let Err(ioe) =
+            write_atomically(&self.config_path, serialized.as_bytes())
+        {
+            eprintln!(
+                "Writing to {} failed with the following error:\n\t{}\nThis \
+                 is what should have been written:\n{}",
+                self.config_path.display(),
+                ioe,
+                serialized,
+            );
+
+            return;
+        }
+
+        *last_saved = serialized;
+    }
+}
+
+fn serialize(state: &State) -> Option<String> {
+    json::State::from_state_ref(state)
+        .to_json_string()
+        .map_err(|e| eprintln!("Serializing the config failed with: {}", e))
+        .ok()
+}
+
+/// Replaces the contents of the file at `path` with `contents`, such that
+/// the file is never observed to be partially written, and the old contents
+/// become the newest backup.
+fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let parent = path.parent().unwrap_or_else(|| Path::new("."));
+    fs::create_dir_all(parent)?;
+
+    let file_name = path
+        .file_name()
+        .map_or_else(|| "config.json".into(), |name| name.to_string_lossy());
+    if path.exists() {
+        let backup_path =
+            |n: usize| path.with_file_name(format!("{}.{}", file_name, n));
+
+        for n in (1..BACKUP_COUNT).rev() {
+            match fs::rename(backup_path(n), backup_path(n + 1)) {
+                Err(ioe) if ioe.kind() != io::ErrorKind::NotFound => {
+                    return Err(ioe);
+                }
+                _ => (),
+            }
+        }
+
+        // Copying (rather than renaming) means that there's never a moment
+        // where `path` doesn't exist.
+        fs::copy(path, backup_path(1))?;
+    }
+
+    // The PID & random suffix keep other instances of toonmux (or of this
+    // function) from writing to the same temporary file at the same time.
+    let tmp_path = path.with_file_name(format!(
+        "{}.{}.{:08x}.tmp",
+        file_name,
+        process::id(),
+        glib::random_int(),
+    ));
+    let mut f = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&tmp_path)?;
+    if let Err(ioe) = f.write_all(contents).and_then(|_| f.sync_all()) {
+        let _ = fs::remove_file(&tmp_path);
+
+        return Err(ioe);
+    }
+    drop(f);
+
+    if let Err(ioe) = fs::rename(&tmp_path, path) {
+        let _ = fs::remove_file(&tmp_path);
+
+        return Err(ioe);
+    }
+
+    // Make sure that the rename itself hits the disk.
+    File::open(parent)?.sync_all()
+}
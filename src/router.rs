@@ -0,0 +1,1038 @@
+//! The routing core.  A `Router` takes canonical key presses/releases and
+//! works out which key operations need to be performed on which game windows.
+//! Nothing in here knows about GTK; the GTK window, the global key grab, and
+//! any other source of input just feed their key events in, and then hand the
+//! resulting operations to an output backend.
+
+use crate::{
+    key::{Chord, Mods},
+    macros::Macro,
+    output::{OutputEvent, OutputEventKind},
+    stagger::{self, Delay},
+    state::{
+        self, ActionDef, AtomicBitSet, AtomicKey, Controller, MainBindings,
+        SendMode,
+    },
+};
+use gdk::keys::Key;
+use rustc_hash::FxHashMap;
+use std::{
+    iter,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, RwLock,
+    },
+    time::Duration,
+};
+
+#[derive(Debug)]
+pub struct Router<'a> {
+    routing: &'a Routing,
+}
+
+/// Everything that routing goes by: the live actions, bindings & controllers,
+/// and what's going on with the keys that they route.  None of it involves
+/// the X server, so a `Routing` can be made up on its own, e.g. for tests;
+/// `State` owns the real one, and keeps the key grabs and such in line with
+/// it.
+#[derive(Debug)]
+pub struct Routing {
+    pub mirroring: AtomicBool,
+    /// Whether or not mirroring follows chains of mirrors, i.e. whether or
+    /// not a controller that mirrors a mirroring controller gets the keys
+    /// that it gets from its own mirror, too.  While this is on, mirrors
+    /// can't loop.
+    pub transitive_mirroring: AtomicBool,
+    /// The actions of the active profile, i.e. the columns of the UI.
+    pub actions: RwLock<Vec<ActionDef>>,
+    pub main_bindings: MainBindings,
+    pub controllers: RwLock<Vec<Controller>>,
+    /// Each route is a `(controller index, action index)` pair.
+    pub routes: RwLock<FxHashMap<Chord, Vec<(usize, usize)>>>,
+    pub talking: AtomicBitSet,
+    pub macros: RwLock<Vec<Macro>>,
+    /// The last key that was pressed, if it hasn't been released since.
+    /// Used to tell auto-repeated presses of macro keys apart from real
+    /// ones.
+    pub held_macro_key: AtomicKey,
+    /// Keys that are being held back from mirroring controllers (see
+    /// `Controller::mirror_delay`).
+    pub stagger: stagger::Queue,
+    /// The modifiers of the routed chord that each currently held-down key
+    /// was matched as, so that releases go to the same place as the
+    /// corresponding presses, no matter what happened to the modifiers in
+    /// the meantime.
+    pub held: Mutex<FxHashMap<Key, Mods>>,
+}
+
+/// The result of routing a single key event.
+#[derive(Debug, Default)]
+pub struct Routed {
+    /// Operations to perform, in order.
+    pub events: Vec<OutputEvent>,
+    /// `Some(new_value)` iff this event toggled mirroring.
+    pub mirroring: Option<bool>,
+    /// Whether or not this event asks for the next profile to be switched to.
+    /// Unlike mirror toggling, the router doesn't do this itself, as it also
+    /// involves rebuilding the UI.
+    pub cycle_profile: bool,
+    /// Whether or not this event asks for the next window layout to be
+    /// applied.
+    pub cycle_layout: bool,
+    /// The index of the macro whose key this event is, if any.
+    pub play_macro: Option<usize>,
+    /// Whether or not any controller entered or left the "talking" state.
+    pub talking_changed: bool,
+    /// Operations for mirroring controllers that are to be held back by the
+    /// given delays (see `Controller::mirror_delay`), in order.
+    pub staggered: Vec<(Duration, OutputEvent)>,
+}
+
+impl<'a> Router<'a> {
+    #[inline(always)]
+    pub fn new(routing: &'a Routing) -> Self {
+        Self { routing }
+    }
+
+    /// Routes a press of `event_key` while `mods` are held down.  A binding
+    /// for the exact chord wins; failing that, a binding for the plain key is
+    /// used, so that e.g. holding <kbd>Shift</kbd> doesn't stop a toon from
+    /// walking.
+    pub fn press(&self, event_key: &Key, mods: Mods) -> Routed {
+        let routing = self.routing;
+        let mut routed = Routed::default();
+
+        // Handle controllers that are in the "talking" state.
+        let talking = !routing.talking.is_empty();
+        if talking {
+            // Getting a read lock on the controller state reader-writer lock.
+            let ctls = routing.controllers.read().unwrap();
+
+            // Talking isn't mirrored, so nothing here is a follower's, but
+            // it still has to wait for whatever came before it.
+            for i in routing.talking.iter() {
+                routed.send(
+                    routing,
+                    &ctls[i],
+                    false,
+                    OutputEvent {
+                        window: ctls[i].window.load(Ordering::SeqCst),
+                        key: *event_key,
+                        kind: OutputEventKind::Down,
+                    },
+                );
+            }
+
+            // Relinquishing read lock on the controller state reader-writer
+            // lock.
+        }
+
+        // Handle mirror toggling.
+        let mirroring = if !talking
+            && *event_key == routing.main_bindings.toggle_mirroring()
+        {
+            let mirroring =
+                !routing.mirroring.fetch_nand(true, Ordering::SeqCst);
+            routed.mirroring = Some(mirroring);
+
+            mirroring
+        } else {
+            routing.mirroring.load(Ordering::SeqCst)
+        };
+
+        routed.cycle_profile =
+            !talking && *event_key == routing.main_bindings.cycle_profile();
+        routed.cycle_layout =
+            !talking && *event_key == routing.main_bindings.cycle_layout();
+        // Auto-repeated presses of a macro key that is already held down
+        // don't count.
+        if !talking
+            && routing.held_macro_key.swap(**event_key, Ordering::SeqCst)
+                != **event_key
+        {
+            routed.play_macro = routing
+                .macros
+                .read()
+                .unwrap()
+                .iter()
+                .position(|m| m.key != 0 && m.key == **event_key);
+        }
+
+        // Getting a read lock on the routing state reader-writer lock.
+        let routes_state_lock = routing.routes.read().unwrap();
+
+        let matched = routes_state_lock
+            .get_key_value(&Chord::new(*event_key, mods))
+            .or_else(|| {
+                routes_state_lock.get_key_value(&Chord::plain(*event_key))
+            });
+        if let Some((chord, routes)) = matched {
+            routing.held.lock().unwrap().insert(*event_key, chord.mods);
+
+            // Getting a read lock on the action state reader-writer lock.
+            let actions = routing.actions.read().unwrap();
+            // Getting a read lock on the controller state reader-writer lock.
+            let ctls = routing.controllers.read().unwrap();
+
+            for (ctl_ix, action_ix) in routes {
+                let main_controller = &ctls[*ctl_ix];
+                let action = &actions[*action_ix];
+                let key = action.key();
+                if *key == 0 {
+                    continue;
+                }
+
+                let mut handle_action =
+                    |(mirrored_or_ctl_ix, controller): (
+                        usize,
+                        &Controller,
+                    )| {
+                        let follower = mirrored_or_ctl_ix != *ctl_ix;
+                        if follower
+                            && !state::copies_from(
+                                &ctls,
+                                mirrored_or_ctl_ix,
+                                *ctl_ix,
+                                *action_ix,
+                            )
+                        {
+                            return;
+                        }
+                        let window = controller.window.load(Ordering::SeqCst);
+
+                        match action.mode {
+                            SendMode::Hold => {
+                                if !talking {
+                                    routed.send(
+                                        routing,
+                                        controller,
+                                        follower,
+                                        OutputEvent {
+                                            window,
+                                            key,
+                                            kind: OutputEventKind::Down,
+                                        },
+                                    );
+                                }
+                            }
+                            SendMode::Tap => {
+                                if !talking {
+                                    routed.send(
+                                        routing,
+                                        controller,
+                                        follower,
+                                        OutputEvent {
+                                            window,
+                                            key,
+                                            kind: OutputEventKind::Tap,
+                                        },
+                                    );
+                                }
+                            }
+                            SendMode::ChatToggle => {
+                                if follower || !controller.has_mirror() {
+                                    let was_talking = routing
+                                        .talking
+                                        .toggle(mirrored_or_ctl_ix);
+                                    routed.talking_changed = true;
+
+                                    // If this controller was in the "talking"
+                                    // state before, then we've already sent a
+                                    // key down.  So we just send the
+                                    // corresponding key up here.
+                                    routed.send(
+                                        routing,
+                                        controller,
+                                        follower,
+                                        OutputEvent {
+                                            window,
+                                            key,
+                                            kind: if was_talking {
+                                                OutputEventKind::Up
+                                            } else {
+                                                OutputEventKind::Tap
+                                            },
+                                        },
+                                    );
+                                }
+                            }
+                        }
+                    };
+
+                handle_action((*ctl_ix, main_controller));
+                if mirroring {
+                    main_controller
+                        .followers
+                        .iter()
+                        .map(|i| (i, &ctls[i]))
+                        .for_each(&mut handle_action);
+                }
+            }
+
+            // Relinquishing read lock on the controller state reader-writer
+            // lock.
+            // Relinquishing read lock on the action state reader-writer lock.
+        }
+
+        // Relinquishing read lock on the routing state reader-writer lock.
+
+        routed
+    }
+
+    /// Routes a release of `event_key`, to wherever its press was routed.
+    pub fn release(&self, event_key: &Key) -> Routed {
+        let mods = self
+            .routing
+            .held
+            .lock()
+            .unwrap()
+            .remove(event_key)
+            .unwrap_or(Mods::NONE);
+        let _ = self.routing.held_macro_key.compare_exchange(
+            **event_key,
+            0,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        );
+
+        self.route_release(event_key, mods)
+    }
+
+    /// Works out where a release of `event_key`, which was pressed as `mods`
+    /// plus `event_key`, goes, without changing what's held down.
+    fn route_release(&self, event_key: &Key, mods: Mods) -> Routed {
+        let routing = self.routing;
+        let mut routed = Routed::default();
+
+        if !routing.talking.is_empty() {
+            // Handle controllers that are in the "talking" state.
+
+            // Getting a read lock on the controller state reader-writer lock.
+            let ctls = routing.controllers.read().unwrap();
+
+            // Talking isn't mirrored, so nothing here is a follower's, but
+            // it still has to wait for whatever came before it.
+            for i in routing.talking.iter() {
+                routed.send(
+                    routing,
+                    &ctls[i],
+                    false,
+                    OutputEvent {
+                        window: ctls[i].window.load(Ordering::SeqCst),
+                        key: *event_key,
+                        kind: OutputEventKind::Up,
+                    },
+                );
+            }
+
+            // Relinquishing read lock on the controller state reader-writer
+            // lock.
+        } else {
+            let mirroring = routing.mirroring.load(Ordering::SeqCst);
+
+            // Getting a read lock on the routing state reader-writer lock.
+            let routes_state_lock = routing.routes.read().unwrap();
+
+            if let Some(routes) = routes_state_lock.get(&Chord {
+                key: *event_key,
+                mods,
+            }) {
+                // Getting a read lock on the action state reader-writer lock.
+                let actions = routing.actions.read().unwrap();
+                // Getting a read lock on the controller state reader-writer
+                // lock.
+                let ctls = routing.controllers.read().unwrap();
+
+                for (ctl_ix, action_ix) in routes {
+                    let main_controller = &ctls[*ctl_ix];
+                    let action = &actions[*action_ix];
+                    let key = action.key();
+                    if *key == 0 {
+                        continue;
+                    }
+
+                    let mut handle_action =
+                        |(mirrored_or_ctl_ix, controller): (
+                            usize,
+                            &Controller,
+                        )| {
+                            let follower = mirrored_or_ctl_ix != *ctl_ix;
+                            if follower
+                                && !state::copies_from(
+                                    &ctls,
+                                    mirrored_or_ctl_ix,
+                                    *ctl_ix,
+                                    *action_ix,
+                                )
+                            {
+                                return;
+                            }
+
+                            match action.mode {
+                                SendMode::Hold => routed.send(
+                                    routing,
+                                    controller,
+                                    follower,
+                                    OutputEvent {
+                                        window: controller
+                                            .window
+                                            .load(Ordering::SeqCst),
+                                        key,
+                                        kind: OutputEventKind::Up,
+                                    },
+                                ),
+                                SendMode::Tap => (),
+                                SendMode::ChatToggle => (),
+                            }
+                        };
+
+                    handle_action((*ctl_ix, main_controller));
+                    if mirroring {
+                        main_controller
+                            .followers
+                            .iter()
+                            .map(|i| (i, &ctls[i]))
+                            .for_each(&mut handle_action);
+                    }
+                }
+
+                // Relinquishing read lock on the controller state
+                // reader-writer lock.
+                // Relinquishing read lock on the action state reader-writer
+                // lock.
+            }
+
+            // Relinquishing read lock on the routing state reader-writer
+            // lock.
+        }
+
+        routed
+    }
+}
+
+impl Routed {
+    /// Adds `event`, for `controller`, to the operations to perform.  If
+    /// `controller` is a `follower` (i.e. it's only getting `event` because
+    /// it mirrors another controller), then `event` may get held back.  It
+    /// also gets held back if anything before it is still being held back
+    /// for the same window, so that the window gets its keys in order.
+    ///
+    /// Every event for a game window goes through here, as otherwise it
+    /// could overtake one that is being held back.
+    fn send(
+        &mut self,
+        routing: &Routing,
+        controller: &Controller,
+        follower: bool,
+        event: OutputEvent,
+    ) {
+        let delay = if follower {
+            *controller.mirror_delay.read().unwrap()
+        } else {
+            Delay::default()
+        };
+        if !delay.is_zero()
+            || routing.stagger.has_pending(event.window)
+            || self.staggered.iter().any(|(_, e)| e.window == event.window)
+        {
+            self.staggered.push((delay.sample(), event));
+        } else {
+            self.events.push(event);
+        }
+    }
+}
+
+impl Routing {
+    /// Routes `controllers`, which are for `actions`.
+    pub fn new(
+        actions: Vec<ActionDef>,
+        main_bindings: MainBindings,
+        controllers: Vec<Controller>,
+        transitive_mirroring: bool,
+        macros: Vec<Macro>,
+    ) -> Self {
+        let mut routes = FxHashMap::default();
+        for (ctl_ix, ctl) in controllers.iter().enumerate() {
+            state::route_controller(&mut routes, ctl_ix, ctl);
+        }
+
+        Self {
+            mirroring: AtomicBool::new(true),
+            transitive_mirroring: AtomicBool::new(transitive_mirroring),
+            actions: RwLock::new(actions),
+            main_bindings,
+            controllers: RwLock::new(controllers),
+            routes: RwLock::new(routes),
+            talking: AtomicBitSet::new(),
+            macros: RwLock::new(macros),
+            held_macro_key: AtomicKey::new(0),
+            stagger: Default::default(),
+            held: Default::default(),
+        }
+    }
+
+    /// The index of the action named `name`, if there is one.
+    pub fn action_index(&self, name: &str) -> Option<usize> {
+        self.actions
+            .read()
+            .unwrap()
+            .iter()
+            .position(|a| a.name == name)
+    }
+
+    /// Binds the action named `action` of the controller at `ctl_ix` to
+    /// `new_chord` (`Chord::default()` to unbind).  Returns whether or not
+    /// that changed anything, i.e. whether or not the keys need regrabbing.
+    pub fn bind(
+        &self,
+        ctl_ix: usize,
+        action: &str,
+        new_chord: Chord,
+    ) -> Result<bool, String> {
+        let action_ix = self.action_index(action).ok_or_else(|| {
+            format!("There is no action named \"{}\".", action)
+        })?;
+        let old_chord = self
+            .controllers
+            .read()
+            .unwrap()
+            .get(ctl_ix)
+            .ok_or_else(|| format!("There is no controller {}.", ctl_ix))?
+            .bindings[action_ix]
+            .swap(new_chord, Ordering::SeqCst);
+        if old_chord == new_chord {
+            return Ok(false);
+        }
+        self.reroute(ctl_ix, action_ix, &old_chord, &new_chord);
+
+        Ok(true)
+    }
+
+    /// Moves the route for the action at `action_ix` of the controller at
+    /// `ctl_ix` from `old_chord` to `new_chord` (either of which may be
+    /// `Chord::default()`, i.e. nothing).  The caller is responsible for
+    /// regrabbing.
+    pub fn reroute(
+        &self,
+        ctl_ix: usize,
+        action_ix: usize,
+        old_chord: &Chord,
+        new_chord: &Chord,
+    ) {
+        // Getting a write lock on the routing state reader-writer lock.
+        let mut r_lk = self.routes.write().unwrap();
+
+        // If we are rebinding and not adding a fresh new binding.
+        if !old_chord.is_none() {
+            // Remove the old routing.
+            if let Some(dests) = r_lk.get_mut(old_chord) {
+                if let Some(j) =
+                    dests.iter().position(|&dest| dest == (ctl_ix, action_ix))
+                {
+                    dests.swap_remove(j);
+                }
+            }
+        }
+
+        // Add new routing.
+        if !new_chord.is_none() {
+            r_lk.entry(*new_chord)
+                .or_default()
+                .push((ctl_ix, action_ix));
+        }
+
+        // Relinquishing write lock on the routing state reader-writer lock.
+    }
+
+    /// Throws away all routes, and then routes every one of `ctls`.
+    pub fn route_all(&self, ctls: &[Controller]) {
+        // Getting a write lock on the routing state reader-writer lock.
+        let mut r_lk = self.routes.write().unwrap();
+
+        r_lk.clear();
+        for (ctl_ix, ctl) in ctls.iter().enumerate() {
+            state::route_controller(&mut r_lk, ctl_ix, ctl);
+        }
+
+        // Relinquishing write lock on the routing state reader-writer lock.
+    }
+
+    /// Makes the controller at `ctl_ix` mirror the one at `mirror`, or
+    /// nothing if `mirror` is `usize::MAX`.
+    pub fn set_mirror(
+        &self,
+        ctl_ix: usize,
+        mirror: usize,
+    ) -> Result<(), String> {
+        // Getting a read lock on the controller state reader-writer lock.
+        let ctls = self.controllers.read().unwrap();
+
+        // Like the UI, these messages number controllers from 1.
+        if ctl_ix >= ctls.len() {
+            return Err(format!("There is no controller {}.", ctl_ix + 1));
+        }
+        if mirror != usize::MAX && (mirror >= ctls.len() || mirror == ctl_ix) {
+            return Err(format!(
+                "Controller {} can't mirror controller {}.",
+                ctl_ix + 1,
+                mirror + 1,
+            ));
+        }
+        let transitive = self.transitive_mirroring.load(Ordering::SeqCst);
+        if transitive
+            && state::mirror_would_loop(&state::mirrors(&ctls), ctl_ix, mirror)
+        {
+            return Err(format!(
+                "Controller {} can't mirror controller {}, as controller {} \
+                 already follows controller {}, so that would make a loop.",
+                ctl_ix + 1,
+                mirror + 1,
+                mirror + 1,
+                ctl_ix + 1,
+            ));
+        }
+
+        ctls[ctl_ix].mirror.store(mirror, Ordering::SeqCst);
+        state::link_mirrors(&ctls, transitive);
+
+        Ok(())
+
+        // Relinquishing read lock on the controller state reader-writer lock.
+    }
+
+    /// Whether or not making the controller at `ctl_ix` mirror the one at
+    /// `mirror` would make mirrors loop, which isn't allowed while mirroring
+    /// is transitive.
+    pub fn mirror_would_loop(&self, ctl_ix: usize, mirror: usize) -> bool {
+        self.transitive_mirroring.load(Ordering::SeqCst)
+            && state::mirror_would_loop(
+                &state::mirrors(&self.controllers.read().unwrap()),
+                ctl_ix,
+                mirror,
+            )
+    }
+
+    /// The index of some controller whose chain of mirrors loops back around
+    /// to it, if any.
+    pub fn mirror_loop(&self) -> Option<usize> {
+        state::find_mirror_loop(&state::mirrors(
+            &self.controllers.read().unwrap(),
+        ))
+    }
+
+    /// Turns transitive mirroring (see `transitive_mirroring`) on or off.
+    /// It can't be turned on while mirrors loop.
+    pub fn set_transitive_mirroring(&self, on: bool) -> Result<(), String> {
+        // Getting a read lock on the controller state reader-writer lock.
+        let ctls = self.controllers.read().unwrap();
+
+        if on {
+            if let Some(ctl_ix) =
+                state::find_mirror_loop(&state::mirrors(&ctls))
+            {
+                return Err(format!(
+                    "Mirroring can't follow chains of mirrors, as the chain \
+                     of mirrors starting at controller {} loops back around \
+                     to it.",
+                    ctl_ix + 1,
+                ));
+            }
+        }
+        self.transitive_mirroring.store(on, Ordering::SeqCst);
+        state::link_mirrors(&ctls, on);
+
+        Ok(())
+
+        // Relinquishing read lock on the controller state reader-writer lock.
+    }
+
+    /// Sets whether or not the controller at `ctl_ix` copies the action at
+    /// `action_ix` from its mirror.
+    pub fn set_mirror_action(
+        &self,
+        ctl_ix: usize,
+        action_ix: usize,
+        on: bool,
+    ) -> Result<(), String> {
+        // Getting a read lock on the controller state reader-writer lock.
+        let ctls = self.controllers.read().unwrap();
+
+        ctls.get(ctl_ix)
+            .ok_or_else(|| format!("There is no controller {}.", ctl_ix))?
+            .mirror_actions
+            .get(action_ix)
+            .ok_or_else(|| format!("There is no action {}.", action_ix))?
+            .store(on, Ordering::SeqCst);
+
+        Ok(())
+
+        // Relinquishing read lock on the controller state reader-writer lock.
+    }
+
+    /// Sets how long the controller at `ctl_ix` has the keys that it gets
+    /// from its mirror held back.
+    pub fn set_mirror_delay(
+        &self,
+        ctl_ix: usize,
+        delay: Delay,
+    ) -> Result<(), String> {
+        delay.validate()?;
+        *self
+            .controllers
+            .read()
+            .unwrap()
+            .get(ctl_ix)
+            .ok_or_else(|| format!("There is no controller {}.", ctl_ix))?
+            .mirror_delay
+            .write()
+            .unwrap() = delay;
+
+        Ok(())
+    }
+
+    /// Lets go of everything that the controller at `ctl_ix`, and the
+    /// controllers that follow it, hold down in their windows because of it:
+    /// first whatever is still being held back for those windows, and then
+    /// the releases of the held-down keys that were routed there.  Once the
+    /// controller is removed, the releases would have no way to get there.
+    pub fn release_controller(&self, ctl_ix: usize) -> Vec<OutputEvent> {
+        let windows: Vec<_> = {
+            // Getting a read lock on the controller state reader-writer lock.
+            let ctls = self.controllers.read().unwrap();
+
+            let Some(ctl) = ctls.get(ctl_ix) else {
+                return Vec::new();
+            };
+            iter::once(ctl_ix)
+                .chain(ctl.followers.iter())
+                .map(|i| ctls[i].window.load(Ordering::SeqCst))
+                .filter(|&window| window != 0)
+                .collect()
+
+            // Relinquishing read lock on the controller state reader-writer
+            // lock.
+        };
+
+        let mut events = self.stagger.take_for(&windows);
+        let held: Vec<(Key, Mods)> = self
+            .held
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, mods)| (*key, *mods))
+            .collect();
+        let router = Router::new(self);
+        for (key, mods) in held {
+            let routed = router.route_release(&key, mods);
+            events.extend(
+                routed
+                    .events
+                    .into_iter()
+                    .chain(routed.staggered.into_iter().map(|(_, e)| e))
+                    .filter(|e| windows.contains(&e.window)),
+            );
+        }
+
+        events
+    }
+
+    /// Removes, and returns, the controller at index `removed_ix`,
+    /// renumbering all references to the controllers that come after it.
+    pub fn remove_controller(&self, removed_ix: usize) -> Option<Controller> {
+        // Getting a write lock on the controllers state reader-writer lock.
+        let mut ctls = self.controllers.write().unwrap();
+        if removed_ix >= ctls.len() {
+            return None;
+        }
+
+        let removed = ctls.remove(removed_ix);
+        self.renumber(&ctls, state::after_removal(removed_ix));
+
+        Some(removed)
+
+        // Relinquishing write lock on the controllers state reader-writer
+        // lock.
+    }
+
+    /// Moves the controller at index `from` so that it ends up at index `to`,
+    /// shifting the controllers in between over by one.  Returns whether or
+    /// not anything moved.
+    pub fn move_controller(&self, from: usize, to: usize) -> bool {
+        // Getting a write lock on the controllers state reader-writer lock.
+        let mut ctls = self.controllers.write().unwrap();
+        if from >= ctls.len() || to >= ctls.len() || from == to {
+            return false;
+        }
+
+        let ctl = ctls.remove(from);
+        ctls.insert(to, ctl);
+        self.renumber(&ctls, state::after_move(from, to));
+
+        true
+
+        // Relinquishing write lock on the controllers state reader-writer
+        // lock.
+    }
+
+    /// Renumbers every reference to a controller that is in here (other than
+    /// the positions of the controllers themselves, which the caller has
+    /// already changed) according to `new_ix`, which maps old indices to new
+    /// ones, or to `None` for controllers that no longer exist.
+    ///
+    /// The caller must be holding a write lock on the controller state, and
+    /// pass in the (already rearranged) controllers as `ctls`.
+    fn renumber<F: Fn(usize) -> Option<usize>>(
+        &self,
+        ctls: &[Controller],
+        new_ix: F,
+    ) {
+        for (i, ctl) in ctls.iter().enumerate() {
+            let mirror = ctl.mirror.load(Ordering::SeqCst);
+            if mirror != usize::MAX {
+                ctl.mirror.store(
+                    new_ix(mirror).filter(|&m| m != i).unwrap_or(usize::MAX),
+                    Ordering::SeqCst,
+                );
+            }
+        }
+        state::link_mirrors(
+            ctls,
+            self.transitive_mirroring.load(Ordering::SeqCst),
+        );
+
+        let talking: Vec<_> = self.talking.iter().collect();
+        self.talking.clear();
+        for i in talking.into_iter().filter_map(&new_ix) {
+            self.talking.insert(i);
+        }
+
+        // Getting a write lock on the routing state reader-writer lock.
+        let mut routes = self.routes.write().unwrap();
+
+        for dests in routes.values_mut() {
+            dests.retain_mut(|(ctl_ix, _)| {
+                if let Some(i) = new_ix(*ctl_ix) {
+                    *ctl_ix = i;
+
+                    true
+                } else {
+                    false
+                }
+            });
+        }
+
+        // Relinquishing write lock on the routing state reader-writer lock.
+        drop(routes);
+
+        // Macros of controllers that are gone would otherwise be played back
+        // to whichever controller ends up in their place.
+        self.macros.write().unwrap().retain_mut(|mac| {
+            if let Some(i) = new_ix(mac.controller) {
+                mac.controller = i;
+
+                true
+            } else {
+                eprintln!(
+                    "Dropping macro \"{}\", as its controller is gone.",
+                    mac.name,
+                );
+
+                false
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+impl Routing {
+    /// Three controllers with nothing bound, where controller `i` is
+    /// associated with window `i + 1`.
+    pub fn for_tests() -> Self {
+        let actions = state::default_actions();
+        let controllers: Vec<_> = (0..3)
+            .map(|i| {
+                let ctl = Controller::new(&actions);
+                for binding in ctl.bindings.iter() {
+                    binding.swap(Chord::default(), Ordering::SeqCst);
+                }
+                ctl.set_window(i + 1);
+
+                ctl
+            })
+            .collect();
+
+        Self::new(actions, Default::default(), controllers, false, Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gdk::keys::constants;
+
+    fn event(window: u64, key: Key, kind: OutputEventKind) -> OutputEvent {
+        OutputEvent { window, key, kind }
+    }
+
+    #[test]
+    fn chord_falls_back_to_plain_key() {
+        let routing = Routing::for_tests();
+        routing
+            .bind(0, "forward", Chord::plain(constants::w))
+            .unwrap();
+        routing
+            .bind(0, "back", Chord::new(constants::w, Mods::CONTROL))
+            .unwrap();
+        let router = Router::new(&routing);
+
+        // Nothing is bound to Shift+w, so it counts as plain w.
+        assert_eq!(
+            router.press(&constants::w, Mods::SHIFT).events,
+            [event(1, constants::Up, OutputEventKind::Down)],
+        );
+        router.release(&constants::w);
+
+        // An exact match wins over the plain key.
+        assert_eq!(
+            router.press(&constants::w, Mods::CONTROL).events,
+            [event(1, constants::Down, OutputEventKind::Down)],
+        );
+    }
+
+    #[test]
+    fn release_goes_where_press_went() {
+        let routing = Routing::for_tests();
+        routing
+            .bind(0, "forward", Chord::plain(constants::w))
+            .unwrap();
+        routing
+            .bind(0, "back", Chord::new(constants::w, Mods::CONTROL))
+            .unwrap();
+        let router = Router::new(&routing);
+
+        router.press(&constants::w, Mods::CONTROL);
+        // Ctrl was let go of first, but the release still goes to "back".
+        assert_eq!(
+            router.release(&constants::w).events,
+            [event(1, constants::Down, OutputEventKind::Up)],
+        );
+
+        router.press(&constants::w, Mods::NONE);
+        assert_eq!(
+            router.release(&constants::w).events,
+            [event(1, constants::Up, OutputEventKind::Up)],
+        );
+    }
+
+    #[test]
+    fn removed_controllers_let_go_of_their_keys() {
+        let routing = Routing::for_tests();
+        routing
+            .bind(0, "forward", Chord::plain(constants::w))
+            .unwrap();
+        routing
+            .bind(2, "forward", Chord::plain(constants::w))
+            .unwrap();
+        routing.set_mirror(1, 0).unwrap();
+        let router = Router::new(&routing);
+
+        router.press(&constants::w, Mods::NONE);
+        // Controller 0's follower lets go too, but controller 2, which only
+        // shares the binding, keeps walking.
+        assert_eq!(
+            routing.release_controller(0),
+            [
+                event(1, constants::Up, OutputEventKind::Up),
+                event(2, constants::Up, OutputEventKind::Up),
+            ],
+        );
+        routing.remove_controller(0).unwrap();
+        assert_eq!(
+            router.release(&constants::w).events,
+            [event(3, constants::Up, OutputEventKind::Up)],
+        );
+    }
+
+    #[test]
+    fn chat_toggle_is_suppressed_for_followers() {
+        let routing = Routing::for_tests();
+        routing
+            .bind(0, "talk", Chord::plain(constants::Return))
+            .unwrap();
+        routing
+            .bind(1, "talk", Chord::plain(constants::KP_Enter))
+            .unwrap();
+        routing.set_mirror(1, 0).unwrap();
+        let router = Router::new(&routing);
+
+        // Controller 1 mirrors controller 0, so it can't start talking on its
+        // own.
+        assert!(router
+            .press(&constants::KP_Enter, Mods::NONE)
+            .events
+            .is_empty());
+        assert!(routing.talking.is_empty());
+        router.release(&constants::KP_Enter);
+
+        // Controller 0 doesn't mirror anyone, so both it and its follower
+        // start talking.
+        let routed = router.press(&constants::Return, Mods::NONE);
+        assert!(routed.talking_changed);
+        assert_eq!(
+            routed.events,
+            [
+                event(1, constants::Return, OutputEventKind::Tap),
+                event(2, constants::Return, OutputEventKind::Tap),
+            ],
+        );
+        assert_eq!(routing.talking.iter().collect::<Vec<_>>(), [0, 1]);
+    }
+
+    #[test]
+    fn delayed_followers_get_everything_in_order() {
+        let routing = Routing::for_tests();
+        routing
+            .bind(0, "talk", Chord::plain(constants::Return))
+            .unwrap();
+        routing.set_mirror(1, 0).unwrap();
+        routing
+            .set_mirror_delay(1, Delay { min: 50, max: 50 })
+            .unwrap();
+        let router = Router::new(&routing);
+
+        // The follower's half of the chat toggle is held back...
+        let routed = router.press(&constants::Return, Mods::NONE);
+        assert_eq!(
+            routed
+                .staggered
+                .iter()
+                .map(|(_, e)| e.clone())
+                .collect::<Vec<_>>(),
+            [event(2, constants::Return, OutputEventKind::Tap)],
+        );
+        for (delay, event) in routed.staggered.iter() {
+            routing.stagger.push(event.clone(), *delay);
+        }
+        assert_eq!(
+            routed.events,
+            [event(1, constants::Return, OutputEventKind::Tap)],
+        );
+        router.release(&constants::Return);
+
+        // ...and so is whatever is typed after it, even though talking
+        // isn't mirrored.
+        let routed = router.press(&constants::a, Mods::NONE);
+        assert_eq!(
+            routed
+                .staggered
+                .iter()
+                .map(|(_, e)| e.clone())
+                .collect::<Vec<_>>(),
+            [event(2, constants::a, OutputEventKind::Down)],
+        );
+        assert_eq!(
+            routed.events,
+            [event(1, constants::a, OutputEventKind::Down)],
+        );
+    }
+}
@@ -0,0 +1,129 @@
+//! Delaying the keys that are sent to mirroring controllers, so that toons
+//! don't all do exactly the same thing in exactly the same frame.
+
+use crate::output::OutputEvent;
+use serde::{Deserialize, Serialize};
+use std::{collections::VecDeque, sync::Mutex, time::Duration};
+use x11::xlib::Window;
+
+/// How long (in milliseconds) to hold back the keys that a mirroring
+/// controller gets from the controller that it mirrors.  Each key gets a
+/// random delay between `min` & `max`, inclusive; for a fixed delay, they're
+/// equal.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub struct Delay {
+    pub min: u64,
+    pub max: u64,
+}
+
+/// Keys that are waiting to be sent, in the order that they're due.
+#[derive(Debug, Default)]
+pub struct Queue {
+    /// Each entry is due at the given `glib::monotonic_time()`.  Entries are
+    /// sorted by when they're due, and entries that are due at the same time
+    /// stay in the order that they were pushed in.
+    pending: Mutex<VecDeque<(i64, OutputEvent)>>,
+}
+
+impl Delay {
+    #[inline(always)]
+    pub fn is_zero(&self) -> bool {
+        self.max == 0
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.min > self.max {
+            Err("The minimum mirror delay can't be more than the maximum \
+                 mirror delay."
+                .to_owned())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// A delay somewhere in this range.
+    pub fn sample(&self) -> Duration {
+        let millis = if self.min == self.max {
+            self.min
+        } else {
+            glib::random_double_range(self.min as f64, self.max as f64 + 1.0)
+                as u64
+        };
+
+        Duration::from_millis(millis.min(self.max))
+    }
+}
+
+impl Queue {
+    /// Queues `event` to be sent after `delay`.  If something is already
+    /// queued for the same window, then `event` waits for it, so that the
+    /// window gets its keys in order (e.g. a release never overtakes its
+    /// press).  Returns how long from now `event` is due.
+    pub fn push(&self, event: OutputEvent, delay: Duration) -> Duration {
+        let now = glib::monotonic_time();
+
+        // Getting a lock on the pending events mutex.
+        let mut pending = self.pending.lock().unwrap();
+
+        let due = pending
+            .iter()
+            .filter(|(_, e)| e.window == event.window)
+            .map(|&(due, _)| due)
+            .fold(now + delay.as_micros() as i64, i64::max);
+        let ix = pending.partition_point(|&(d, _)| d <= due);
+        pending.insert(ix, (due, event));
+
+        Duration::from_micros((due - now) as u64)
+
+        // Relinquishing lock on the pending events mutex.
+    }
+
+    /// Whether or not anything is still queued for `window`.
+    pub fn has_pending(&self, window: Window) -> bool {
+        self.pending
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|(_, e)| e.window == window)
+    }
+
+    /// Removes, and returns, every event that is due.
+    pub fn take_due(&self) -> Vec<OutputEvent> {
+        let now = glib::monotonic_time();
+
+        // Getting a lock on the pending events mutex.
+        let mut pending = self.pending.lock().unwrap();
+
+        let due_count = pending.partition_point(|&(d, _)| d <= now);
+        pending.drain(..due_count).map(|(_, e)| e).collect()
+
+        // Relinquishing lock on the pending events mutex.
+    }
+
+    /// Removes, and returns, every event for any of `windows`, due or not.
+    pub fn take_for(&self, windows: &[Window]) -> Vec<OutputEvent> {
+        // Getting a lock on the pending events mutex.
+        let mut pending = self.pending.lock().unwrap();
+
+        let (taken, kept) = pending
+            .drain(..)
+            .partition(|(_, e)| windows.contains(&e.window));
+        *pending = kept;
+
+        taken.into_iter().map(|(_, e)| e).collect()
+
+        // Relinquishing lock on the pending events mutex.
+    }
+
+    /// Removes, and returns, every event, due or not.
+    pub fn take_all(&self) -> Vec<OutputEvent> {
+        self.pending
+            .lock()
+            .unwrap()
+            .drain(..)
+            .map(|(_, e)| e)
+            .collect()
+    }
+}
@@ -1,40 +1,102 @@
-use crate::{json, ui, xdo::Xdo};
+use crate::{
+    clicks::ClickWatch,
+    discover::{self, WindowIdentity, WindowPattern},
+    grab::KeyGrab,
+    json, keep_alive,
+    key::Chord,
+    launch::{LaunchCommand, Process},
+    layout::{self, LayoutPreset, Rect},
+    macros::{Playback, Recording},
+    output::{BackendKind, Click, OutputBackend, OutputEvent},
+    router::{Router, Routing},
+    stagger::Delay,
+    watch::WindowWatch,
+    xdo::Xdo,
+};
 use gdk::keys::{self, Key};
-use gtk::prelude::*;
 use rustc_hash::FxHashMap;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::{
-    fs::File,
-    io::BufReader,
+    cmp, iter, mem,
     path::Path,
     sync::{
-        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
-        RwLock,
+        atomic::{
+            AtomicBool, AtomicI64, AtomicU32, AtomicU64, AtomicUsize, Ordering,
+        },
+        Arc, Mutex, RwLock,
     },
+    time::Duration,
 };
 
 const USIZE_BITS: usize = std::mem::size_of::<usize>() * 8;
 
 pub type AtomicKey = AtomicU32;
 
-#[derive(Debug)]
-pub struct AtomicBitSet(AtomicUsize);
+/// A `Chord`, packed into a single atomic.
+#[derive(Debug, Default)]
+pub struct AtomicChord(AtomicU64);
+
+/// The most controllers that we can have at once.  Each controller is there to
+/// drive a game client of its own, and an X server only takes 256 clients by
+/// default (toonmux itself included), so there's no point in more.  Keeping
+/// it that low also keeps the UI usable, as every controller's mirror menu
+/// has an item for every other controller.
+pub const MAX_CONTROLLERS: usize = 256;
+
+/// A growable set of `usize`s with interior mutability.  Modifications are
+/// copy-on-write, so that iteration always sees a consistent snapshot.
+///
+/// Invariant: the underlying words never have any trailing zero words.
+#[derive(Debug, Default)]
+pub struct AtomicBitSet(RwLock<Arc<Vec<usize>>>);
 
 #[derive(Debug)]
 pub struct BitSetIter {
+    words: Arc<Vec<usize>>,
+    word_ix: usize,
     bits: usize,
-    offset: usize,
 }
 
 #[derive(Debug)]
 pub struct State {
+    /// Only used for picking windows; keys are sent through `output`.
     pub xdo: Xdo,
+    pub output: Box<dyn OutputBackend>,
+    pub key_grab: KeyGrab,
+    pub window_watch: WindowWatch,
+    pub click_watch: ClickWatch,
     pub hidden: AtomicBool,
-    pub mirroring: AtomicBool,
-    pub main_bindings: MainBindings,
-    pub controllers: RwLock<Vec<Controller>>,
-    pub routes: RwLock<FxHashMap<Key, Vec<(usize, Action)>>>,
-    pub talking: AtomicBitSet,
+    /// Whether or not we are capturing routed keys globally (i.e. at the X11
+    /// root window) instead of only when the toonmux window has focus.
+    pub capturing: AtomicBool,
+    /// Every profile.  The entry for the active profile is only brought up
+    /// to date when switching away from it (or when serializing); the live
+    /// version of it is in `routing`.
+    pub profiles: RwLock<Vec<json::Profile>>,
+    pub active_profile: AtomicUsize,
+    pub routing: Routing,
+    pub keep_alive: RwLock<keep_alive::Settings>,
+    pub window_patterns: RwLock<Vec<WindowPattern>>,
+    /// Whether or not to reattach windows like the ones that went away, as
+    /// soon as they show up.
+    pub reattach: AtomicBool,
+    pub layouts: RwLock<Vec<LayoutPreset>>,
+    /// The index of the layout that was applied last.  We use
+    /// `active_layout = usize::MAX` to represent the windows being wherever
+    /// they were before any layout was applied.
+    pub active_layout: AtomicUsize,
+    /// Where each window that got moved by a layout was before that, so that
+    /// it can be put back.
+    pub original_geometries: Mutex<Vec<(u64, Rect)>>,
+    /// The macro that is being recorded, if any.
+    pub recording: Mutex<Option<Recording>>,
+    /// The macro that is being played back, if any.
+    pub playback: Mutex<Option<Playback>>,
+    /// The ID of the next playback (see `Playback::id`).
+    pub next_playback_id: AtomicU64,
+    /// Game clients that are still running, but whose controllers are gone
+    /// (see `orphan`).  They're kept around so that they still get reaped.
+    pub orphans: Mutex<Vec<Process>>,
 }
 
 #[derive(Debug)]
@@ -45,81 +107,267 @@ pub struct Controller {
     /// We use `mirror = usize::MAX` to represent no mirroring ("none").
     pub mirror: AtomicUsize,
     pub mirrored: AtomicBitSet,
-    pub bindings: Bindings,
+    /// Every controller that gets this controller's keys when mirroring: the
+    /// ones in `mirrored`, plus (with transitive mirroring) the ones that
+    /// follow them, in turn.  Recomputed whenever the mirrors change.
+    pub followers: AtomicBitSet,
+    /// One binding per action, in the same order as `State::actions`.
+    pub bindings: Vec<AtomicChord>,
+    /// Whether or not this controller's toon is kept alive when idle.
+    pub keep_alive: AtomicBool,
+    /// Whether or not clicks in the window of this controller's mirror are
+    /// repeated in this controller's window.
+    pub mirror_clicks: AtomicBool,
+    /// Whether or not this controller copies each action from its mirror, in
+    /// the same order as `State::actions`.
+    pub mirror_actions: Vec<AtomicBool>,
+    /// How long to hold back the keys that this controller gets from its
+    /// mirror.
+    pub mirror_delay: RwLock<Delay>,
+    /// When (in `glib::monotonic_time()` microseconds) this controller's toon
+    /// is due to be kept alive next.  `0` means not yet scheduled.
+    pub keep_alive_at: AtomicI64,
+    /// What we remember about the window that this controller had in a
+    /// previous session, until that window is found again, or until this
+    /// controller gets another window.
+    pub saved_window: Mutex<Option<WindowIdentity>>,
+    /// How to start this controller's game client, if toonmux is supposed to
+    /// do that.
+    pub launch: RwLock<Option<LaunchCommand>>,
+    /// The game client that this controller launched last, if any.
+    pub process: Mutex<Process>,
 }
 
+/// The main bindings that aren't actions, i.e. that control toonmux itself
+/// rather than being sent to game windows.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MainBindings {
-    pub forward: AtomicKey,
-    pub back: AtomicKey,
-    pub left: AtomicKey,
-    pub right: AtomicKey,
-    pub jump: AtomicKey,
-    pub dismount: AtomicKey,
-    pub throw: AtomicKey,
-    pub talk: AtomicKey,
     pub toggle_mirroring: AtomicKey,
+    /// Unlike the others, this one is shared by every profile (and so is
+    /// stored at the top level of the config file), so that switching to a
+    /// profile can't leave us stuck there.
+    #[serde(skip)]
+    pub cycle_profile: AtomicKey,
+    #[serde(default)]
+    pub cycle_layout: AtomicKey,
 }
 
+/// Something that controllers can bind a key to: a main key (the one that
+/// actually gets sent to game windows), and how to send it.
 #[derive(Debug, Serialize, Deserialize)]
-pub struct Bindings {
-    pub forward: AtomicKey,
-    pub back: AtomicKey,
-    pub left: AtomicKey,
-    pub right: AtomicKey,
-    pub jump: AtomicKey,
-    pub dismount: AtomicKey,
-    pub throw: AtomicKey,
-    pub low_throw: AtomicKey,
-    pub talk: AtomicKey,
+pub struct ActionDef {
+    pub name: String,
+    pub key: AtomicKey,
+    pub mode: SendMode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SendMode {
+    /// The main key is held down for as long as the bound key is.
+    Hold,
+    /// The main key is tapped (pressed & immediately released) when the
+    /// bound key is pressed.
+    Tap,
+    /// The main key is tapped, and the controller enters (or leaves) the
+    /// "talking" state, in which every key gets passed straight through.
+    ChatToggle,
+}
+
+/// The actions that new profiles start out with, as `(name, main key, send
+/// mode, the key that new controllers bind it to)`.
+const DEFAULT_ACTIONS: [(&str, Key, SendMode, Option<Key>); 13] = [
+    (
+        "forward",
+        keys::constants::Up,
+        SendMode::Hold,
+        Some(keys::constants::Up),
+    ),
+    (
+        "back",
+        keys::constants::Down,
+        SendMode::Hold,
+        Some(keys::constants::Down),
+    ),
+    (
+        "left",
+        keys::constants::Left,
+        SendMode::Hold,
+        Some(keys::constants::Left),
+    ),
+    (
+        "right",
+        keys::constants::Right,
+        SendMode::Hold,
+        Some(keys::constants::Right),
+    ),
+    (
+        "jump",
+        keys::constants::Control_L,
+        SendMode::Hold,
+        Some(keys::constants::Control_L),
+    ),
+    (
+        "dismount",
+        keys::constants::Escape,
+        SendMode::Hold,
+        Some(keys::constants::Escape),
+    ),
+    (
+        "throw",
+        keys::constants::Delete,
+        SendMode::Hold,
+        Some(keys::constants::Delete),
+    ),
+    // A "low throw" is just a throw whose key is released right away.
+    (
+        "low_throw",
+        keys::constants::Delete,
+        SendMode::Tap,
+        Some(keys::constants::Insert),
+    ),
+    (
+        "talk",
+        keys::constants::Return,
+        SendMode::ChatToggle,
+        Some(keys::constants::Return),
+    ),
+    // The in-game keys for opening (and closing) various screens.  These
+    // start out unbound, as the book's key is the same as dismounting's.
+    ("gags", keys::constants::Home, SendMode::Tap, None),
+    ("tasks", keys::constants::End, SendMode::Tap, None),
+    ("map", keys::constants::Alt_L, SendMode::Tap, None),
+    ("book", keys::constants::Escape, SendMode::Tap, None),
+];
+
+/// Why global key capture can't be used along with the XTEST backend.
+const XTEST_CAPTURE_ERROR: &str =
+    "Global key capture can't be used with the xtest output backend, as the \
+     keys that it sends would be captured right back.";
+
+/// The names of the main bindings that aren't actions, which actions
+/// therefore can't be named.
+pub const RESERVED_ACTION_NAMES: [&str; 3] =
+    ["toggle_mirroring", "cycle_profile", "cycle_layout"];
+
+impl AtomicChord {
+    #[inline(always)]
+    pub fn new(chord: Chord) -> Self {
+        Self(AtomicU64::new(chord.pack()))
+    }
+
+    #[inline(always)]
+    pub fn load(&self, order: Ordering) -> Chord {
+        Chord::unpack(self.0.load(order))
+    }
+
+    #[inline(always)]
+    pub fn swap(&self, chord: Chord, order: Ordering) -> Chord {
+        Chord::unpack(self.0.swap(chord.pack(), order))
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub enum Action {
-    Simple(Key),
-    LowThrow(Key),
-    Talk(Key),
+impl Serialize for AtomicChord {
+    #[inline]
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        self.load(Ordering::SeqCst).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for AtomicChord {
+    #[inline]
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        Chord::deserialize(deserializer).map(Self::new)
+    }
 }
 
 impl AtomicBitSet {
     #[inline(always)]
     pub fn new() -> Self {
-        Self(AtomicUsize::new(0))
+        Default::default()
     }
 
     #[inline(always)]
     pub fn is_empty(&self) -> bool {
-        self.0.load(Ordering::SeqCst) == 0
+        self.0.read().unwrap().is_empty()
     }
 
-    #[inline(always)]
+    #[inline]
     pub fn insert(&self, i: usize) {
-        self.0.fetch_or(1 << i, Ordering::SeqCst);
+        self.modify(|words| {
+            let word_ix = i / USIZE_BITS;
+            if word_ix >= words.len() {
+                words.resize(word_ix + 1, 0);
+            }
+
+            words[word_ix] |= 1 << (i % USIZE_BITS);
+        });
     }
 
-    #[inline(always)]
+    #[inline]
     pub fn remove(&self, i: usize) {
-        self.0.fetch_and(!(1 << i), Ordering::SeqCst);
+        self.modify(|words| {
+            if let Some(word) = words.get_mut(i / USIZE_BITS) {
+                *word &= !(1 << (i % USIZE_BITS));
+            }
+        });
+    }
+
+    #[inline]
+    pub fn clear(&self) {
+        *self.0.write().unwrap() = Default::default();
     }
 
     /// Returns the previous value.
-    #[inline(always)]
+    #[inline]
     pub fn toggle(&self, i: usize) -> bool {
-        let mask = 1 << i;
+        let mut was_set = false;
+        self.modify(|words| {
+            let word_ix = i / USIZE_BITS;
+            if word_ix >= words.len() {
+                words.resize(word_ix + 1, 0);
+            }
 
-        self.0.fetch_xor(mask, Ordering::SeqCst) & mask != 0
+            let mask = 1 << (i % USIZE_BITS);
+            was_set = words[word_ix] & mask != 0;
+            words[word_ix] ^= mask;
+        });
+
+        was_set
     }
 
-    /// Only performs **one** load.
-    #[inline(always)]
+    /// Only performs **one** load (of the current snapshot), so concurrent
+    /// modifications are never partially observed.
+    #[inline]
     pub fn iter(&self) -> BitSetIter {
-        let bits = self.0.load(Ordering::SeqCst);
+        let words = Arc::clone(&self.0.read().unwrap());
 
         BitSetIter {
-            bits,
-            // Optimizing for the empty set case.
-            offset: if bits == 0 { ::std::usize::MAX } else { 0 },
+            bits: words.first().copied().unwrap_or(0),
+            words,
+            word_ix: 0,
+        }
+    }
+
+    /// Applies `f` to a private copy of the current words (unless nobody
+    /// else is holding onto them), and then publishes the result.
+    #[inline]
+    fn modify<F: FnOnce(&mut Vec<usize>)>(&self, f: F) {
+        // Getting a write lock on the bitset's reader-writer lock.
+        let mut w_lk = self.0.write().unwrap();
+
+        let words = Arc::make_mut(&mut w_lk);
+        f(words);
+        while words.last() == Some(&0) {
+            words.pop();
         }
+
+        // Relinquishing write lock on the bitset's reader-writer lock.
     }
 }
 
@@ -128,411 +376,2138 @@ impl Iterator for BitSetIter {
 
     #[inline]
     fn next(&mut self) -> Option<Self::Item> {
-        while self.offset < USIZE_BITS {
-            let set_bit = if (self.bits & (1 << self.offset)) != 0 {
-                Some(self.offset)
-            } else {
-                None
-            };
+        loop {
+            if self.bits != 0 {
+                let offset = self.bits.trailing_zeros() as usize;
+                // Clear the lowest set bit.
+                self.bits &= self.bits - 1;
 
-            self.offset += 1;
-
-            if set_bit.is_some() {
-                return set_bit;
+                return Some(self.word_ix * USIZE_BITS + offset);
             }
-        }
 
-        None
+            self.word_ix += 1;
+            self.bits = *self.words.get(self.word_ix)?;
+        }
     }
 }
 
 impl State {
-    /// Returns `None` iff xdo instance creation fails.
+    /// Returns `None` iff xdo instance creation, output backend creation, or
+    /// connecting to the X server, fails.
     pub fn new() -> Option<Self> {
         Xdo::new()
-            .map(|xdo| Self {
-                xdo,
-                hidden: AtomicBool::new(false),
-                mirroring: AtomicBool::new(true),
-                main_bindings: Default::default(),
-                controllers: RwLock::new(vec![
-                    Default::default(),
-                    Default::default(),
-                    Default::default(),
-                ]),
-                routes: Default::default(),
-                talking: AtomicBitSet::new(),
-            })
-            .map(|mut state| {
-                state.init();
-
-                state
+            .zip(BackendKind::default().instantiate().ok())
+            .zip(KeyGrab::new())
+            .zip(WindowWatch::new())
+            .zip(ClickWatch::new())
+            .map(|((((xdo, output), key_grab), window_watch), click_watch)| {
+                let profile = json::Profile::default();
+
+                Self {
+                    xdo,
+                    output,
+                    key_grab,
+                    window_watch,
+                    click_watch,
+                    hidden: AtomicBool::new(false),
+                    capturing: AtomicBool::new(false),
+                    routing: Routing::new(
+                        profile.actions.clone(),
+                        profile.main_bindings.clone(),
+                        controllers_from_json(
+                            profile.controllers.clone(),
+                            &profile.actions,
+                            false,
+                        ),
+                        false,
+                        Vec::new(),
+                    ),
+                    profiles: RwLock::new(vec![profile]),
+                    active_profile: AtomicUsize::new(0),
+                    keep_alive: Default::default(),
+                    window_patterns: RwLock::new(discover::default_patterns()),
+                    reattach: AtomicBool::new(false),
+                    layouts: RwLock::new(layout::default_presets()),
+                    active_layout: AtomicUsize::new(usize::MAX),
+                    original_geometries: Default::default(),
+                    recording: Default::default(),
+                    playback: Default::default(),
+                    next_playback_id: AtomicU64::new(0),
+                    orphans: Default::default(),
+                }
             })
     }
 
     pub fn from_json_file<P: AsRef<Path>>(
         json_path: P,
     ) -> Result<Self, String> {
-        let f = File::open(json_path).map_err(|e| e.to_string())?;
-        let buf_reader = BufReader::new(f);
         let json::State {
+            version: _,
+            mut profiles,
+            active_profile,
+            capture,
+            output,
+            keep_alive,
+            window_patterns,
+            windows,
+            reattach,
+            layouts,
+            macros,
+            transitive_mirroring,
+            cycle_profile,
+        } = json::State::from_file(json_path)?;
+
+        if profiles.is_empty() {
+            profiles.push(Default::default());
+        }
+        let active_ix = profiles
+            .iter()
+            .position(|p| p.name == active_profile)
+            .unwrap_or_else(|| {
+                eprintln!(
+                    "There is no profile named \"{}\"; using \"{}\" \
+                     instead.",
+                    active_profile, profiles[0].name,
+                );
+
+                0
+            });
+        let json::Profile {
+            actions,
             main_bindings,
             controllers,
-        } = json::State::from_reader(buf_reader)?;
-
-        let controllers: Vec<_> = controllers
-            .into_iter()
-            .map(|c| Controller {
-                window: AtomicU64::new(0),
-                mirror: c.mirror,
-                mirrored: AtomicBitSet::new(),
-                bindings: c.bindings.into(),
-            })
-            .collect();
-        for (i, controller) in controllers.iter().enumerate() {
-            let mirror = controller.mirror.load(Ordering::SeqCst);
-            if mirror != ::std::usize::MAX && mirror != i {
-                controllers.get(mirror).map(|c| c.mirrored.insert(i));
-            }
+            ..
+        } = profiles[active_ix].clone();
+        main_bindings
+            .cycle_profile
+            .store(cycle_profile, Ordering::SeqCst);
+        let controllers =
+            controllers_from_json(controllers, &actions, transitive_mirroring);
+        for (ctl, window) in controllers.iter().zip(windows) {
+            *ctl.saved_window.lock().unwrap() = window;
         }
 
         let xdo =
             Xdo::new().ok_or_else(|| "Failed to initialize xdo".to_owned())?;
-
-        let mut state = Self {
+        let output_kind = output;
+        let output = output.instantiate()?;
+        let key_grab = KeyGrab::new()
+            .ok_or_else(|| "Failed to connect to the X server".to_owned())?;
+        let window_watch = WindowWatch::new()
+            .ok_or_else(|| "Failed to connect to the X server".to_owned())?;
+        let click_watch = ClickWatch::new()
+            .ok_or_else(|| "Failed to connect to the X server".to_owned())?;
+
+        Ok(Self {
             xdo,
+            output,
+            key_grab,
+            window_watch,
+            click_watch,
             hidden: AtomicBool::new(false),
-            mirroring: AtomicBool::new(true),
-            main_bindings: main_bindings.into(),
-            controllers: RwLock::new(controllers),
-            routes: Default::default(),
-            talking: AtomicBitSet::new(),
-        };
-        state.init();
+            capturing: AtomicBool::new(capture_allowed(capture, output_kind)),
+            routing: Routing::new(
+                actions,
+                main_bindings,
+                controllers,
+                transitive_mirroring,
+                macros,
+            ),
+            profiles: RwLock::new(profiles),
+            active_profile: AtomicUsize::new(active_ix),
+            keep_alive: RwLock::new(keep_alive),
+            window_patterns: RwLock::new(window_patterns),
+            reattach: AtomicBool::new(reattach),
+            layouts: RwLock::new(layouts),
+            active_layout: AtomicUsize::new(usize::MAX),
+            original_geometries: Default::default(),
+            recording: Default::default(),
+            playback: Default::default(),
+            next_playback_id: AtomicU64::new(0),
+            orphans: Default::default(),
+        })
+    }
+
+    /// Adds a new controller, using the current last controller (if any) as
+    /// a template.  Returns the index of the new controller.
+    pub fn add_controller(&self) -> Result<usize, String> {
+        // Getting a write lock on the controller state reader-writer lock.
+        let mut ctls = self.routing.controllers.write().unwrap();
+
+        if ctls.len() >= MAX_CONTROLLERS {
+            return Err(format!(
+                "Cannot add another controller; toonmux supports at most {} \
+                 controllers.",
+                MAX_CONTROLLERS,
+            ));
+        }
+
+        let new_ctl = ctls
+            .last()
+            .map(Controller::from_template)
+            .unwrap_or_else(|| {
+                Controller::new(&self.routing.actions.read().unwrap())
+            });
+        // Like it always has been, the new controller starts out unrouted,
+        // as its bindings are copies of the template's, and would otherwise
+        // drive both toons at once.  Its bindings get routed as they're
+        // rebound.
+        let ctl_ix = ctls.len();
+        ctls.push(new_ctl);
+
+        Ok(ctl_ix)
+
+        // Relinquishing write lock on the controller state reader-writer
+        // lock.
+    }
+
+    /// Makes the profile at index `profile_ix` the active one, stashing the
+    /// currently active profile away first.  Windows stay associated with
+    /// controllers at the same indices, as far as the new profile's
+    /// controller count allows.
+    pub fn switch_profile(&self, profile_ix: usize) {
+        // Getting a write lock on the profiles reader-writer lock.
+        let mut profiles = self.profiles.write().unwrap();
+        if profile_ix >= profiles.len() {
+            return;
+        }
+
+        let active_ix = self.active_profile.load(Ordering::SeqCst);
+        let active_name = profiles[active_ix].name.clone();
+        profiles[active_ix] = json::Profile::from_state_ref(self, active_name);
+
+        self.load_profile(profiles[profile_ix].clone());
+        self.active_profile.store(profile_ix, Ordering::SeqCst);
+
+        // Relinquishing write lock on the profiles reader-writer lock.
+        drop(profiles);
 
-        Ok(state)
+        self.regrab();
     }
 
-    fn init(&mut self) {
-        // Loading initial routes.
-        let r_lk = self.routes.get_mut().unwrap();
+    /// Replaces the live actions, bindings & controllers with `profile`'s,
+    /// routing everything from scratch.  Windows stay associated with
+    /// controllers at the same indices.  The caller is responsible for
+    /// regrabbing.
+    fn load_profile(&self, profile: json::Profile) {
+        self.release_everything();
+
+        let json::Profile {
+            actions,
+            main_bindings,
+            controllers,
+            ..
+        } = profile;
+        self.routing.main_bindings.set(&main_bindings);
+        let new_ctls = controllers_from_json(
+            controllers,
+            &actions,
+            self.routing.transitive_mirroring.load(Ordering::SeqCst),
+        );
+        *self.routing.actions.write().unwrap() = actions;
 
-        for (ctl_ix, ctl) in
-            self.controllers.get_mut().unwrap().iter().enumerate()
         {
-            macro_rules! route_action {
-                ( $action_id:ident, $action_ty:ident ) => {
-                    let key = ctl.bindings.$action_id.load(Ordering::SeqCst);
-                    if key != 0 {
-                        r_lk.entry(key.into()).or_insert_with(Vec::new).push(
-                            (
-                                ctl_ix,
-                                Action::$action_ty(
-                                    self.main_bindings.$action_id(),
-                                ),
-                            ),
-                        );
-                    }
-                };
+            // Getting a write lock on the controller state reader-writer
+            // lock.
+            let mut ctls = self.routing.controllers.write().unwrap();
+
+            for (new_ctl, old_ctl) in new_ctls.iter().zip(ctls.iter()) {
+                new_ctl.window.store(
+                    old_ctl.window.load(Ordering::SeqCst),
+                    Ordering::SeqCst,
+                );
+                *new_ctl.saved_window.lock().unwrap() =
+                    old_ctl.saved_window.lock().unwrap().take();
+                *new_ctl.process.lock().unwrap() =
+                    mem::take(&mut old_ctl.process.lock().unwrap());
             }
+            for old_ctl in ctls.iter().skip(new_ctls.len()) {
+                self.orphan(old_ctl);
+            }
+            *ctls = new_ctls;
+            self.routing.route_all(&ctls);
 
-            route_action!(forward, Simple);
-            route_action!(back, Simple);
-            route_action!(left, Simple);
-            route_action!(right, Simple);
-            route_action!(jump, Simple);
-            route_action!(dismount, Simple);
-            route_action!(throw, Simple);
-            route_action!(low_throw, LowThrow);
-            route_action!(talk, Talk);
-        }
-    }
-
-    pub fn is_bound_main(&self, key: &Key) -> bool {
-        self.main_bindings.forward.load(Ordering::SeqCst) == **key
-            || self.main_bindings.back.load(Ordering::SeqCst) == **key
-            || self.main_bindings.left.load(Ordering::SeqCst) == **key
-            || self.main_bindings.right.load(Ordering::SeqCst) == **key
-            || self.main_bindings.jump.load(Ordering::SeqCst) == **key
-            || self.main_bindings.dismount.load(Ordering::SeqCst) == **key
-            || self.main_bindings.throw.load(Ordering::SeqCst) == **key
-            || self.main_bindings.talk.load(Ordering::SeqCst) == **key
-    }
-
-    pub fn reroute_main(&self, old_key: &Key, new_key: &Key) {
-        // Getting a write lock on the routing state reader-writer lock.
-        let mut r_lk = self.routes.write().unwrap();
-
-        if **new_key != 0 {
-            r_lk.values_mut().for_each(|dests| {
-                dests
-                    .iter_mut()
-                    .filter(|(_, a)| a.key() == old_key)
-                    .for_each(|(_, a)| a.set_key(new_key.clone()));
-            });
-        } else {
-            r_lk.values_mut().for_each(|dests| {
-                let mut i = 0;
-                while let Some((_, a)) = dests.get(i) {
-                    if a.key() == old_key {
-                        dests.swap_remove(i);
-                    } else {
-                        i += 1;
-                    }
-                }
-            });
+            // Relinquishing write lock on the controller state reader-writer
+            // lock.
         }
 
-        // Relinquishing write lock on the routing state reader-writer lock.
+        self.routing.talking.clear();
     }
 
-    pub fn reroute(
-        &self,
-        ctl_ix: usize,
-        old_key: &Key,
-        new_key: &Key,
-        main_key: &Key,
-        action: Option<Action>,
-    ) {
-        // Getting a write lock on the routing state reader-writer lock.
-        let mut r_lk = self.routes.write().unwrap();
-
-        // If we are rebinding and not adding a fresh new binding.
-        if **old_key != 0 {
-            // Remove the old routing.
-            r_lk.get_mut(&old_key).map(|dests| {
-                dests
-                    .iter()
-                    .enumerate()
-                    .find(|(_, (i, a))| i == &ctl_ix && a.key() == main_key)
-                    .map(|(j, _)| j)
-                    .map(|j| dests.swap_remove(j));
-            });
+    /// Lets go of everything that we're holding down in game windows: keys
+    /// that were pressed through the current routes, keys that are still
+    /// being held back (see `Routing::stagger`), and whatever macro is
+    /// playing.  Once the routes are replaced, the releases would have
+    /// nowhere to go.
+    fn release_everything(&self) {
+        let mut events = self.routing.stagger.take_all();
+
+        let held: Vec<Key> =
+            self.routing.held.lock().unwrap().keys().cloned().collect();
+        for key in held {
+            let routed = Router::new(&self.routing).release(&key);
+            events.extend(routed.events);
+            // There's no point in holding back releases that we're about to
+            // lose track of.
+            events.extend(routed.staggered.into_iter().map(|(_, e)| e));
         }
+        events.extend(self.stop_macro());
+        self.routing.held_macro_key.store(0, Ordering::SeqCst);
 
-        // Add new routing.
-        if let Some(a) = action {
-            r_lk.entry(new_key.clone())
-                .or_insert_with(Vec::new)
-                .push((ctl_ix, a));
-        }
+        self.send_now(&events);
+    }
 
-        // Relinquishing write lock on the routing state reader-writer lock.
+    /// Sends `events` right away, for when there's no caller to hand them
+    /// back to.
+    fn send_now(&self, events: &[OutputEvent]) {
+        for event in events {
+            if let Err(e) = self.output.perform(event) {
+                eprintln!(
+                    "{}: sending {} failed with: {}",
+                    self.output.kind(),
+                    event.kind,
+                    e,
+                );
+            }
+        }
     }
 
-    /// NOTE/FIXME?: The fact that this method takes &ui::Interface is gross.
-    pub fn remove_controller(&self, interface: &ui::Interface) {
-        // Getting a write lock on the controllers state reader-writer lock.
-        let mut ctls = self.controllers.write().unwrap();
-        ctls.pop();
-        let removed_ix = ctls.len();
+    /// Applies a config that was (re-)read while we were already running.
+    /// The active profile is diffed against the live state, so that only
+    /// what actually changed gets rerouted.  If the active profile no longer
+    /// exists, we stay on the profile with the same name as the one that we
+    /// were on, or failing that, the first one.
+    pub fn reload(&self, new_state: json::State) -> Result<(), String> {
+        let json::State {
+            version: _,
+            profiles,
+            active_profile,
+            capture,
+            output,
+            keep_alive,
+            window_patterns,
+            // Live windows are left alone.
+            windows: _,
+            reattach,
+            layouts,
+            macros,
+            transitive_mirroring,
+            cycle_profile,
+        } = new_state;
+
+        if profiles.is_empty() {
+            return Err("The config has no profiles.".to_owned());
+        }
+        if output != self.output.kind() {
+            eprintln!(
+                "Switching from the {} output backend to the {} one requires \
+                 restarting toonmux.",
+                self.output.kind(),
+                output,
+            );
+        }
 
+        let active_name = self.active_profile_name();
+        let active_ix = profiles
+            .iter()
+            .position(|p| p.name == active_profile)
+            .or_else(|| profiles.iter().position(|p| p.name == active_name))
+            .unwrap_or(0);
+        let profile = profiles[active_ix].clone();
         {
-            // Getting a read lock on the controller UIs' reader-writer lock.
-            let ctl_uis = interface.controller_uis.read().unwrap();
-            for (ctl, ctl_ui) in ctls.iter().zip(ctl_uis.iter()) {
-                if ctl.mirror.load(Ordering::SeqCst) == removed_ix {
-                    ctl.mirror.store(::std::usize::MAX, Ordering::SeqCst);
+            // Getting a write lock on the profiles reader-writer lock.
+            let mut profiles_lk = self.profiles.write().unwrap();
 
-                    ctl_ui.mirror.button.set_label("\u{22a3}");
-                }
+            *profiles_lk = profiles;
+            self.active_profile.store(active_ix, Ordering::SeqCst);
 
-                ctl.mirrored.remove(removed_ix);
-            }
+            // Relinquishing write lock on the profiles reader-writer lock.
+        }
 
-            // Relinquishing read lock on the controller UIs' reader-writer
-            // lock.
+        self.capturing.store(
+            capture_allowed(capture, self.output.kind()),
+            Ordering::SeqCst,
+        );
+        *self.keep_alive.write().unwrap() = keep_alive;
+        *self.window_patterns.write().unwrap() = window_patterns;
+        self.reattach.store(reattach, Ordering::SeqCst);
+        self.routing
+            .transitive_mirroring
+            .store(transitive_mirroring, Ordering::SeqCst);
+        self.routing
+            .main_bindings
+            .cycle_profile
+            .store(cycle_profile, Ordering::SeqCst);
+        *self.layouts.write().unwrap() = layouts;
+        *self.routing.macros.write().unwrap() = macros;
+        if profile.controllers.len()
+            == self.routing.controllers.read().unwrap().len()
+            && self.has_same_actions(&profile.actions)
+        {
+            self.apply_profile_diff(profile);
+        } else {
+            // Controllers or actions came or went, so there's no sensible way
+            // to diff.
+            self.load_profile(profile);
         }
 
-        // Getting a write lock on the routing state reader-writer lock.
-        let mut routes = self.routes.write().unwrap();
+        self.regrab();
 
-        for (_, dests) in routes.iter_mut() {
-            let mut i = 0;
-            while let Some((ctl_ix, _)) = dests.get_mut(i) {
-                if *ctl_ix == removed_ix {
-                    dests.swap_remove(i);
-                } else {
-                    i += 1;
+        Ok(())
+    }
+
+    /// Whether or not `actions` are the same as the live actions, main keys
+    /// aside.
+    fn has_same_actions(&self, actions: &[ActionDef]) -> bool {
+        let live_actions = self.routing.actions.read().unwrap();
+
+        live_actions.len() == actions.len()
+            && live_actions
+                .iter()
+                .zip(actions.iter())
+                .all(|(a, b)| a.name == b.name && a.mode == b.mode)
+    }
+
+    /// Brings the live main keys, bindings & mirrors in line with `profile`,
+    /// which must have exactly as many controllers as we do, and the same
+    /// actions (see `has_same_actions`).
+    fn apply_profile_diff(&self, profile: json::Profile) {
+        let json::Profile {
+            actions,
+            main_bindings,
+            controllers,
+            ..
+        } = profile;
+
+        // Routes refer to actions by index, so changing main keys doesn't
+        // involve any rerouting.
+        for (action, new_action) in self
+            .routing
+            .actions
+            .read()
+            .unwrap()
+            .iter()
+            .zip(actions.iter())
+        {
+            action.key.store(
+                new_action.key.load(Ordering::SeqCst),
+                Ordering::SeqCst,
+            );
+        }
+        self.routing.main_bindings.set(&main_bindings);
+
+        let new_ctls = controllers_from_json(
+            controllers,
+            &actions,
+            self.routing.transitive_mirroring.load(Ordering::SeqCst),
+        );
+
+        // Getting a read lock on the controller state reader-writer lock.
+        let ctls = self.routing.controllers.read().unwrap();
+
+        for (ctl_ix, (ctl, new_ctl)) in
+            ctls.iter().zip(new_ctls.iter()).enumerate()
+        {
+            for (action_ix, (binding, new_binding)) in
+                ctl.bindings.iter().zip(new_ctl.bindings.iter()).enumerate()
+            {
+                let new_chord = new_binding.load(Ordering::SeqCst);
+                let old_chord = binding.swap(new_chord, Ordering::SeqCst);
+                if old_chord != new_chord {
+                    self.routing
+                        .reroute(ctl_ix, action_ix, &old_chord, &new_chord);
                 }
             }
-        }
 
-        // Relinquishing write lock on the routing state reader-writer lock.
+            ctl.keep_alive.store(
+                new_ctl.keep_alive.load(Ordering::SeqCst),
+                Ordering::SeqCst,
+            );
+            ctl.mirror_clicks.store(
+                new_ctl.mirror_clicks.load(Ordering::SeqCst),
+                Ordering::SeqCst,
+            );
+            for (mirror_action, new_mirror_action) in
+                ctl.mirror_actions.iter().zip(new_ctl.mirror_actions.iter())
+            {
+                mirror_action.store(
+                    new_mirror_action.load(Ordering::SeqCst),
+                    Ordering::SeqCst,
+                );
+            }
+            *ctl.mirror_delay.write().unwrap() =
+                *new_ctl.mirror_delay.read().unwrap();
+            *ctl.launch.write().unwrap() =
+                new_ctl.launch.read().unwrap().clone();
+
+            let mirror = new_ctl.mirror.load(Ordering::SeqCst);
+            ctl.mirror.store(
+                if mirror < ctls.len() && mirror != ctl_ix {
+                    mirror
+                } else {
+                    usize::MAX
+                },
+                Ordering::SeqCst,
+            );
+        }
+        link_mirrors(
+            &ctls,
+            self.routing.transitive_mirroring.load(Ordering::SeqCst),
+        );
 
-        // Relinquishing write lock on the controllers state reader-writer
+        // Relinquishing read lock on the controller state reader-writer
         // lock.
     }
-}
 
-impl Default for Controller {
-    #[inline]
-    fn default() -> Self {
-        Self {
-            window: AtomicU64::new(0),
-            mirror: AtomicUsize::new(::std::usize::MAX),
-            mirrored: AtomicBitSet::new(),
-            bindings: Default::default(),
+    /// Switches to the profile named `name`.
+    pub fn select_profile(&self, name: &str) -> Result<(), String> {
+        let profile_ix = self
+            .profiles
+            .read()
+            .unwrap()
+            .iter()
+            .position(|p| p.name == name)
+            .ok_or_else(|| {
+                format!("There is no profile named \"{}\".", name)
+            })?;
+        if profile_ix != self.active_profile.load(Ordering::SeqCst) {
+            self.switch_profile(profile_ix);
         }
+
+        Ok(())
     }
-}
 
-impl Controller {
+    /// Switches to the profile after the active one, wrapping around.
     #[inline]
-    pub fn from_template(template: &Self) -> Self {
-        Self {
-            window: AtomicU64::new(0),
-            mirror: AtomicUsize::new(::std::usize::MAX),
-            mirrored: AtomicBitSet::new(),
-            bindings: template.bindings.clone(),
-        }
-    }
+    pub fn cycle_profile(&self) {
+        let profile_count = self.profiles.read().unwrap().len();
+        let active_ix = self.active_profile.load(Ordering::SeqCst);
 
-    #[inline(always)]
-    pub fn has_mirror(&self) -> bool {
-        self.mirror.load(Ordering::SeqCst) != ::std::usize::MAX
+        self.switch_profile((active_ix + 1) % profile_count);
     }
-}
 
-impl MainBindings {
-    #[inline(always)]
-    pub fn forward(&self) -> Key {
-        self.forward.load(Ordering::SeqCst).into()
+    /// Creates a new profile that starts out as a copy of the active one, and
+    /// then switches to it.
+    pub fn add_profile(&self, name: String) -> Result<(), String> {
+        let new_ix = {
+            // Getting a write lock on the profiles reader-writer lock.
+            let mut profiles = self.profiles.write().unwrap();
+
+            validate_profile_name(&profiles, &name)?;
+            profiles.push(json::Profile::from_state_ref(self, name));
+
+            profiles.len() - 1
+
+            // Relinquishing write lock on the profiles reader-writer lock.
+        };
+
+        self.switch_profile(new_ix);
+
+        Ok(())
     }
 
-    #[inline(always)]
-    pub fn back(&self) -> Key {
-        self.back.load(Ordering::SeqCst).into()
+    pub fn rename_profile(
+        &self,
+        profile_ix: usize,
+        name: String,
+    ) -> Result<(), String> {
+        // Getting a write lock on the profiles reader-writer lock.
+        let mut profiles = self.profiles.write().unwrap();
+
+        validate_profile_name(&profiles, &name)?;
+        if let Some(profile) = profiles.get_mut(profile_ix) {
+            profile.name = name;
+        }
+
+        Ok(())
+
+        // Relinquishing write lock on the profiles reader-writer lock.
     }
 
-    #[inline(always)]
-    pub fn left(&self) -> Key {
-        self.left.load(Ordering::SeqCst).into()
+    /// Removes the active profile, switching to the one before it.  The last
+    /// remaining profile cannot be removed.
+    pub fn remove_active_profile(&self) -> Result<(), String> {
+        let active_ix = self.active_profile.load(Ordering::SeqCst);
+        if self.profiles.read().unwrap().len() < 2 {
+            return Err("Cannot remove the only profile.".to_owned());
+        }
+
+        // Switch away first, so that we always have a valid active profile.
+        let next_ix = if active_ix == 0 { 1 } else { active_ix - 1 };
+        self.switch_profile(next_ix);
+        self.profiles.write().unwrap().remove(active_ix);
+        // Removing the old profile shifts everything after it down by one.
+        if next_ix > active_ix {
+            self.active_profile.store(next_ix - 1, Ordering::SeqCst);
+        }
+
+        Ok(())
     }
 
-    #[inline(always)]
-    pub fn right(&self) -> Key {
-        self.right.load(Ordering::SeqCst).into()
+    /// The name of the active profile.
+    #[inline]
+    pub fn active_profile_name(&self) -> String {
+        self.profiles.read().unwrap()
+            [self.active_profile.load(Ordering::SeqCst)]
+        .name
+        .clone()
     }
 
-    #[inline(always)]
-    pub fn jump(&self) -> Key {
-        self.jump.load(Ordering::SeqCst).into()
+    /// Makes the controller at `ctl_ix` mirror the one at `mirror` (see
+    /// `Routing::set_mirror`), and regrabs clicks accordingly.
+    pub fn set_mirror(
+        &self,
+        ctl_ix: usize,
+        mirror: usize,
+    ) -> Result<(), String> {
+        self.routing.set_mirror(ctl_ix, mirror)?;
+        self.regrab_clicks();
+
+        Ok(())
     }
 
-    #[inline(always)]
-    pub fn dismount(&self) -> Key {
-        self.dismount.load(Ordering::SeqCst).into()
+    /// Turns transitive mirroring on or off (see
+    /// `Routing::set_transitive_mirroring`), and regrabs clicks accordingly.
+    pub fn set_transitive_mirroring(&self, on: bool) -> Result<(), String> {
+        self.routing.set_transitive_mirroring(on)?;
+        self.regrab_clicks();
+
+        Ok(())
     }
 
-    #[inline(always)]
-    pub fn throw(&self) -> Key {
-        self.throw.load(Ordering::SeqCst).into()
+    /// Turns keep-alive on or off for the controller at `ctl_ix`.  Turning it
+    /// on (re)schedules the next keep-alive from scratch.
+    pub fn set_keep_alive(
+        &self,
+        ctl_ix: usize,
+        on: bool,
+    ) -> Result<(), String> {
+        // Getting a read lock on the controller state reader-writer lock.
+        let ctls = self.routing.controllers.read().unwrap();
+
+        let ctl = ctls
+            .get(ctl_ix)
+            .ok_or_else(|| format!("There is no controller {}.", ctl_ix))?;
+        ctl.keep_alive_at.store(0, Ordering::SeqCst);
+        ctl.keep_alive.store(on, Ordering::SeqCst);
+
+        Ok(())
+
+        // Relinquishing read lock on the controller state reader-writer lock.
     }
 
-    #[inline(always)]
-    pub fn talk(&self) -> Key {
-        self.talk.load(Ordering::SeqCst).into()
+    /// Turns click mirroring on or off for the controller at `ctl_ix`.
+    pub fn set_mirror_clicks(
+        &self,
+        ctl_ix: usize,
+        on: bool,
+    ) -> Result<(), String> {
+        self.routing
+            .controllers
+            .read()
+            .unwrap()
+            .get(ctl_ix)
+            .ok_or_else(|| format!("There is no controller {}.", ctl_ix))?
+            .mirror_clicks
+            .store(on, Ordering::SeqCst);
+        self.regrab_clicks();
+
+        Ok(())
     }
 
-    #[inline(always)]
-    pub fn toggle_mirroring(&self) -> Key {
-        self.toggle_mirroring.load(Ordering::SeqCst).into()
+    /// Repeats `click`, which is one of those from `mirror_click`, without
+    /// catching it again ourselves (see `ClickWatch::expect`).
+    pub fn send_mirrored_click(&self, click: &Click) -> Result<(), String> {
+        if self.output.kind() == BackendKind::XTest {
+            self.click_watch.expect(click);
+        }
+
+        self.output.send_click(click)
     }
 
-    /// One of these things is not like the others...
-    #[inline(always)]
-    pub fn low_throw(&self) -> Key {
-        self.throw()
+    /// Brings the windows whose clicks are caught (see `click_watch`) in line
+    /// with the controllers: a window's clicks are caught iff some controller
+    /// that mirrors it has click mirroring on.
+    pub fn regrab_clicks(&self) {
+        // Getting a read lock on the controller state reader-writer lock.
+        let ctls = self.routing.controllers.read().unwrap();
+
+        let windows: Vec<_> = ctls
+            .iter()
+            .filter(|ctl| {
+                ctl.followers.iter().any(|i| {
+                    ctls.get(i).is_some_and(|follower| {
+                        follower.mirror_clicks.load(Ordering::SeqCst)
+                            && follower.window.load(Ordering::SeqCst) != 0
+                    })
+                })
+            })
+            .map(|ctl| ctl.window.load(Ordering::SeqCst))
+            .collect();
+
+        // Relinquishing read lock on the controller state reader-writer lock.
+        drop(ctls);
+
+        self.click_watch.grab(windows);
     }
-}
 
-impl Clone for MainBindings {
-    #[inline]
-    fn clone(&self) -> Self {
-        Self {
-            forward: AtomicKey::new(self.forward.load(Ordering::SeqCst)),
-            back: AtomicKey::new(self.back.load(Ordering::SeqCst)),
-            left: AtomicKey::new(self.left.load(Ordering::SeqCst)),
-            right: AtomicKey::new(self.right.load(Ordering::SeqCst)),
-            jump: AtomicKey::new(self.jump.load(Ordering::SeqCst)),
-            dismount: AtomicKey::new(self.dismount.load(Ordering::SeqCst)),
-            throw: AtomicKey::new(self.throw.load(Ordering::SeqCst)),
-            talk: AtomicKey::new(self.talk.load(Ordering::SeqCst)),
-            toggle_mirroring: AtomicKey::new(
-                self.toggle_mirroring.load(Ordering::SeqCst),
-            ),
+    /// Where `click` (in the window of one or more mirrored controllers)
+    /// should be repeated: the same spot, relative to the size of the window,
+    /// in the window of every controller that mirrors them with click
+    /// mirroring on.  Nothing is mirrored while mirroring is off.
+    ///
+    /// Clicks aren't actions, so the actions that a controller copies (see
+    /// `Controller::copies`) don't matter here.  Clicks aren't held back by
+    /// `Controller::mirror_delay` either, as they are made right as the
+    /// caught click is let through, and the game may well react to the
+    /// caught click (e.g. by closing a menu) before a delayed copy would get
+    /// there.
+    pub fn mirror_click(&self, click: &Click) -> Vec<Click> {
+        if !self.routing.mirroring.load(Ordering::SeqCst) {
+            return Vec::new();
         }
-    }
-}
+        let Some((width, height)) = self.click_watch.size(click.window) else {
+            return Vec::new();
+        };
+        let scale = |n: i32, from: i32, to: i32| {
+            if from > 0 {
+                (n as i64 * to as i64 / from as i64) as i32
+            } else {
+                n
+            }
+        };
 
-impl Default for MainBindings {
-    #[inline]
-    fn default() -> Self {
-        Self {
-            forward: AtomicKey::new(*keys::constants::Up),
-            back: AtomicKey::new(*keys::constants::Down),
-            left: AtomicKey::new(*keys::constants::Left),
-            right: AtomicKey::new(*keys::constants::Right),
-            jump: AtomicKey::new(*keys::constants::Control_L),
-            dismount: AtomicKey::new(*keys::constants::Escape),
-            throw: AtomicKey::new(*keys::constants::Delete),
-            talk: AtomicKey::new(*keys::constants::Return),
-            toggle_mirroring: AtomicKey::new(*keys::constants::Shift_L),
+        // Getting a read lock on the controller state reader-writer lock.
+        let ctls = self.routing.controllers.read().unwrap();
+
+        let mut windows = Vec::new();
+        for ctl in ctls
+            .iter()
+            .filter(|ctl| ctl.window.load(Ordering::SeqCst) == click.window)
+        {
+            for follower in ctl.followers.iter().filter_map(|i| ctls.get(i)) {
+                let window = follower.window.load(Ordering::SeqCst);
+                if follower.mirror_clicks.load(Ordering::SeqCst)
+                    && window != 0
+                    && window != click.window
+                    && !windows.contains(&window)
+                {
+                    windows.push(window);
+                }
+            }
         }
+
+        // Relinquishing read lock on the controller state reader-writer lock.
+        drop(ctls);
+
+        windows
+            .into_iter()
+            .filter_map(|window| {
+                let (to_width, to_height) = self.click_watch.size(window)?;
+
+                Some(Click {
+                    window,
+                    x: scale(click.x, width, to_width),
+                    y: scale(click.y, height, to_height),
+                    button: click.button,
+                })
+            })
+            .collect()
     }
-}
 
-impl Clone for Bindings {
-    #[inline]
-    fn clone(&self) -> Self {
-        Self {
-            forward: AtomicKey::new(self.forward.load(Ordering::SeqCst)),
-            back: AtomicKey::new(self.back.load(Ordering::SeqCst)),
-            left: AtomicKey::new(self.left.load(Ordering::SeqCst)),
-            right: AtomicKey::new(self.right.load(Ordering::SeqCst)),
-            jump: AtomicKey::new(self.jump.load(Ordering::SeqCst)),
-            dismount: AtomicKey::new(self.dismount.load(Ordering::SeqCst)),
-            throw: AtomicKey::new(self.throw.load(Ordering::SeqCst)),
-            low_throw: AtomicKey::new(self.low_throw.load(Ordering::SeqCst)),
-            talk: AtomicKey::new(self.talk.load(Ordering::SeqCst)),
+    /// Finds every game window (see `window_patterns`) that isn't associated
+    /// with a controller yet, and associates them with the controllers that
+    /// have no window, in order.  Returns the indices of the controllers that
+    /// got a window.
+    pub fn attach_all(&self) -> Result<Vec<usize>, String> {
+        let windows =
+            discover::find_windows(&self.window_patterns.read().unwrap())?;
+
+        // Getting a read lock on the controller state reader-writer lock.
+        let ctls = self.routing.controllers.read().unwrap();
+
+        let attached: Vec<_> = ctls
+            .iter()
+            .map(|ctl| ctl.window.load(Ordering::SeqCst))
+            .filter(|&w| w != 0)
+            .collect();
+        let mut unattached =
+            windows.into_iter().filter(|w| !attached.contains(w));
+
+        let mut newly_attached = Vec::new();
+        for (ctl_ix, ctl) in ctls.iter().enumerate() {
+            if ctl.window.load(Ordering::SeqCst) != 0 {
+                continue;
+            }
+            match unattached.next() {
+                Some(window) => {
+                    ctl.set_window(window);
+                    newly_attached.push(ctl_ix);
+                }
+                None => break,
+            }
         }
+
+        // Relinquishing read lock on the controller state reader-writer lock.
+        drop(ctls);
+
+        self.rewatch();
+
+        Ok(newly_attached)
     }
-}
 
-impl Default for Bindings {
-    #[inline]
-    fn default() -> Self {
-        Self {
-            forward: AtomicKey::new(*keys::constants::Up),
-            back: AtomicKey::new(*keys::constants::Down),
-            left: AtomicKey::new(*keys::constants::Left),
-            right: AtomicKey::new(*keys::constants::Right),
-            jump: AtomicKey::new(*keys::constants::Control_L),
-            dismount: AtomicKey::new(*keys::constants::Escape),
-            throw: AtomicKey::new(*keys::constants::Delete),
-            low_throw: AtomicKey::new(*keys::constants::Insert),
-            talk: AtomicKey::new(*keys::constants::Return),
+    /// Finds the windows that controllers had in a previous session (see
+    /// `Controller::saved_window`) again, and reattaches them.  Returns the
+    /// indices of the controllers that got a window.
+    pub fn restore_windows(&self) -> Result<Vec<usize>, String> {
+        // Getting a read lock on the controller state reader-writer lock.
+        let ctls = self.routing.controllers.read().unwrap();
+
+        let taken: Vec<_> = ctls
+            .iter()
+            .map(|ctl| ctl.window.load(Ordering::SeqCst))
+            .filter(|&w| w != 0)
+            .collect();
+        let wanted: Vec<_> = ctls
+            .iter()
+            .map(|ctl| {
+                if ctl.window.load(Ordering::SeqCst) == 0 {
+                    ctl.saved_window.lock().unwrap().clone()
+                } else {
+                    None
+                }
+            })
+            .collect();
+        let found = discover::find_identities(&wanted, &taken)?;
+
+        let mut newly_attached = Vec::new();
+        for (ctl_ix, (ctl, window)) in ctls.iter().zip(found).enumerate() {
+            if let Some(window) = window {
+                ctl.set_window(window);
+                newly_attached.push(ctl_ix);
+            }
         }
+
+        // Relinquishing read lock on the controller state reader-writer lock.
+        drop(ctls);
+
+        self.rewatch();
+
+        Ok(newly_attached)
     }
-}
 
-impl Action {
-    #[inline(always)]
-    pub fn key(&self) -> &Key {
-        match self {
-            Self::Simple(key) => key,
-            Self::LowThrow(key) => key,
-            Self::Talk(key) => key,
+    /// (Re)launches the game client of the controller at `ctl_ix`.  The
+    /// controller is detached, so that it can be attached to the new
+    /// client's window once that shows up (see `poll_processes`).
+    pub fn launch(&self, ctl_ix: usize) -> Result<(), String> {
+        // Getting a read lock on the controller state reader-writer lock.
+        let ctls = self.routing.controllers.read().unwrap();
+
+        let ctl = ctls
+            .get(ctl_ix)
+            .ok_or_else(|| format!("There is no controller {}.", ctl_ix))?;
+        let command = ctl.launch.read().unwrap().clone().ok_or_else(|| {
+            format!("Controller {} has no launch command.", ctl_ix)
+        })?;
+
+        // Getting a lock on the controller's process mutex.
+        let mut process = ctl.process.lock().unwrap();
+
+        process.poll();
+        if process.is_running() {
+            return Err(format!(
+                "Controller {}'s game is already {}.",
+                ctl_ix, process,
+            ));
+        }
+        *process = command.spawn();
+        if let Process::Failed(ref e) = *process {
+            return Err(e.clone());
+        }
+        let detached = ctl.set_window(0) != 0;
+
+        // Relinquishing lock on the controller's process mutex.
+        drop(process);
+        // Relinquishing read lock on the controller state reader-writer lock.
+        drop(ctls);
+
+        // Like picking a window, this stops watching (and catching clicks on)
+        // the old one.
+        if detached {
+            self.rewatch();
         }
+
+        Ok(())
     }
 
-    #[inline(always)]
-    fn set_key(&mut self, key: Key) {
-        match self {
-            Self::Simple(k) => *k = key,
-            Self::LowThrow(k) => *k = key,
-            Self::Talk(k) => *k = key,
+    /// Checks up on every launched game client: reaps the ones that have
+    /// exited, and attaches controllers that are still waiting for their
+    /// client's window to it, if it has shown up.  Returns the indices of
+    /// the controllers whose process or window changed.
+    pub fn poll_processes(&self) -> Vec<usize> {
+        // Getting a read lock on the controller state reader-writer lock.
+        let ctls = self.routing.controllers.read().unwrap();
+
+        self.orphans
+            .lock()
+            .unwrap()
+            .retain_mut(|process| !process.poll());
+
+        let mut changed = Vec::new();
+        let mut waiting = Vec::new();
+        for (ctl_ix, ctl) in ctls.iter().enumerate() {
+            let mut process = ctl.process.lock().unwrap();
+            if process.poll() {
+                changed.push(ctl_ix);
+            }
+            if let Some(pid) = process.pid() {
+                if ctl.window.load(Ordering::SeqCst) == 0 {
+                    waiting.push((ctl_ix, pid));
+                }
+            }
+        }
+        if waiting.is_empty() {
+            return changed;
         }
+
+        let pids: Vec<_> = waiting.iter().map(|&(_, pid)| pid).collect();
+        match discover::windows_by_pid(&pids) {
+            Ok(windows) => {
+                for ((ctl_ix, _), window) in waiting.into_iter().zip(windows) {
+                    if let Some(window) = window {
+                        ctls[ctl_ix].set_window(window);
+                        if !changed.contains(&ctl_ix) {
+                            changed.push(ctl_ix);
+                        }
+                    }
+                }
+            }
+            Err(e) => eprintln!("{}", e),
+        }
+
+        // Relinquishing read lock on the controller state reader-writer lock.
+        drop(ctls);
+
+        if !changed.is_empty() {
+            self.rewatch();
+        }
+
+        changed
+    }
+
+    /// Takes the game client of `ctl`, which is going away, off of its hands.
+    /// The client keeps running, but a dropped `Child` would never get
+    /// reaped once it exits.
+    fn orphan(&self, ctl: &Controller) {
+        let process = mem::take(&mut *ctl.process.lock().unwrap());
+        if process.is_running() {
+            self.orphans.lock().unwrap().push(process);
+        }
+    }
+
+    /// Whether or not any controller without a window is still waiting for a
+    /// window from before (see `Controller::saved_window`).
+    pub fn awaiting_windows(&self) -> bool {
+        self.routing.controllers.read().unwrap().iter().any(|ctl| {
+            ctl.window.load(Ordering::SeqCst) == 0
+                && ctl.saved_window.lock().unwrap().is_some()
+        })
+    }
+
+    /// Detaches every controller whose window was `window`, which has gone
+    /// away.  Those controllers remember what the window was like, so that a
+    /// replacement can be reattached to them (see `restore_windows`).
+    /// Returns the indices of the detached controllers.
+    pub fn window_gone(&self, window: u64) -> Vec<usize> {
+        // The process is gone too, and a new one will have a new PID.
+        let identity =
+            self.window_watch
+                .forget(window)
+                .map(|identity| WindowIdentity {
+                    pid: None,
+                    ..identity
+                });
+
+        // Getting a read lock on the controller state reader-writer lock.
+        let ctls = self.routing.controllers.read().unwrap();
+
+        let mut detached = Vec::new();
+        for (ctl_ix, ctl) in ctls.iter().enumerate() {
+            if ctl
+                .window
+                .compare_exchange(
+                    window,
+                    0,
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                )
+                .is_ok()
+            {
+                *ctl.saved_window.lock().unwrap() = identity.clone();
+                self.routing.talking.remove(ctl_ix);
+                detached.push(ctl_ix);
+            }
+        }
+
+        // Relinquishing read lock on the controller state reader-writer lock.
+        drop(ctls);
+
+        if !detached.is_empty() {
+            self.regrab_clicks();
+            if self.capturing.load(Ordering::SeqCst) {
+                self.key_grab
+                    .set_keyboard_grabbed(!self.routing.talking.is_empty());
+            }
+        }
+
+        detached
+    }
+
+    /// Brings the set of windows that `window_watch` is watching (and whose
+    /// clicks are caught) in line with the controllers' windows.
+    pub fn rewatch(&self) {
+        let windows: Vec<_> = self
+            .routing
+            .controllers
+            .read()
+            .unwrap()
+            .iter()
+            .map(|ctl| ctl.window.load(Ordering::SeqCst))
+            .collect();
+
+        self.window_watch.watch(windows);
+        self.regrab_clicks();
+    }
+
+    /// Associates the controller at `ctl_ix` with `window` (`0` for none).
+    pub fn attach(&self, ctl_ix: usize, window: u64) -> Result<(), String> {
+        self.routing
+            .controllers
+            .read()
+            .unwrap()
+            .get(ctl_ix)
+            .ok_or_else(|| format!("There is no controller {}.", ctl_ix))?
+            .set_window(window);
+        self.rewatch();
+
+        Ok(())
+    }
+
+    /// The controllers' windows, in controller order, without duplicates.
+    fn attached_windows(&self) -> Vec<u64> {
+        let mut windows = Vec::new();
+        for ctl in self.routing.controllers.read().unwrap().iter() {
+            let window = ctl.window.load(Ordering::SeqCst);
+            if window != 0 && !windows.contains(&window) {
+                windows.push(window);
+            }
+        }
+
+        windows
+    }
+
+    /// Arranges the controllers' windows according to the layout at
+    /// `layout_ix`.  The first time that a layout moves a window, where that
+    /// window was beforehand is remembered (see `restore_layout`).
+    pub fn apply_layout(&self, layout_ix: usize) -> Result<(), String> {
+        let layout = self
+            .layouts
+            .read()
+            .unwrap()
+            .get(layout_ix)
+            .ok_or_else(|| format!("There is no layout {}.", layout_ix))?
+            .layout;
+        let windows = self.attached_windows();
+        let monitors = layout::monitor_work_areas();
+        if monitors.is_empty() {
+            return Err(
+                "There are no monitors to arrange windows on.".to_owned()
+            );
+        }
+
+        {
+            // Getting a lock on the original geometries mutex.
+            let mut originals = self.original_geometries.lock().unwrap();
+
+            let new_windows: Vec<_> = windows
+                .iter()
+                .copied()
+                .filter(|w| !originals.iter().any(|(v, _)| v == w))
+                .collect();
+            let identities = discover::identify(&new_windows)?;
+            for (window, identity) in new_windows.into_iter().zip(identities) {
+                if let Some(geometry) = identity.and_then(|i| i.geometry) {
+                    originals.push((window, geometry));
+                }
+            }
+
+            // Relinquishing lock on the original geometries mutex.
+        }
+
+        let rects = layout.arrange(windows.len(), &monitors);
+        let moves: Vec<_> = windows.into_iter().zip(rects).collect();
+        layout::move_windows(&moves)?;
+        self.active_layout.store(layout_ix, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    /// Puts every window that a layout moved back where it was before.
+    pub fn restore_layout(&self) -> Result<(), String> {
+        let originals =
+            mem::take(&mut *self.original_geometries.lock().unwrap());
+        self.active_layout.store(usize::MAX, Ordering::SeqCst);
+
+        layout::move_windows(&originals)
+    }
+
+    /// Applies the layout named `name`, or restores the original geometries
+    /// if `name` is `None`.
+    pub fn select_layout(&self, name: Option<&str>) -> Result<(), String> {
+        let Some(name) = name else {
+            return self.restore_layout();
+        };
+        let layout_ix = self
+            .layouts
+            .read()
+            .unwrap()
+            .iter()
+            .position(|l| l.name == name)
+            .ok_or_else(|| {
+                format!("There is no layout named \"{}\".", name)
+            })?;
+
+        self.apply_layout(layout_ix)
+    }
+
+    /// Applies the layout after the one that was applied last.  After the
+    /// last layout, the original geometries are restored instead.
+    pub fn cycle_layout(&self) -> Result<(), String> {
+        let layout_count = self.layouts.read().unwrap().len();
+        let next_ix =
+            self.active_layout.load(Ordering::SeqCst).wrapping_add(1);
+
+        if next_ix < layout_count {
+            self.apply_layout(next_ix)
+        } else {
+            self.restore_layout()
+        }
+    }
+
+    /// The name of the layout that was applied last, if any.
+    pub fn active_layout_name(&self) -> Option<String> {
+        self.layouts
+            .read()
+            .unwrap()
+            .get(self.active_layout.load(Ordering::SeqCst))
+            .map(|l| l.name.clone())
+    }
+
+    /// Starts recording the keys that are routed to the window of the
+    /// controller at `ctl_ix`, as the macro named `name`.
+    pub fn start_recording(
+        &self,
+        ctl_ix: usize,
+        name: String,
+    ) -> Result<(), String> {
+        if name.trim().is_empty() {
+            return Err("Macros must have names.".to_owned());
+        }
+        if ctl_ix >= self.routing.controllers.read().unwrap().len() {
+            return Err(format!("There is no controller {}.", ctl_ix));
+        }
+
+        // Getting a lock on the recording mutex.
+        let mut recording = self.recording.lock().unwrap();
+
+        if let Some(r) = recording.as_ref() {
+            return Err(format!(
+                "The \"{}\" macro is already being recorded.",
+                r.name,
+            ));
+        }
+        *recording = Some(Recording::new(name, ctl_ix));
+
+        Ok(())
+
+        // Relinquishing lock on the recording mutex.
+    }
+
+    /// Records `events` (which were just routed), if a macro is being
+    /// recorded.
+    pub fn record(&self, events: &[OutputEvent]) {
+        // Getting a lock on the recording mutex.
+        let mut recording = self.recording.lock().unwrap();
+
+        let Some(recording) = recording.as_mut() else {
+            return;
+        };
+        let window = self
+            .routing
+            .controllers
+            .read()
+            .unwrap()
+            .get(recording.controller)
+            .map_or(0, |ctl| ctl.window.load(Ordering::SeqCst));
+        if window != 0 {
+            recording.record(events, window);
+        }
+
+        // Relinquishing lock on the recording mutex.
+    }
+
+    /// Stops recording, and saves the recorded macro.  A macro with the same
+    /// name is replaced, but its key is kept.  Returns the macro's name.
+    pub fn stop_recording(&self) -> Result<String, String> {
+        let mut new_macro = self
+            .recording
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| "No macro is being recorded.".to_owned())?
+            .finish();
+        let name = new_macro.name.clone();
+
+        // Getting a write lock on the macros reader-writer lock.
+        let mut macros = self.routing.macros.write().unwrap();
+
+        if let Some(old_macro) = macros.iter_mut().find(|m| m.name == name) {
+            new_macro.key = old_macro.key;
+            *old_macro = new_macro;
+        } else {
+            macros.push(new_macro);
+        }
+
+        Ok(name)
+
+        // Relinquishing write lock on the macros reader-writer lock.
+    }
+
+    #[inline]
+    pub fn macro_name(&self, macro_ix: usize) -> Option<String> {
+        self.routing
+            .macros
+            .read()
+            .unwrap()
+            .get(macro_ix)
+            .map(|m| m.name.clone())
+    }
+
+    #[inline]
+    pub fn macro_index(&self, name: &str) -> Option<usize> {
+        self.routing
+            .macros
+            .read()
+            .unwrap()
+            .iter()
+            .position(|m| m.name == name)
+    }
+
+    /// Binds the macro named `name` to `new_key` (`0` to unbind).
+    pub fn bind_macro(&self, name: &str, new_key: Key) -> Result<(), String> {
+        self.routing
+            .macros
+            .write()
+            .unwrap()
+            .iter_mut()
+            .find(|m| m.name == name)
+            .ok_or_else(|| format!("There is no macro named \"{}\".", name))?
+            .key = *new_key;
+        self.regrab();
+
+        Ok(())
+    }
+
+    /// Starts playing the macro at `macro_ix` back to its controller, and (if
+    /// mirroring is on) to the controllers that mirror it.  Whatever macro
+    /// was playing before is stopped.  Returns the ID of the new playback,
+    /// along with the events that stop the old one.
+    pub fn play_macro(
+        &self,
+        macro_ix: usize,
+    ) -> Result<(u64, Vec<OutputEvent>), String> {
+        // Getting a read lock on the macros reader-writer lock.
+        let macros = self.routing.macros.read().unwrap();
+
+        let mac = macros
+            .get(macro_ix)
+            .ok_or_else(|| format!("There is no macro {}.", macro_ix))?;
+        if mac.steps.is_empty() {
+            return Err(format!("The \"{}\" macro is empty.", mac.name));
+        }
+
+        let windows = {
+            // Getting a read lock on the controller state reader-writer lock.
+            let ctls = self.routing.controllers.read().unwrap();
+
+            let ctl = ctls.get(mac.controller).ok_or_else(|| {
+                format!(
+                    "The \"{}\" macro is for controller {}, which doesn't \
+                     exist.",
+                    mac.name, mac.controller,
+                )
+            })?;
+            let mut windows = vec![ctl.window.load(Ordering::SeqCst)];
+            if self.routing.mirroring.load(Ordering::SeqCst) {
+                windows.extend(
+                    ctl.followers
+                        .iter()
+                        .filter_map(|i| ctls.get(i))
+                        .map(|c| c.window.load(Ordering::SeqCst)),
+                );
+            }
+            let mut deduped = Vec::with_capacity(windows.len());
+            for window in windows {
+                if window != 0 && !deduped.contains(&window) {
+                    deduped.push(window);
+                }
+            }
+
+            deduped
+
+            // Relinquishing read lock on the controller state reader-writer
+            // lock.
+        };
+
+        let id = self.next_playback_id.fetch_add(1, Ordering::SeqCst);
+        let stopped = self.stop_macro();
+        *self.playback.lock().unwrap() = Some(Playback::new(id, mac, windows));
+
+        Ok((id, stopped))
+
+        // Relinquishing read lock on the macros reader-writer lock.
+    }
+
+    /// Stops whatever macro is playing.  Returns the events that release the
+    /// keys that it was holding down.
+    pub fn stop_macro(&self) -> Vec<OutputEvent> {
+        self.playback
+            .lock()
+            .unwrap()
+            .take()
+            .map(Playback::stop)
+            .unwrap_or_default()
+    }
+
+    /// How long the playback with ID `id` waits before its next step, or
+    /// `None` if it has finished (or was stopped).
+    pub fn playback_delay(&self, id: u64) -> Option<Duration> {
+        self.playback
+            .lock()
+            .unwrap()
+            .as_ref()
+            .filter(|p| p.id == id)
+            .and_then(Playback::next_delay)
+    }
+
+    /// Takes the next step of the playback with ID `id`, if it's still
+    /// playing.  After the last step, the playback is finished, and the keys
+    /// that it left held down are released.
+    pub fn playback_step(&self, id: u64) -> Option<Vec<OutputEvent>> {
+        // Getting a lock on the playback mutex.
+        let mut playback = self.playback.lock().unwrap();
+
+        let mut events = playback.as_mut().filter(|p| p.id == id)?.step();
+        if playback.as_ref().and_then(Playback::next_delay).is_none() {
+            events.extend(
+                playback.take().map(Playback::stop).unwrap_or_default(),
+            );
+        }
+
+        Some(events)
+
+        // Relinquishing lock on the playback mutex.
+    }
+
+    /// The name of the macro that is being played back, if any.
+    pub fn playing_macro_name(&self) -> Option<String> {
+        self.playback
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|p| p.name.clone())
+    }
+
+    /// The name of the macro that is being recorded, if any.
+    pub fn recording_macro_name(&self) -> Option<String> {
+        self.recording
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|r| r.name.clone())
+    }
+
+    /// Binds the main binding named `action` (the name of either an action,
+    /// or of one of the `MainBindings`) to `new_key` (`0` to unbind).
+    pub fn bind_main(&self, action: &str, new_key: Key) -> Result<(), String> {
+        match action {
+            "toggle_mirroring" => self
+                .routing
+                .main_bindings
+                .toggle_mirroring
+                .store(*new_key, Ordering::SeqCst),
+            "cycle_profile" => self
+                .routing
+                .main_bindings
+                .cycle_profile
+                .store(*new_key, Ordering::SeqCst),
+            "cycle_layout" => self
+                .routing
+                .main_bindings
+                .cycle_layout
+                .store(*new_key, Ordering::SeqCst),
+            _ => {
+                // Getting a read lock on the action state reader-writer lock.
+                let actions = self.routing.actions.read().unwrap();
+
+                let action_ix = actions
+                    .iter()
+                    .position(|a| a.name == action)
+                    .ok_or_else(|| {
+                        format!(
+                            "There is no main binding named \"{}\".",
+                            action,
+                        )
+                    })?;
+                if *new_key != 0
+                    && main_key_conflicts(&actions, action_ix, &new_key)
+                {
+                    return Err("Main bindings may not overlap.".to_owned());
+                }
+
+                // Routes refer to actions by index, so there's no need to
+                // reroute anything.
+                actions[action_ix].key.store(*new_key, Ordering::SeqCst);
+
+                // Relinquishing read lock on the action state reader-writer
+                // lock.
+            }
+        }
+
+        self.regrab();
+
+        Ok(())
+    }
+
+    /// Binds the action named `action` of the controller at `ctl_ix` to
+    /// `new_chord` (see `Routing::bind`), and regrabs accordingly.
+    pub fn bind(
+        &self,
+        ctl_ix: usize,
+        action: &str,
+        new_chord: Chord,
+    ) -> Result<(), String> {
+        if self.routing.bind(ctl_ix, action, new_chord)? {
+            self.regrab();
+        }
+
+        Ok(())
+    }
+
+    /// Turns global key capture on or off, and regrabs accordingly.
+    pub fn set_capturing(&self, on: bool) -> Result<(), String> {
+        if on && self.output.kind() == BackendKind::XTest {
+            return Err(XTEST_CAPTURE_ERROR.to_owned());
+        }
+        self.capturing.store(on, Ordering::SeqCst);
+        self.regrab();
+
+        Ok(())
+    }
+
+    /// Brings the global key grabs in line with the current routes (plus the
+    /// mirroring toggle key), or releases all of them if we aren't
+    /// capturing.  The button grabs for click mirroring don't depend on
+    /// capturing, and are always brought up to date (see `regrab_clicks`).
+    pub fn regrab(&self) {
+        self.regrab_clicks();
+        if !self.capturing.load(Ordering::SeqCst) {
+            self.key_grab.ungrab_keys();
+            self.key_grab.set_keyboard_grabbed(false);
+
+            return;
+        }
+
+        let chords: Vec<Chord> = {
+            // Getting a read lock on the routing state reader-writer lock.
+            let r_lk = self.routing.routes.read().unwrap();
+
+            r_lk.iter()
+                .filter(|(_, dests)| !dests.is_empty())
+                .map(|(chord, _)| *chord)
+                .collect()
+
+            // Relinquishing read lock on the routing state reader-writer
+            // lock.
+        };
+
+        let macro_keys: Vec<_> = self
+            .routing
+            .macros
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|m| m.key != 0)
+            .map(|m| Key::from(m.key))
+            .collect();
+
+        if let Err(e) = self.key_grab.grab_keys(
+            chords
+                .into_iter()
+                .chain(iter::once(Chord::plain(
+                    self.routing.main_bindings.toggle_mirroring(),
+                )))
+                .chain(iter::once(Chord::plain(
+                    self.routing.main_bindings.cycle_profile(),
+                )))
+                .chain(iter::once(Chord::plain(
+                    self.routing.main_bindings.cycle_layout(),
+                )))
+                .chain(macro_keys.into_iter().map(Chord::plain)),
+        ) {
+            eprintln!("{}", e);
+        }
+        self.key_grab
+            .set_keyboard_grabbed(!self.routing.talking.is_empty());
+    }
+
+    /// Removes the controller at index `removed_ix` (see
+    /// `Routing::remove_controller`), after letting go of whatever it holds
+    /// down (see `Routing::release_controller`).  Its game client, if we
+    /// launched one, is kept track of until it exits.
+    pub fn remove_controller(&self, removed_ix: usize) {
+        self.send_now(&self.routing.release_controller(removed_ix));
+        let Some(removed) = self.routing.remove_controller(removed_ix) else {
+            return;
+        };
+
+        self.orphan(&removed);
+        self.renumber(after_removal(removed_ix));
+        self.regrab();
+    }
+
+    /// Moves the controller at index `from` so that it ends up at index `to`
+    /// (see `Routing::move_controller`).  Held-down and held-back keys are
+    /// kept track of by chord & window respectively, so they stay put.
+    pub fn move_controller(&self, from: usize, to: usize) {
+        if self.routing.move_controller(from, to) {
+            self.renumber(after_move(from, to));
+            self.regrab_clicks();
+        }
+    }
+
+    /// Renumbers the controllers of the macro that is being recorded or
+    /// played back, if any, according to `new_ix` (see `Routing::renumber`).
+    /// A playback whose controller is gone is stopped.
+    fn renumber<F: Fn(usize) -> Option<usize>>(&self, new_ix: F) {
+        {
+            // Getting a lock on the recording mutex.
+            let mut recording = self.recording.lock().unwrap();
+
+            *recording = recording.take().and_then(|mut r| {
+                r.controller = new_ix(r.controller)?;
+
+                Some(r)
+            });
+
+            // Relinquishing lock on the recording mutex.
+        }
+
+        let stopped = {
+            // Getting a lock on the playback mutex.
+            let mut playback = self.playback.lock().unwrap();
+
+            match playback.take() {
+                Some(mut p) => match new_ix(p.controller) {
+                    Some(i) => {
+                        p.controller = i;
+                        *playback = Some(p);
+
+                        Vec::new()
+                    }
+                    None => p.stop(),
+                },
+                None => Vec::new(),
+            }
+
+            // Relinquishing lock on the playback mutex.
+        };
+        self.send_now(&stopped);
+    }
+}
+
+/// Maps the index that each controller had before the one at `removed_ix`
+/// was removed to the one that it has afterwards, if any.
+pub fn after_removal(removed_ix: usize) -> impl Fn(usize) -> Option<usize> {
+    move |i| match i.cmp(&removed_ix) {
+        cmp::Ordering::Less => Some(i),
+        cmp::Ordering::Equal => None,
+        cmp::Ordering::Greater => Some(i - 1),
+    }
+}
+
+/// Maps the index that each controller had before the one at `from` was
+/// moved to `to` to the one that it has afterwards.
+pub fn after_move(from: usize, to: usize) -> impl Fn(usize) -> Option<usize> {
+    move |i| {
+        Some(if i == from {
+            to
+        } else if from < to && from < i && i <= to {
+            i - 1
+        } else if to < from && to <= i && i < from {
+            i + 1
+        } else {
+            i
+        })
+    }
+}
+
+/// Creates the live version of some deserialized controllers, which starts out
+/// with no windows.  Bindings for actions that aren't in `actions` are
+/// ignored, and actions that a controller has no binding for are unbound.
+pub fn controllers_from_json(
+    controllers: Vec<json::Controller>,
+    actions: &[ActionDef],
+    transitive: bool,
+) -> Vec<Controller> {
+    let controllers: Vec<_> = controllers
+        .into_iter()
+        .map(|c| Controller {
+            window: AtomicU64::new(0),
+            mirror: c.mirror,
+            mirrored: AtomicBitSet::new(),
+            followers: AtomicBitSet::new(),
+            bindings: actions
+                .iter()
+                .map(|a| {
+                    AtomicChord::new(
+                        c.bindings.get(&a.name).copied().unwrap_or_default(),
+                    )
+                })
+                .collect(),
+            keep_alive: AtomicBool::new(c.keep_alive),
+            mirror_clicks: AtomicBool::new(c.mirror_clicks),
+            mirror_actions: actions
+                .iter()
+                .map(|a| {
+                    AtomicBool::new(
+                        c.mirror_actions
+                            .as_ref()
+                            .is_none_or(|names| names.contains(&a.name)),
+                    )
+                })
+                .collect(),
+            mirror_delay: RwLock::new(c.mirror_delay),
+            keep_alive_at: AtomicI64::new(0),
+            saved_window: Mutex::new(None),
+            launch: RwLock::new(c.launch),
+            process: Default::default(),
+        })
+        .collect();
+    link_mirrors(&controllers, transitive);
+
+    controllers
+}
+
+/// Recomputes every controller's `mirrored` & `followers` sets from the
+/// `mirror`s.  With `transitive` mirroring, followers' followers are
+/// followers too.
+pub fn link_mirrors(ctls: &[Controller], transitive: bool) {
+    for ctl in ctls {
+        ctl.mirrored.clear();
+    }
+    for (i, ctl) in ctls.iter().enumerate() {
+        let mirror = ctl.mirror.load(Ordering::SeqCst);
+        if mirror != usize::MAX && mirror != i {
+            if let Some(c) = ctls.get(mirror) {
+                c.mirrored.insert(i);
+            }
+        }
+    }
+
+    for (i, ctl) in ctls.iter().enumerate() {
+        ctl.followers.clear();
+
+        let mut seen = vec![false; ctls.len()];
+        seen[i] = true;
+        let mut to_visit: Vec<_> = ctl.mirrored.iter().collect();
+        while let Some(follower_ix) = to_visit.pop() {
+            if seen[follower_ix] {
+                continue;
+            }
+            seen[follower_ix] = true;
+            ctl.followers.insert(follower_ix);
+            if transitive {
+                to_visit.extend(ctls[follower_ix].mirrored.iter());
+            }
+        }
+    }
+}
+
+/// Every controller's `mirror`, in order.
+#[inline]
+pub fn mirrors(ctls: &[Controller]) -> Vec<usize> {
+    ctls.iter()
+        .map(|c| c.mirror.load(Ordering::SeqCst))
+        .collect()
+}
+
+/// Whether or not following the chain of `mirrors` (one per controller, with
+/// `usize::MAX` for none) from the controller at `from` ever gets to the one
+/// at `to`.
+pub fn mirror_chain_reaches(
+    mirrors: &[usize],
+    from: usize,
+    to: usize,
+) -> bool {
+    let mut ctl_ix = from;
+    // Chains that don't loop can't be longer than there are controllers.
+    for _ in 0..mirrors.len() {
+        ctl_ix = match mirrors.get(ctl_ix) {
+            Some(&mirror) if mirror != usize::MAX => mirror,
+            _ => return false,
+        };
+        if ctl_ix == to {
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Whether or not making the controller at `ctl_ix` mirror the one at
+/// `mirror` would make the chain of `mirrors` loop.
+#[inline]
+pub fn mirror_would_loop(
+    mirrors: &[usize],
+    ctl_ix: usize,
+    mirror: usize,
+) -> bool {
+    mirror != usize::MAX
+        && (mirror == ctl_ix || mirror_chain_reaches(mirrors, mirror, ctl_ix))
+}
+
+/// The index of some controller whose chain of `mirrors` loops back around
+/// to it, if any.
+pub fn find_mirror_loop(mirrors: &[usize]) -> Option<usize> {
+    (0..mirrors.len()).find(|&i| mirror_chain_reaches(mirrors, i, i))
+}
+
+/// Whether or not the controller at `follower_ix` copies the action at
+/// `action_ix` from the one at `leader_ix`.  With transitive mirroring,
+/// every controller in the chain of mirrors in between has to copy it, too.
+pub fn copies_from(
+    ctls: &[Controller],
+    follower_ix: usize,
+    leader_ix: usize,
+    action_ix: usize,
+) -> bool {
+    let mut ctl_ix = follower_ix;
+    for _ in 0..ctls.len() {
+        let Some(ctl) = ctls.get(ctl_ix) else {
+            return true;
+        };
+        if !ctl.copies(action_ix) {
+            return false;
+        }
+
+        ctl_ix = ctl.mirror.load(Ordering::SeqCst);
+        if ctl_ix == leader_ix {
+            break;
+        }
+    }
+
+    true
+}
+
+/// Whether or not global key capture can be turned on (as configured by
+/// `capture`) along with the `output` backend.  If it can't, then it's turned
+/// off, and the user is told why.
+fn capture_allowed(capture: bool, output: BackendKind) -> bool {
+    if capture && output == BackendKind::XTest {
+        eprintln!("{}  Turning global key capture off.", XTEST_CAPTURE_ERROR);
+
+        false
+    } else {
+        capture
+    }
+}
+
+fn validate_profile_name(
+    profiles: &[json::Profile],
+    name: &str,
+) -> Result<(), String> {
+    if name.trim().is_empty() {
+        Err("Profile names may not be empty.".to_owned())
+    } else if profiles.iter().any(|p| p.name == name) {
+        Err(format!("There is already a profile named \"{}\".", name))
+    } else {
+        Ok(())
+    }
+}
+
+/// Whether or not some action other than the one at `action_ix` already
+/// sends `key` in the same way.  Two such actions would step on each other's
+/// toes, e.g. one of them releasing the key while the other one is still
+/// holding it down.
+pub fn main_key_conflicts(
+    actions: &[ActionDef],
+    action_ix: usize,
+    key: &Key,
+) -> bool {
+    let mode = actions[action_ix].mode;
+
+    actions.iter().enumerate().any(|(i, a)| {
+        i != action_ix
+            && a.mode == mode
+            && a.key.load(Ordering::SeqCst) == **key
+    })
+}
+
+/// Adds routes for all of `ctl`'s bindings.
+pub fn route_controller(
+    r_lk: &mut FxHashMap<Chord, Vec<(usize, usize)>>,
+    ctl_ix: usize,
+    ctl: &Controller,
+) {
+    for (action_ix, binding) in ctl.bindings.iter().enumerate() {
+        let chord = binding.load(Ordering::SeqCst);
+        if !chord.is_none() {
+            r_lk.entry(chord).or_default().push((ctl_ix, action_ix));
+        }
+    }
+}
+
+impl Controller {
+    /// A fresh controller, with the default bindings for `actions`.
+    #[inline]
+    pub fn new(actions: &[ActionDef]) -> Self {
+        Self {
+            window: AtomicU64::new(0),
+            mirror: AtomicUsize::new(::std::usize::MAX),
+            mirrored: AtomicBitSet::new(),
+            followers: AtomicBitSet::new(),
+            bindings: actions
+                .iter()
+                .map(|a| AtomicChord::new(default_binding(&a.name)))
+                .collect(),
+            keep_alive: AtomicBool::new(false),
+            mirror_clicks: AtomicBool::new(false),
+            mirror_actions: actions
+                .iter()
+                .map(|_| AtomicBool::new(true))
+                .collect(),
+            mirror_delay: Default::default(),
+            keep_alive_at: AtomicI64::new(0),
+            saved_window: Mutex::new(None),
+            launch: RwLock::new(None),
+            process: Default::default(),
+        }
+    }
+
+    #[inline]
+    pub fn from_template(template: &Self) -> Self {
+        Self {
+            window: AtomicU64::new(0),
+            mirror: AtomicUsize::new(::std::usize::MAX),
+            mirrored: AtomicBitSet::new(),
+            followers: AtomicBitSet::new(),
+            bindings: template
+                .bindings
+                .iter()
+                .map(|b| AtomicChord::new(b.load(Ordering::SeqCst)))
+                .collect(),
+            keep_alive: AtomicBool::new(false),
+            mirror_clicks: AtomicBool::new(false),
+            mirror_actions: template
+                .mirror_actions
+                .iter()
+                .map(|_| AtomicBool::new(true))
+                .collect(),
+            mirror_delay: Default::default(),
+            keep_alive_at: AtomicI64::new(0),
+            saved_window: Mutex::new(None),
+            launch: RwLock::new(None),
+            process: Default::default(),
+        }
+    }
+
+    /// Associates this controller with `window` (`0` for none), forgetting
+    /// any window from a previous session.  Returns the previous window.
+    #[inline]
+    pub fn set_window(&self, window: u64) -> u64 {
+        *self.saved_window.lock().unwrap() = None;
+
+        self.window.swap(window, Ordering::SeqCst)
+    }
+
+    #[inline(always)]
+    pub fn has_mirror(&self) -> bool {
+        self.mirror.load(Ordering::SeqCst) != ::std::usize::MAX
+    }
+
+    /// Whether or not this controller copies the action at `action_ix` from
+    /// its mirror.
+    #[inline]
+    pub fn copies(&self, action_ix: usize) -> bool {
+        self.mirror_actions
+            .get(action_ix)
+            .is_none_or(|m| m.load(Ordering::SeqCst))
+    }
+}
+
+impl MainBindings {
+    #[inline(always)]
+    pub fn toggle_mirroring(&self) -> Key {
+        self.toggle_mirroring.load(Ordering::SeqCst).into()
+    }
+
+    #[inline(always)]
+    pub fn cycle_profile(&self) -> Key {
+        self.cycle_profile.load(Ordering::SeqCst).into()
+    }
+
+    #[inline(always)]
+    pub fn cycle_layout(&self) -> Key {
+        self.cycle_layout.load(Ordering::SeqCst).into()
+    }
+
+    /// Overwrites all of these bindings with `other`'s, except for the
+    /// `cycle_profile` binding, which isn't per-profile.
+    pub fn set(&self, other: &Self) {
+        macro_rules! set_binding {
+            ( $( $key_id:ident ),* ) => {
+                $(
+                    self.$key_id.store(
+                        other.$key_id.load(Ordering::SeqCst),
+                        Ordering::SeqCst,
+                    );
+                )*
+            };
+        }
+
+        set_binding!(toggle_mirroring, cycle_layout);
+    }
+}
+
+impl Clone for MainBindings {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self {
+            toggle_mirroring: AtomicKey::new(
+                self.toggle_mirroring.load(Ordering::SeqCst),
+            ),
+            cycle_profile: AtomicKey::new(
+                self.cycle_profile.load(Ordering::SeqCst),
+            ),
+            cycle_layout: AtomicKey::new(
+                self.cycle_layout.load(Ordering::SeqCst),
+            ),
+        }
+    }
+}
+
+impl Default for MainBindings {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            toggle_mirroring: AtomicKey::new(*keys::constants::Shift_L),
+            cycle_profile: AtomicKey::new(0),
+            cycle_layout: AtomicKey::new(0),
+        }
+    }
+}
+
+impl ActionDef {
+    #[inline(always)]
+    pub fn key(&self) -> Key {
+        self.key.load(Ordering::SeqCst).into()
+    }
+
+    /// How this action is labelled in the UI.
+    #[inline]
+    pub fn label(&self) -> String {
+        binding_label(&self.name)
+    }
+}
+
+impl Clone for ActionDef {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            key: AtomicKey::new(self.key.load(Ordering::SeqCst)),
+            mode: self.mode,
+        }
+    }
+}
+
+/// How the (action or main) binding named `name` is labelled in the UI.
+#[inline]
+pub fn binding_label(name: &str) -> String {
+    name.replace('_', " ")
+}
+
+/// The actions that new profiles start out with.
+pub fn default_actions() -> Vec<ActionDef> {
+    DEFAULT_ACTIONS
+        .iter()
+        .map(|(name, key, mode, _)| ActionDef {
+            name: (*name).to_owned(),
+            key: AtomicKey::new(**key),
+            mode: *mode,
+        })
+        .collect()
+}
+
+/// What new controllers bind the action named `name` to.
+pub fn default_binding(name: &str) -> Chord {
+    DEFAULT_ACTIONS
+        .iter()
+        .find(|(n, _, _, _)| *n == name)
+        .and_then(|(_, _, _, key)| key.map(Chord::plain))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const NONE: usize = usize::MAX;
+
+    /// Controllers with the given `mirror`s, which copy the named actions
+    /// from their mirrors (or every action, for `None`).
+    fn controllers(
+        mirrors: &[(usize, Option<&[&str]>)],
+        transitive: bool,
+    ) -> Vec<Controller> {
+        let controllers = mirrors
+            .iter()
+            .map(|&(mirror, copies)| {
+                serde_json::from_value(json!({
+                    "mirror": mirror,
+                    "bindings": {},
+                    "mirror_actions": copies,
+                }))
+                .unwrap()
+            })
+            .collect();
+
+        controllers_from_json(controllers, &default_actions(), transitive)
+    }
+
+    fn followers(ctls: &[Controller]) -> Vec<Vec<usize>> {
+        ctls.iter().map(|c| c.followers.iter().collect()).collect()
+    }
+
+    fn action_ix(name: &str) -> usize {
+        default_actions()
+            .iter()
+            .position(|a| a.name == name)
+            .unwrap()
+    }
+
+    #[test]
+    fn chains_are_followed_only_when_transitive() {
+        // 2 mirrors 1, which mirrors 0.
+        let chain = [(NONE, None), (0, None), (1, None)];
+
+        let ctls = controllers(&chain, false);
+        assert_eq!(followers(&ctls), [vec![1], vec![2], vec![]]);
+
+        let ctls = controllers(&chain, true);
+        assert_eq!(followers(&ctls), [vec![1, 2], vec![2], vec![]]);
+
+        let mirrors = mirrors(&ctls);
+        assert_eq!(find_mirror_loop(&mirrors), None);
+        assert!(mirror_chain_reaches(&mirrors, 2, 0));
+        assert!(!mirror_chain_reaches(&mirrors, 0, 2));
+        // Closing the chain up would make a loop, but branching off of it
+        // wouldn't.
+        assert!(mirror_would_loop(&mirrors, 0, 2));
+        assert!(!mirror_would_loop(&mirrors, 2, 0));
+        assert!(!mirror_would_loop(&mirrors, 0, NONE));
+    }
+
+    #[test]
+    fn two_cycles_are_loops() {
+        let cycle = [(1, None), (0, None)];
+
+        for transitive in [false, true] {
+            let ctls = controllers(&cycle, transitive);
+
+            // Nobody follows themselves, even around a loop.
+            assert_eq!(followers(&ctls), [vec![1], vec![0]]);
+            assert_eq!(find_mirror_loop(&mirrors(&ctls)), Some(0));
+        }
+    }
+
+    #[test]
+    fn self_mirrors_are_loops() {
+        for transitive in [false, true] {
+            let ctls = controllers(&[(0, None), (NONE, None)], transitive);
+
+            assert!(followers(&ctls).iter().all(Vec::is_empty));
+            assert_eq!(find_mirror_loop(&mirrors(&ctls)), Some(0));
+        }
+
+        let mirrors = [NONE, NONE];
+        assert!(mirror_would_loop(&mirrors, 1, 1));
+        assert!(!mirror_would_loop(&mirrors, 1, 0));
+    }
+
+    #[test]
+    fn copying_needs_every_link_of_the_chain() {
+        // 1 only copies "forward" from 0, and 2 copies everything from 1.
+        let chain = [(NONE, None), (0, Some(&["forward"][..])), (1, None)];
+        let forward = action_ix("forward");
+        let jump = action_ix("jump");
+
+        for transitive in [false, true] {
+            let ctls = controllers(&chain, transitive);
+
+            assert!(copies_from(&ctls, 1, 0, forward));
+            assert!(!copies_from(&ctls, 1, 0, jump));
+            assert!(copies_from(&ctls, 2, 1, forward));
+            assert!(copies_from(&ctls, 2, 1, jump));
+
+            // Only reachable transitively, through 1.
+            assert!(copies_from(&ctls, 2, 0, forward));
+            assert!(!copies_from(&ctls, 2, 0, jump));
+        }
+    }
+
+    #[test]
+    fn loops_are_refused_after_reordering() {
+        let routing = Routing::for_tests();
+        routing.set_transitive_mirroring(true).unwrap();
+        routing.set_mirror(1, 0).unwrap();
+        routing.set_mirror(2, 1).unwrap();
+
+        // The chain 2 → 1 → 0 becomes 1 → 0 → 2.
+        routing.move_controller(0, 2);
+        assert_eq!(
+            mirrors(&routing.controllers.read().unwrap()),
+            [2, 0, NONE],
+        );
+        assert_eq!(
+            followers(&routing.controllers.read().unwrap()),
+            [vec![1], vec![], vec![0, 1]],
+        );
+
+        assert!(routing.set_mirror(2, 1).is_err());
+        assert!(routing.set_mirror(2, 0).is_err());
+        assert_eq!(
+            routing.controllers.read().unwrap()[2]
+                .mirror
+                .load(Ordering::SeqCst),
+            NONE,
+        );
+
+        // Without transitive mirroring, loops are fine.
+        routing.set_transitive_mirroring(false).unwrap();
+        assert!(routing.set_mirror(2, 1).is_ok());
+        assert!(routing.set_transitive_mirroring(true).is_err());
     }
 }
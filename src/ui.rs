@@ -1,27 +1,45 @@
 use crate::{
-    json,
-    key::key_name,
+    control,
+    key::{chord_name, key_name},
+    launch::Process,
+    persist::Saver,
+    stagger::Delay,
     state::{self, State},
 };
 use glib::Propagation;
 use gtk::prelude::*;
-use serde_json;
-use std::{
-    fs::{self, File},
-    path::PathBuf,
-    sync::{atomic::Ordering, Arc, RwLock},
-};
+use std::sync::{atomic::Ordering, Arc, RwLock};
+
+/// The grid column of the first action; the ones before it are for the
+/// controllers' handles, windows, mirrors, click mirroring toggles,
+/// keep-alive toggles, and launch buttons.
+const FIRST_ACTION_COLUMN: i32 = 6;
+
+/// How many items the mirror menus have after the controllers: a separator,
+/// the submenu of actions that are copied from the mirror, the mirror delay
+/// item, and the transitive mirroring toggle.
+const MIRROR_MENU_EXTRAS: usize = 4;
 
 pub struct Toonmux {
     pub main_window: gtk::Window,
     pub header: Header,
     pub interface: Interface,
+    pub saver: Arc<Saver>,
+    pub control: Arc<control::Server>,
 }
 
 pub struct Header {
     container: gtk::HeaderBar,
     pub expand: gtk::Button,
     pub mirroring: gtk::Button,
+    pub capture: gtk::Button,
+    pub attach_all: gtk::Button,
+    pub layout: gtk::MenuButton,
+    pub layout_menu: gtk::Menu,
+    pub macros: gtk::MenuButton,
+    pub macros_menu: gtk::Menu,
+    pub profile: gtk::MenuButton,
+    pub profile_menu: gtk::Menu,
     pub add: gtk::Button,
     pub remove: gtk::Button,
 }
@@ -33,55 +51,64 @@ pub struct Interface {
     pub controller_uis: RwLock<Vec<ControllerUi>>,
 }
 
+/// The actions' names, one per column.
 struct LabelRow {
-    forward_label: gtk::Label,
-    back_label: gtk::Label,
-    left_label: gtk::Label,
-    right_label: gtk::Label,
-    jump_label: gtk::Label,
-    dismount_label: gtk::Label,
-    throw_label: gtk::Label,
-    low_throw_label: gtk::Label,
-    talk_label: gtk::Label,
+    labels: RwLock<Vec<gtk::Label>>,
 }
 
 pub struct MainBindingsRow {
+    pub profile_label: gtk::Label,
     pub window_label: gtk::Label,
     pub mirror_label: gtk::Label,
-    pub forward: gtk::Button,
-    pub back: gtk::Button,
-    pub left: gtk::Button,
-    pub right: gtk::Button,
-    pub jump: gtk::Button,
-    pub dismount: gtk::Button,
-    pub throw: gtk::Button,
+    pub mirror_clicks_label: gtk::Label,
+    pub keep_alive_label: gtk::Label,
+    pub launch_label: gtk::Label,
+    /// The actions' main keys, one per column.
+    pub actions: RwLock<Vec<gtk::Button>>,
     pub toggle_mirroring: gtk::Button,
+    pub cycle_profile: gtk::Button,
+    pub cycle_layout: gtk::Button,
 }
 
 pub struct ControllerUi {
+    /// Drag handle, for reordering (or removing) this controller.
+    pub handle: gtk::EventBox,
     pub pick_window: gtk::Button,
     pub mirror: Mirror,
-    pub forward: gtk::Button,
-    pub back: gtk::Button,
-    pub left: gtk::Button,
-    pub right: gtk::Button,
-    pub jump: gtk::Button,
-    pub dismount: gtk::Button,
-    pub throw: gtk::Button,
-    pub low_throw: gtk::Button,
-    pub talk: gtk::Button,
+    pub mirror_clicks: gtk::ToggleButton,
+    pub keep_alive: gtk::ToggleButton,
+    /// (Re)launches this controller's game client, and shows how it's doing.
+    pub launch: gtk::Button,
+    /// One button per action.
+    pub bindings: Vec<gtk::Button>,
 }
 
 pub struct Mirror {
     pub button: gtk::MenuButton,
+    /// One item per controller that can be mirrored (plus "none"), followed
+    /// by the `MIRROR_MENU_EXTRAS`.
     pub menu: gtk::Menu,
+    /// Whether or not each action is copied from the mirror, in the same
+    /// order as `State::actions`.
+    pub copies: Vec<gtk::CheckMenuItem>,
+    /// Asks for the range that the keys from the mirror are held back by.
+    pub delay: gtk::MenuItem,
+    /// Whether or not mirroring is transitive.  This is the same for every
+    /// controller.
+    pub follow_chains: gtk::CheckMenuItem,
 }
 
 impl Toonmux {
-    pub fn new(state: Arc<State>, config_path: PathBuf) -> Self {
+    pub fn new(
+        state: Arc<State>,
+        saver: Arc<Saver>,
+        control: Arc<control::Server>,
+    ) -> Self {
         let main_window = gtk::Window::new(gtk::WindowType::Toplevel);
 
         let header = Header::new();
+        header.change_capturing(state.capturing.load(Ordering::SeqCst));
+        header.set_profile_name(&state.active_profile_name());
         let interface = Interface::new(&state);
 
         main_window.set_titlebar(Some(&header.container));
@@ -91,41 +118,15 @@ impl Toonmux {
         // The icon that the app will display.
         //Window::set_default_icon_name("iconname");
 
+        saver.start_periodic(&state);
+
         // Programs what to do when the exit button is used.
+        let saver_clone = Arc::clone(&saver);
         main_window.connect_delete_event(move |_, _| {
-            // Save current state to config file.
-            let config_parent_path = config_path.parent().unwrap();
-            if let Err(ioe) = fs::create_dir_all(config_parent_path) {
-                eprintln!(
-                    "Failed to ensure that {} exists as a directory with the \
-                     following error:\n\t{}",
-                    config_parent_path.display(),
-                    ioe,
-                );
-            }
+            let saver = &saver_clone;
 
-            let json_state = json::State::from_state_ref(&state);
-            match File::create(&config_path) {
-                Ok(f) => {
-                    if let Err(e) = json_state.to_writer(f) {
-                        eprintln!(
-                            "Serializing to {} failed with the following \
-                             error:\n\t{}\nThis is what should have been \
-                             written:\n{}",
-                            config_path.display(),
-                            e,
-                            serde_json::to_string_pretty(&json_state).unwrap(),
-                        );
-                    }
-                }
-                Err(ioe) => eprintln!(
-                    "Failed to create the file {} with the following \
-                     error:\n\t{}\nThis is what should have been written:\n{}",
-                    config_path.display(),
-                    ioe,
-                    serde_json::to_string_pretty(&json_state).unwrap(),
-                ),
-            }
+            // Save current state to config file.
+            saver.save(&state);
 
             // Actually quit.
             gtk::main_quit();
@@ -137,8 +138,17 @@ impl Toonmux {
             main_window,
             header,
             interface,
+            saver,
+            control,
         }
     }
+
+    /// Lets everyone who cares know that (persistent) state has changed.
+    #[inline]
+    pub fn state_changed(&self, state: &Arc<State>) {
+        self.saver.schedule(state);
+        self.control.notify(state);
+    }
 }
 
 impl Header {
@@ -155,18 +165,59 @@ impl Header {
         let mirroring = gtk::Button::with_label("\u{22a3}");
         container.pack_start(&mirroring);
 
+        let capture = gtk::Button::with_label("\u{2328}");
+        container.pack_start(&capture);
+
+        let attach_all = gtk::Button::with_label("\u{229e}");
+        attach_all.set_tooltip_text(Some(
+            "Attach every game window that isn\u{2019}t attached yet to a \
+             controller that has no window",
+        ));
+        container.pack_start(&attach_all);
+
+        let layout = gtk::MenuButton::new();
+        layout.set_label("\u{25a6}");
+        let layout_menu = gtk::Menu::new();
+        layout.set_popup(Some(&layout_menu));
+        container.pack_start(&layout);
+
+        let macros = gtk::MenuButton::new();
+        macros.set_label("\u{23fa}");
+        let macros_menu = gtk::Menu::new();
+        macros.set_popup(Some(&macros_menu));
+        container.pack_start(&macros);
+
+        let profile = gtk::MenuButton::new();
+        let profile_menu = gtk::Menu::new();
+        profile.set_popup(Some(&profile_menu));
+        container.pack_start(&profile);
+
         let add = gtk::Button::with_label("+");
         add.style_context().add_class("suggested-action");
         container.pack_start(&add);
 
         let remove = gtk::Button::with_label("-");
         remove.style_context().add_class("destructive-action");
+        // Controllers' drag handles can be dropped here to remove them.
+        remove.drag_dest_set(
+            gtk::DestDefaults::ALL,
+            &controller_drag_targets(),
+            gdk::DragAction::MOVE,
+        );
         container.pack_start(&remove);
 
         Self {
             container,
             expand,
             mirroring,
+            capture,
+            attach_all,
+            layout,
+            layout_menu,
+            macros,
+            macros_menu,
+            profile,
+            profile_menu,
             add,
             remove,
         }
@@ -179,6 +230,62 @@ impl Header {
             self.mirroring.set_label("\u{1f6c7}");
         }
     }
+
+    /// Shows which layout the windows were last arranged with (if any) in
+    /// the layout button's tooltip.
+    pub fn set_layout_name(&self, name: Option<&str>) {
+        match name {
+            Some(name) => self.layout.set_tooltip_text(Some(&format!(
+                "The game windows are arranged using the \u{201c}{}\u{201d} \
+                 layout",
+                name,
+            ))),
+            None => self
+                .layout
+                .set_tooltip_text(Some("Arrange the attached game windows")),
+        }
+    }
+
+    /// Shows whether a macro is being recorded or played back on the macros
+    /// button.
+    pub fn set_macro_status(
+        &self,
+        recording: Option<&str>,
+        playing: Option<&str>,
+    ) {
+        let macros_style_ctx = self.macros.style_context();
+        macros_style_ctx.remove_class("destructive-action");
+        macros_style_ctx.remove_class("suggested-action");
+
+        if let Some(name) = recording {
+            macros_style_ctx.add_class("destructive-action");
+            self.macros.set_tooltip_text(Some(&format!(
+                "Recording the \u{201c}{}\u{201d} macro",
+                name,
+            )));
+        } else if let Some(name) = playing {
+            macros_style_ctx.add_class("suggested-action");
+            self.macros.set_tooltip_text(Some(&format!(
+                "Playing the \u{201c}{}\u{201d} macro",
+                name,
+            )));
+        } else {
+            self.macros.set_tooltip_text(Some("Record & play macros"));
+        }
+    }
+
+    pub fn set_profile_name(&self, name: &str) {
+        self.profile.set_label(name);
+    }
+
+    pub fn change_capturing(&self, to: bool) {
+        let capture_style_ctx = self.capture.style_context();
+        if to {
+            capture_style_ctx.add_class("suggested-action");
+        } else {
+            capture_style_ctx.remove_class("suggested-action");
+        }
+    }
 }
 
 impl Interface {
@@ -187,11 +294,13 @@ impl Interface {
         container.set_row_spacing(2);
         container.set_column_spacing(2);
 
-        let label_row = LabelRow::new();
+        let label_row = LabelRow::new(state);
         let main_bindings_row = MainBindingsRow::new(state);
         let controller_uis = {
+            // Getting a read lock on the action state reader-writer lock.
+            let actions = state.routing.actions.read().unwrap();
             // Getting a read lock on controller state reader-writer lock.
-            let ctls_state = state.controllers.read().unwrap();
+            let ctls_state = state.routing.controllers.read().unwrap();
 
             let ctl_count = ctls_state.len();
             RwLock::new(
@@ -199,12 +308,13 @@ impl Interface {
                     .iter()
                     .enumerate()
                     .map(|(i, ctl_state)| {
-                        ControllerUi::new(ctl_state, i, ctl_count)
+                        ControllerUi::new(ctl_state, &actions, i, ctl_count)
                     })
                     .collect(),
             )
 
             // Relinquishing read lock on controller state reader-writer lock.
+            // Relinquishing read lock on the action state reader-writer lock.
         };
 
         let mut interface = Self {
@@ -219,267 +329,337 @@ impl Interface {
     }
 
     fn attach(&mut self) {
+        self.container.attach(
+            &self.main_bindings_row.profile_label,
+            0,
+            0,
+            1,
+            1,
+        );
+        self.container.attach(
+            &self.main_bindings_row.window_label,
+            1,
+            0,
+            1,
+            1,
+        );
         self.container.attach(
             &self.main_bindings_row.mirror_label,
+            2,
+            0,
+            1,
             1,
+        );
+        self.container.attach(
+            &self.main_bindings_row.mirror_clicks_label,
+            3,
+            0,
+            1,
+            1,
+        );
+        self.container.attach(
+            &self.main_bindings_row.keep_alive_label,
+            4,
+            0,
+            1,
+            1,
+        );
+        self.container.attach(
+            &self.main_bindings_row.launch_label,
+            5,
             0,
             1,
             1,
         );
-        self.container
-            .attach(&self.label_row.forward_label, 2, 0, 1, 1);
-        self.container
-            .attach(&self.label_row.back_label, 3, 0, 1, 1);
-        self.container
-            .attach(&self.label_row.left_label, 4, 0, 1, 1);
-        self.container
-            .attach(&self.label_row.right_label, 5, 0, 1, 1);
-        self.container
-            .attach(&self.label_row.jump_label, 6, 0, 1, 1);
-        self.container
-            .attach(&self.label_row.dismount_label, 7, 0, 1, 1);
-        self.container
-            .attach(&self.label_row.throw_label, 8, 0, 1, 1);
-        self.container
-            .attach(&self.label_row.low_throw_label, 9, 0, 1, 1);
-        self.container
-            .attach(&self.label_row.talk_label, 10, 0, 1, 1);
+        self.attach_actions();
 
         self.container.attach(
-            &self.main_bindings_row.window_label,
+            &self.main_bindings_row.cycle_profile,
             0,
             1,
             1,
             1,
         );
         self.container.attach(
-            &self.main_bindings_row.toggle_mirroring,
+            &self.main_bindings_row.cycle_layout,
             1,
             1,
             1,
             1,
         );
-        self.container
-            .attach(&self.main_bindings_row.forward, 2, 1, 1, 1);
-        self.container
-            .attach(&self.main_bindings_row.back, 3, 1, 1, 1);
-        self.container
-            .attach(&self.main_bindings_row.left, 4, 1, 1, 1);
-        self.container
-            .attach(&self.main_bindings_row.right, 5, 1, 1, 1);
-        self.container
-            .attach(&self.main_bindings_row.jump, 6, 1, 1, 1);
-        self.container
-            .attach(&self.main_bindings_row.dismount, 7, 1, 1, 1);
-        self.container
-            .attach(&self.main_bindings_row.throw, 8, 1, 1, 1);
-
-        for (i, ctl_ui) in self
-            .controller_uis
-            .get_mut()
-            .unwrap()
-            .iter()
-            .enumerate()
-            .map(|(i, c)| (i as i32, c))
+        self.container.attach(
+            &self.main_bindings_row.toggle_mirroring,
+            2,
+            1,
+            1,
+            1,
+        );
+
+        for (i, ctl_ui) in
+            self.controller_uis.get_mut().unwrap().iter().enumerate()
+        {
+            ctl_ui.attach(&self.container, i);
+        }
+    }
+
+    /// Attaches the action labels & main key buttons to their columns.
+    fn attach_actions(&self) {
+        let labels = self.label_row.labels.read().unwrap();
+        let buttons = self.main_bindings_row.actions.read().unwrap();
+
+        for (i, (label, button)) in
+            labels.iter().zip(buttons.iter()).enumerate()
         {
-            self.container.attach(&ctl_ui.pick_window, 0, 2 + i, 1, 1);
-            self.container.attach(&ctl_ui.mirror.button, 1, 2 + i, 1, 1);
-            self.container.attach(&ctl_ui.forward, 2, 2 + i, 1, 1);
-            self.container.attach(&ctl_ui.back, 3, 2 + i, 1, 1);
-            self.container.attach(&ctl_ui.left, 4, 2 + i, 1, 1);
-            self.container.attach(&ctl_ui.right, 5, 2 + i, 1, 1);
-            self.container.attach(&ctl_ui.jump, 6, 2 + i, 1, 1);
-            self.container.attach(&ctl_ui.dismount, 7, 2 + i, 1, 1);
-            self.container.attach(&ctl_ui.throw, 8, 2 + i, 1, 1);
-            self.container.attach(&ctl_ui.low_throw, 9, 2 + i, 1, 1);
-            self.container.attach(&ctl_ui.talk, 10, 2 + i, 1, 1);
+            let column = FIRST_ACTION_COLUMN + i as i32;
+            self.container.attach(label, column, 0, 1, 1);
+            self.container.attach(button, column, 1, 1, 1);
         }
     }
 
+    /// Throws away the action labels & main key buttons, and creates them
+    /// anew from `state`.  This is needed whenever the set of actions
+    /// changes.  The controller UIs have to be rebuilt separately.
+    pub fn rebuild_actions(&self, state: &State) {
+        {
+            // Getting a write lock on the label UIs' reader-writer lock.
+            let mut labels = self.label_row.labels.write().unwrap();
+            // Getting a write lock on the main key UIs' reader-writer lock.
+            let mut buttons = self.main_bindings_row.actions.write().unwrap();
+
+            for label in labels.drain(..) {
+                self.container.remove(&label);
+            }
+            for button in buttons.drain(..) {
+                self.container.remove(&button);
+            }
+            *labels = action_labels(state);
+            *buttons = action_buttons(state);
+
+            // Relinquishing write lock on the main key UIs' reader-writer
+            // lock.
+            // Relinquishing write lock on the label UIs' reader-writer lock.
+        }
+
+        self.attach_actions();
+        self.container.show_all();
+    }
+
     pub fn add_controller(
         &self,
         ctl_state: &state::Controller,
+        actions: &[state::ActionDef],
         ctl_count: usize,
     ) {
         let ctl_ix = ctl_count - 1;
-        let ctl_ui = ControllerUi::new(ctl_state, ctl_ix, ctl_count);
-
-        let ctl_ix = ctl_ix as i32;
-        self.container
-            .attach(&ctl_ui.pick_window, 0, 2 + ctl_ix, 1, 1);
-        self.container
-            .attach(&ctl_ui.mirror.button, 1, 2 + ctl_ix, 1, 1);
-        self.container.attach(&ctl_ui.forward, 2, 2 + ctl_ix, 1, 1);
-        self.container.attach(&ctl_ui.back, 3, 2 + ctl_ix, 1, 1);
-        self.container.attach(&ctl_ui.left, 4, 2 + ctl_ix, 1, 1);
-        self.container.attach(&ctl_ui.right, 5, 2 + ctl_ix, 1, 1);
-        self.container.attach(&ctl_ui.jump, 6, 2 + ctl_ix, 1, 1);
-        self.container.attach(&ctl_ui.dismount, 7, 2 + ctl_ix, 1, 1);
-        self.container.attach(&ctl_ui.throw, 8, 2 + ctl_ix, 1, 1);
-        self.container
-            .attach(&ctl_ui.low_throw, 9, 2 + ctl_ix, 1, 1);
-        self.container.attach(&ctl_ui.talk, 10, 2 + ctl_ix, 1, 1);
+        let ctl_ui = ControllerUi::new(ctl_state, actions, ctl_ix, ctl_count);
+        ctl_ui.attach(&self.container, ctl_ix);
 
         self.controller_uis.write().unwrap().push(ctl_ui);
 
         self.container.show_all();
     }
 
-    pub fn remove_controller(&self) {
-        if let Some(removed_ctl) = {
-            let mut ctl_uis = self.controller_uis.write().unwrap();
+    /// Throws away all of the controller UIs, and creates them anew from
+    /// `state`.  This is needed whenever controllers get renumbered, since
+    /// their indices are baked into their UIs (and signal handlers).
+    pub fn rebuild_controllers(&self, state: &State) {
+        // Getting a read lock on the action state reader-writer lock.
+        let actions = state.routing.actions.read().unwrap();
+        // Getting a read lock on controller state reader-writer lock.
+        let ctls_state = state.routing.controllers.read().unwrap();
+        // Getting a write lock on the controller UIs' reader-writer lock.
+        let mut ctl_uis = self.controller_uis.write().unwrap();
+
+        for ctl_ui in ctl_uis.drain(..) {
+            ctl_ui.remove(&self.container);
+        }
 
-            ctl_uis.pop()
-        } {
-            removed_ctl.remove(&self.container);
+        let ctl_count = ctls_state.len();
+        ctl_uis.extend(ctls_state.iter().enumerate().map(|(i, ctl_state)| {
+            let ctl_ui = ControllerUi::new(ctl_state, &actions, i, ctl_count);
+            ctl_ui.attach(&self.container, i);
 
-            for ctl in self.controller_uis.read().unwrap().iter() {
-                ctl.mirror.remove_menu_item();
-            }
-        }
+            ctl_ui
+        }));
+
+        self.container.show_all();
+
+        // Relinquishing write lock on the controller UIs' reader-writer lock.
+        // Relinquishing read lock on controller state reader-writer lock.
+        // Relinquishing read lock on the action state reader-writer lock.
     }
 }
 
 impl LabelRow {
-    fn new() -> Self {
+    fn new(state: &State) -> Self {
         Self {
-            forward_label: gtk::Label::new(Some("forward")),
-            back_label: gtk::Label::new(Some("back")),
-            left_label: gtk::Label::new(Some("left")),
-            right_label: gtk::Label::new(Some("right")),
-            jump_label: gtk::Label::new(Some("jump")),
-            dismount_label: gtk::Label::new(Some("dismount")),
-            throw_label: gtk::Label::new(Some("throw")),
-            low_throw_label: gtk::Label::new(Some("low throw")),
-            talk_label: gtk::Label::new(Some("talk")),
+            labels: RwLock::new(action_labels(state)),
         }
     }
 }
 
 impl MainBindingsRow {
     fn new(state: &State) -> Self {
+        let cycle_layout = gtk::Button::with_label(
+            key_name(state.routing.main_bindings.cycle_layout()).as_str(),
+        );
+        cycle_layout.set_tooltip_text(Some(
+            "The key for arranging the windows using the next layout",
+        ));
+
         Self {
+            profile_label: gtk::Label::new(Some("profile")),
             window_label: gtk::Label::new(Some("window")),
             mirror_label: gtk::Label::new(Some("mirror")),
-            forward: gtk::Button::with_label(
-                key_name(state.main_bindings.forward()).as_str(),
-            ),
-            back: gtk::Button::with_label(
-                key_name(state.main_bindings.back()).as_str(),
-            ),
-            left: gtk::Button::with_label(
-                key_name(state.main_bindings.left()).as_str(),
-            ),
-            right: gtk::Button::with_label(
-                key_name(state.main_bindings.right()).as_str(),
-            ),
-            jump: gtk::Button::with_label(
-                key_name(state.main_bindings.jump()).as_str(),
-            ),
-            dismount: gtk::Button::with_label(
-                key_name(state.main_bindings.dismount()).as_str(),
-            ),
-            throw: gtk::Button::with_label(
-                key_name(state.main_bindings.throw()).as_str(),
-            ),
+            mirror_clicks_label: gtk::Label::new(Some("clicks")),
+            keep_alive_label: gtk::Label::new(Some("keep alive")),
+            launch_label: gtk::Label::new(Some("game")),
+            actions: RwLock::new(action_buttons(state)),
             toggle_mirroring: gtk::Button::with_label(
-                key_name(state.main_bindings.toggle_mirroring()).as_str(),
+                key_name(state.routing.main_bindings.toggle_mirroring())
+                    .as_str(),
+            ),
+            cycle_profile: gtk::Button::with_label(
+                key_name(state.routing.main_bindings.cycle_profile()).as_str(),
             ),
+            cycle_layout,
         }
     }
+
+    /// Updates every button's label to match the main bindings in `state`.
+    pub fn refresh(&self, state: &State) {
+        for (button, action) in self
+            .actions
+            .read()
+            .unwrap()
+            .iter()
+            .zip(state.routing.actions.read().unwrap().iter())
+        {
+            button.set_label(key_name(action.key()).as_str());
+        }
+        self.toggle_mirroring.set_label(
+            key_name(state.routing.main_bindings.toggle_mirroring()).as_str(),
+        );
+        self.cycle_profile.set_label(
+            key_name(state.routing.main_bindings.cycle_profile()).as_str(),
+        );
+        self.cycle_layout.set_label(
+            key_name(state.routing.main_bindings.cycle_layout()).as_str(),
+        );
+    }
 }
 
 impl ControllerUi {
     fn new(
         ctl_state: &state::Controller,
+        actions: &[state::ActionDef],
         ctl_ix: usize,
         ctl_count: usize,
     ) -> Self {
-        let pick_window = gtk::Button::with_label("+");
-        pick_window.style_context().add_class("suggested-action");
+        let handle = gtk::EventBox::new();
+        handle
+            .add(&gtk::Label::new(Some(&format!("\u{2630} {}", ctl_ix + 1,))));
+        handle.set_tooltip_text(Some(
+            "Drag onto another controller to move this one there, or onto \
+             \u{201c}-\u{201d} to remove it",
+        ));
+
+        handle.drag_source_set(
+            gdk::ModifierType::BUTTON1_MASK,
+            &controller_drag_targets(),
+            gdk::DragAction::MOVE,
+        );
+        handle.drag_dest_set(
+            gtk::DestDefaults::ALL,
+            &controller_drag_targets(),
+            gdk::DragAction::MOVE,
+        );
+
+        let pick_window = gtk::Button::new();
+        set_window_attached(
+            &pick_window,
+            ctl_state.window.load(Ordering::SeqCst) != 0,
+        );
+
+        let mirror_clicks = gtk::ToggleButton::with_label("\u{1f5b1}");
+        let mirror_clicks_on = ctl_state.mirror_clicks.load(Ordering::SeqCst);
+        mirror_clicks.set_active(mirror_clicks_on);
+        set_mirror_clicks(&mirror_clicks, mirror_clicks_on);
+
+        let mirror = Mirror::new(ctl_state, actions, ctl_ix, ctl_count);
+        set_mirror_delay(
+            &mirror.button,
+            *ctl_state.mirror_delay.read().unwrap(),
+        );
+
+        let keep_alive = gtk::ToggleButton::with_label("\u{2615}");
+        let keep_alive_on = ctl_state.keep_alive.load(Ordering::SeqCst);
+        keep_alive.set_active(keep_alive_on);
+        set_keep_alive(&keep_alive, keep_alive_on);
+
+        let launch = gtk::Button::new();
+        set_process(&launch, ctl_state);
 
         Self {
+            handle,
             pick_window,
-            mirror: Mirror::new(
-                ctl_state.mirror.load(Ordering::SeqCst),
-                ctl_ix,
-                ctl_count,
-            ),
-            forward: gtk::Button::with_label(
-                key_name(
-                    ctl_state.bindings.forward.load(Ordering::SeqCst).into(),
-                )
-                .as_str(),
-            ),
-            back: gtk::Button::with_label(
-                key_name(
-                    ctl_state.bindings.back.load(Ordering::SeqCst).into(),
-                )
-                .as_str(),
-            ),
-            left: gtk::Button::with_label(
-                key_name(
-                    ctl_state.bindings.left.load(Ordering::SeqCst).into(),
-                )
-                .as_str(),
-            ),
-            right: gtk::Button::with_label(
-                key_name(
-                    ctl_state.bindings.right.load(Ordering::SeqCst).into(),
-                )
-                .as_str(),
-            ),
-            jump: gtk::Button::with_label(
-                key_name(
-                    ctl_state.bindings.jump.load(Ordering::SeqCst).into(),
-                )
-                .as_str(),
-            ),
-            dismount: gtk::Button::with_label(
-                key_name(
-                    ctl_state.bindings.dismount.load(Ordering::SeqCst).into(),
-                )
-                .as_str(),
-            ),
-            throw: gtk::Button::with_label(
-                key_name(
-                    ctl_state.bindings.throw.load(Ordering::SeqCst).into(),
-                )
-                .as_str(),
-            ),
-            low_throw: gtk::Button::with_label(
-                key_name(
-                    ctl_state.bindings.low_throw.load(Ordering::SeqCst).into(),
-                )
-                .as_str(),
-            ),
-            talk: gtk::Button::with_label(
-                key_name(
-                    ctl_state.bindings.talk.load(Ordering::SeqCst).into(),
-                )
-                .as_str(),
-            ),
+            mirror,
+            mirror_clicks,
+            keep_alive,
+            launch,
+            bindings: ctl_state
+                .bindings
+                .iter()
+                .map(|b| {
+                    gtk::Button::with_label(&chord_name(
+                        b.load(Ordering::SeqCst),
+                    ))
+                })
+                .collect(),
+        }
+    }
+
+    fn attach(&self, container: &gtk::Grid, ctl_ix: usize) {
+        let row = 2 + ctl_ix as i32;
+        container.attach(&self.handle, 0, row, 1, 1);
+        container.attach(&self.pick_window, 1, row, 1, 1);
+        container.attach(&self.mirror.button, 2, row, 1, 1);
+        container.attach(&self.mirror_clicks, 3, row, 1, 1);
+        container.attach(&self.keep_alive, 4, row, 1, 1);
+        container.attach(&self.launch, 5, row, 1, 1);
+        for (i, binding) in self.bindings.iter().enumerate() {
+            container.attach(
+                binding,
+                FIRST_ACTION_COLUMN + i as i32,
+                row,
+                1,
+                1,
+            );
         }
     }
 
     fn remove<C: IsA<gtk::Container>>(&self, container: &C) {
+        container.remove(&self.handle);
         container.remove(&self.pick_window);
         container.remove(&self.mirror.menu);
         container.remove(&self.mirror.button);
-        container.remove(&self.forward);
-        container.remove(&self.back);
-        container.remove(&self.left);
-        container.remove(&self.right);
-        container.remove(&self.jump);
-        container.remove(&self.dismount);
-        container.remove(&self.throw);
-        container.remove(&self.low_throw);
-        container.remove(&self.talk);
+        container.remove(&self.mirror_clicks);
+        container.remove(&self.keep_alive);
+        container.remove(&self.launch);
+        for binding in self.bindings.iter() {
+            container.remove(binding);
+        }
     }
 }
 
 impl Mirror {
-    fn new(val: usize, ctl_ix: usize, ctl_count: usize) -> Self {
+    fn new(
+        ctl_state: &state::Controller,
+        actions: &[state::ActionDef],
+        ctl_ix: usize,
+        ctl_count: usize,
+    ) -> Self {
+        let val = ctl_state.mirror.load(Ordering::SeqCst);
         let button = gtk::MenuButton::new();
         button.child().map(|c| button.remove(&c));
         if val == ::std::usize::MAX {
@@ -489,51 +669,262 @@ impl Mirror {
         }
 
         let menu = gtk::Menu::new();
-        menu.attach(&gtk::MenuItem::with_label("none"), 0, 1, 0, 1);
+        menu.append(&gtk::MenuItem::with_label("none"));
         for i in 1..(ctl_count + 1) {
-            let i_u32 = i as u32;
-
             if i != ctl_ix + 1 {
-                menu.attach(
-                    &gtk::MenuItem::with_label(&i.to_string()),
-                    0,
-                    1,
-                    i_u32,
-                    i_u32 + 1,
-                );
+                menu.append(&gtk::MenuItem::with_label(&i.to_string()));
             } else {
-                menu.attach(
-                    &gtk::SeparatorMenuItem::new(),
-                    0,
-                    1,
-                    i_u32,
-                    i_u32 + 1,
-                );
+                menu.append(&gtk::SeparatorMenuItem::new());
             }
         }
+
+        // The actions that are copied from the mirror go last, in their own
+        // submenu (see `MIRROR_MENU_EXTRAS`).
+        let copies_menu = gtk::Menu::new();
+        let copies: Vec<_> = actions
+            .iter()
+            .enumerate()
+            .map(|(action_ix, a)| {
+                let item = gtk::CheckMenuItem::with_label(&a.label());
+                item.set_active(ctl_state.copies(action_ix));
+                copies_menu.append(&item);
+
+                item
+            })
+            .collect();
+        let copies_item = gtk::MenuItem::with_label("copied actions");
+        copies_item.set_submenu(Some(&copies_menu));
+        let delay = gtk::MenuItem::with_label("delay\u{2026}");
+        let follow_chains =
+            gtk::CheckMenuItem::with_label("follow chains of mirrors");
+        menu.append(&gtk::SeparatorMenuItem::new());
+        menu.append(&copies_item);
+        menu.append(&delay);
+        menu.append(&follow_chains);
         menu.show_all();
 
         button.set_popup(Some(&menu));
 
-        Self { button, menu }
+        Self {
+            button,
+            menu,
+            copies,
+            delay,
+            follow_chains,
+        }
+    }
+
+    /// The items that pick a mirror, along with their indices: `0` for
+    /// "none", and `i` for controller `i - 1`.
+    pub fn targets(&self) -> Vec<(usize, gtk::MenuItem)> {
+        let children = self.menu.children();
+        let target_count = children.len() - MIRROR_MENU_EXTRAS;
+
+        children
+            .into_iter()
+            .take(target_count)
+            .enumerate()
+            .filter(|(_, c)| {
+                c.downcast_ref::<gtk::SeparatorMenuItem>().is_none()
+            })
+            .map(|(i, c)| (i, c.downcast::<gtk::MenuItem>().unwrap()))
+            .collect()
+    }
+
+    /// Brings the menu of the controller at `ctl_ix` up to date with how the
+    /// mirrors currently are.  Mirrors that would make a loop can't be
+    /// picked while mirroring is transitive, and vice versa.
+    pub fn refresh(&self, state: &State, ctl_ix: usize) {
+        for (i, target) in self.targets().into_iter().skip(1) {
+            if state.routing.mirror_would_loop(ctl_ix, i - 1) {
+                target.set_sensitive(false);
+                target.set_tooltip_text(Some(&format!(
+                    "Controller {} already follows this one, so mirroring it \
+                     would make a loop",
+                    i,
+                )));
+            } else {
+                target.set_sensitive(true);
+                target.set_tooltip_text(None);
+            }
+        }
+
+        let transitive =
+            state.routing.transitive_mirroring.load(Ordering::SeqCst);
+        self.follow_chains.set_active(transitive);
+        match state.routing.mirror_loop().filter(|_| !transitive) {
+            Some(loop_ix) => {
+                self.follow_chains.set_sensitive(false);
+                self.follow_chains.set_tooltip_text(Some(&format!(
+                    "The mirrors starting at controller {} loop back around \
+                     to it; break the loop to follow chains of mirrors",
+                    loop_ix + 1,
+                )));
+            }
+            None => {
+                self.follow_chains.set_sensitive(true);
+                self.follow_chains.set_tooltip_text(Some(
+                    "Whether or not controllers that mirror this one also \
+                     get the keys that it gets from its own mirror",
+                ));
+            }
+        }
     }
 
     #[inline]
     pub fn add_menu_item(&self) -> gtk::MenuItem {
         // FIXME: `.get_children()` allocates.
-        let len = self.menu.children().len() as u32;
+        let len = self.menu.children().len() - MIRROR_MENU_EXTRAS;
 
         let new_item = gtk::MenuItem::with_label(&len.to_string());
-        self.menu.attach(&new_item, 0, 1, len, len + 1);
+        self.menu.insert(&new_item, len as i32);
         new_item.show_all();
 
         new_item
     }
+}
 
-    #[inline]
-    pub fn remove_menu_item(&self) {
-        if let Some(menu_item) = self.menu.children().last() {
-            self.menu.remove(menu_item);
+/// One label per action.
+fn action_labels(state: &State) -> Vec<gtk::Label> {
+    state
+        .routing
+        .actions
+        .read()
+        .unwrap()
+        .iter()
+        .map(|a| gtk::Label::new(Some(&a.label())))
+        .collect()
+}
+
+/// One main key button per action.
+fn action_buttons(state: &State) -> Vec<gtk::Button> {
+    state
+        .routing
+        .actions
+        .read()
+        .unwrap()
+        .iter()
+        .map(|a| gtk::Button::with_label(key_name(a.key()).as_str()))
+        .collect()
+}
+
+/// Drag-and-drop targets for dragging a controller (by its handle) around.
+/// The payload is the controller's index, as a decimal string.
+#[inline]
+pub fn controller_drag_targets() -> [gtk::TargetEntry; 1] {
+    [gtk::TargetEntry::new(
+        "application/x-toonmux-controller",
+        gtk::TargetFlags::SAME_APP,
+        0,
+    )]
+}
+
+/// Styles a controller's pick-a-window button according to whether or not the
+/// controller currently has a window associated with it.
+pub fn set_window_attached(pick_window: &gtk::Button, attached: bool) {
+    let pw_style_ctx = pick_window.style_context();
+    if attached {
+        pick_window.set_label("\u{2213}");
+        pw_style_ctx.remove_class("suggested-action");
+        pw_style_ctx.add_class("destructive-action");
+    } else {
+        pick_window.set_label("+");
+        pw_style_ctx.remove_class("destructive-action");
+        pw_style_ctx.add_class("suggested-action");
+    }
+}
+
+/// Styles a controller's click mirroring toggle according to whether or not
+/// clicks are mirrored to its window.
+pub fn set_mirror_clicks(mirror_clicks: &gtk::ToggleButton, on: bool) {
+    let mc_style_ctx = mirror_clicks.style_context();
+    if on {
+        mc_style_ctx.add_class("suggested-action");
+        mirror_clicks.set_tooltip_text(Some(
+            "Clicks in the mirrored window are repeated in this one; click \
+             to stop",
+        ));
+    } else {
+        mc_style_ctx.remove_class("suggested-action");
+        mirror_clicks.set_tooltip_text(Some(
+            "Click to repeat clicks in the mirrored window in this one, too",
+        ));
+    }
+}
+
+/// Tells, via its mirror button, how long a controller has the keys that it
+/// gets from its mirror held back.
+pub fn set_mirror_delay(mirror: &gtk::MenuButton, delay: Delay) {
+    if delay.is_zero() {
+        mirror.set_tooltip_text(None);
+    } else if delay.min == delay.max {
+        mirror.set_tooltip_text(Some(&format!(
+            "Mirrored keys are held back by {} ms",
+            delay.max,
+        )));
+    } else {
+        mirror.set_tooltip_text(Some(&format!(
+            "Mirrored keys are held back by {}\u{2013}{} ms",
+            delay.min, delay.max,
+        )));
+    }
+}
+
+/// Styles a controller's keep-alive toggle according to whether or not its
+/// toon is being kept alive.
+pub fn set_keep_alive(keep_alive: &gtk::ToggleButton, on: bool) {
+    let ka_style_ctx = keep_alive.style_context();
+    if on {
+        ka_style_ctx.add_class("suggested-action");
+        keep_alive.set_tooltip_text(Some(
+            "This toon is kept alive when idle; click to stop",
+        ));
+    } else {
+        ka_style_ctx.remove_class("suggested-action");
+        keep_alive
+            .set_tooltip_text(Some("Click to keep this toon alive when idle"));
+    }
+}
+
+/// Styles a controller's launch button according to its launch command, and
+/// to what became of the last game client that it launched.
+pub fn set_process(launch: &gtk::Button, ctl_state: &state::Controller) {
+    let command = ctl_state.launch.read().unwrap();
+    let process = ctl_state.process.lock().unwrap();
+
+    let launch_style_ctx = launch.style_context();
+    launch_style_ctx.remove_class("destructive-action");
+    match *command {
+        None => {
+            launch.set_label("\u{2013}");
+            launch.set_sensitive(false);
+            launch.set_tooltip_text(Some(
+                "This controller has no launch command",
+            ));
+        }
+        Some(_) if process.is_running() => {
+            launch.set_label("\u{25cf}");
+            launch.set_sensitive(false);
+            launch.set_tooltip_text(Some(&format!("The game is {}", process)));
+        }
+        Some(ref command) => {
+            launch.set_sensitive(true);
+            if let Process::None = *process {
+                launch.set_label("\u{25b6}");
+                launch.set_tooltip_text(Some(&format!(
+                    "Click to launch {}",
+                    command,
+                )));
+            } else {
+                launch.set_label("\u{27f3}");
+                if process.failed() {
+                    launch_style_ctx.add_class("destructive-action");
+                }
+                launch.set_tooltip_text(Some(&format!(
+                    "The game {}; click to launch it again",
+                    process,
+                )));
+            }
         }
     }
 }
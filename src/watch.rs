@@ -0,0 +1,225 @@
+//! Noticing when attached game windows go away (because the game was closed,
+//! or crashed), and when new windows show up that might replace them.
+
+use crate::discover::{self, WindowIdentity};
+use std::{
+    os::{raw::c_ulong, unix::io::RawFd},
+    ptr,
+    sync::Mutex,
+    time::Duration,
+};
+use x11::xlib::{self, Window};
+
+/// How long to give the window manager (and new windows) to settle down
+/// before looking at a window that was just unmapped, or at windows that just
+/// showed up.
+pub const SETTLE: Duration = Duration::from_secs(2);
+
+/// `WM_STATE`'s state for windows that aren't managed (anymore).
+const WITHDRAWN_STATE: c_ulong = 0;
+
+#[derive(Debug)]
+pub struct WindowWatch {
+    display: *mut xlib::Display,
+    root: Window,
+    client_list: xlib::Atom,
+    wm_state: xlib::Atom,
+    /// The windows that we're watching, along with what they were like when
+    /// we started watching them, since there's no asking a destroyed window
+    /// what it used to be.
+    watched: Mutex<Vec<(Window, Option<WindowIdentity>)>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchEvent {
+    /// A watched window was destroyed.
+    Destroyed(Window),
+    /// A watched window was unmapped, which could mean that it was closed, or
+    /// just that it was minimized.  See `WindowWatch::is_withdrawn`.
+    Unmapped(Window),
+    /// Some top-level window came or went.
+    WindowsChanged,
+}
+
+impl WindowWatch {
+    /// Returns `None` iff we cannot open a connection to the X server.
+    pub fn new() -> Option<Self> {
+        let display = unsafe { xlib::XOpenDisplay(ptr::null()) };
+        if display.is_null() {
+            return None;
+        }
+
+        unsafe {
+            let root = xlib::XDefaultRootWindow(display);
+            xlib::XSelectInput(
+                display,
+                root,
+                xlib::SubstructureNotifyMask | xlib::PropertyChangeMask,
+            );
+            xlib::XFlush(display);
+
+            Some(Self {
+                display,
+                root,
+                client_list: xlib::XInternAtom(
+                    display,
+                    c"_NET_CLIENT_LIST".as_ptr(),
+                    xlib::False,
+                ),
+                wm_state: xlib::XInternAtom(
+                    display,
+                    c"WM_STATE".as_ptr(),
+                    xlib::False,
+                ),
+                watched: Default::default(),
+            })
+        }
+    }
+
+    /// The file descriptor of our X connection, which becomes readable
+    /// whenever there are (probably) events waiting for `next_event`.
+    #[inline(always)]
+    pub fn fd(&self) -> RawFd {
+        unsafe { xlib::XConnectionNumber(self.display) }
+    }
+
+    /// Replaces whatever windows are currently being watched with `windows`.
+    pub fn watch<I: IntoIterator<Item = Window>>(&self, windows: I) {
+        let mut windows: Vec<_> =
+            windows.into_iter().filter(|&w| w != 0).collect();
+        windows.sort_unstable();
+        windows.dedup();
+
+        // Getting a lock on the watched windows mutex.
+        let mut watched = self.watched.lock().unwrap();
+
+        let new_windows: Vec<_> = windows
+            .iter()
+            .copied()
+            .filter(|w| !watched.iter().any(|(v, _)| v == w))
+            .collect();
+        let identities =
+            discover::identify(&new_windows).unwrap_or_else(|e| {
+                eprintln!("Failed to identify windows: {}", e);
+
+                vec![None; new_windows.len()]
+            });
+
+        discover::with_errors_ignored(self.display, || unsafe {
+            for (window, _) in watched.iter() {
+                if !windows.contains(window) {
+                    xlib::XSelectInput(self.display, *window, 0);
+                }
+            }
+            for window in new_windows.iter() {
+                xlib::XSelectInput(
+                    self.display,
+                    *window,
+                    xlib::StructureNotifyMask,
+                );
+            }
+        });
+
+        watched.retain(|(w, _)| windows.contains(w));
+        watched.extend(new_windows.into_iter().zip(identities));
+
+        // Relinquishing lock on the watched windows mutex.
+    }
+
+    /// Stops watching `window`, returning what it was like when we started
+    /// watching it.
+    pub fn forget(&self, window: Window) -> Option<WindowIdentity> {
+        // Getting a lock on the watched windows mutex.
+        let mut watched = self.watched.lock().unwrap();
+
+        let ix = watched.iter().position(|(w, _)| *w == window)?;
+        let (_, identity) = watched.remove(ix);
+
+        identity
+
+        // Relinquishing lock on the watched windows mutex.
+    }
+
+    /// Whether `window` (which was recently unmapped) is gone for good,
+    /// rather than just being minimized or the like.
+    pub fn is_withdrawn(&self, window: Window) -> bool {
+        discover::with_errors_ignored(self.display, || {
+            let mut attrs: xlib::XWindowAttributes =
+                unsafe { std::mem::zeroed() };
+            if unsafe {
+                xlib::XGetWindowAttributes(self.display, window, &mut attrs)
+            } == 0
+            {
+                // The window no longer exists.
+                return true;
+            }
+            if attrs.map_state == xlib::IsViewable {
+                return false;
+            }
+
+            discover::window_property(
+                self.display,
+                window,
+                c"WM_STATE",
+                self.wm_state,
+            )
+            .and_then(|state| state.first().copied())
+            .is_none_or(|state| state == WITHDRAWN_STATE)
+        })
+    }
+
+    pub fn next_event(&self) -> Option<WatchEvent> {
+        unsafe {
+            while xlib::XPending(self.display) > 0 {
+                let mut event = xlib::XEvent { pad: [0; 24] };
+                xlib::XNextEvent(self.display, &mut event);
+
+                let watched = |w: Window| {
+                    self.watched.lock().unwrap().iter().any(|(v, _)| *v == w)
+                };
+
+                match event.get_type() {
+                    xlib::DestroyNotify => {
+                        let window = event.destroy_window.window;
+                        if watched(window) {
+                            return Some(WatchEvent::Destroyed(window));
+                        } else if event.destroy_window.event == self.root {
+                            return Some(WatchEvent::WindowsChanged);
+                        }
+                    }
+                    xlib::UnmapNotify => {
+                        let window = event.unmap.window;
+                        if watched(window) {
+                            return Some(WatchEvent::Unmapped(window));
+                        }
+                    }
+                    // Override-redirect windows are things like menus &
+                    // tooltips, rather than anything that we could attach.
+                    xlib::MapNotify
+                        if event.map.event == self.root
+                            && event.map.override_redirect == xlib::False =>
+                    {
+                        return Some(WatchEvent::WindowsChanged);
+                    }
+                    xlib::PropertyNotify
+                        if event.property.window == self.root
+                            && event.property.atom == self.client_list =>
+                    {
+                        return Some(WatchEvent::WindowsChanged);
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        None
+    }
+}
+
+impl Drop for WindowWatch {
+    fn drop(&mut self) {
+        unsafe {
+            xlib::XCloseDisplay(self.display);
+        }
+    }
+}
@@ -1,9 +1,13 @@
 //! This module is where all of the gross `unsafe` stuff lives.
 
+use crate::output::{BackendKind, Click, OutputBackend};
 use gdk::{self, keys::Key};
 use glib::GString;
 use libxdo_sys;
-use std::{num::NonZeroI32, os::raw::c_char};
+use std::{
+    num::NonZeroI32,
+    os::raw::{c_char, c_int},
+};
 use x11::xlib::Window;
 
 #[derive(Debug)]
@@ -33,13 +37,16 @@ impl Xdo {
             None
         }
     }
+}
+
+impl OutputBackend for Xdo {
+    #[inline(always)]
+    fn kind(&self) -> BackendKind {
+        BackendKind::Xdo
+    }
 
     #[inline]
-    pub fn send_key_down(
-        &self,
-        window: Window,
-        key: &Key,
-    ) -> Result<(), NonZeroI32> {
+    fn send_key_down(&self, window: Window, key: &Key) -> Result<(), String> {
         if window == 0 {
             return Ok(());
         }
@@ -54,19 +61,11 @@ impl Xdo {
             )
         };
 
-        if let Some(code) = NonZeroI32::new(res) {
-            Err(code)
-        } else {
-            Ok(())
-        }
+        xdo_result(res)
     }
 
     #[inline]
-    pub fn send_key_up(
-        &self,
-        window: Window,
-        key: &Key,
-    ) -> Result<(), NonZeroI32> {
+    fn send_key_up(&self, window: Window, key: &Key) -> Result<(), String> {
         if window == 0 {
             return Ok(());
         }
@@ -81,19 +80,11 @@ impl Xdo {
             )
         };
 
-        if let Some(code) = NonZeroI32::new(res) {
-            Err(code)
-        } else {
-            Ok(())
-        }
+        xdo_result(res)
     }
 
     #[inline]
-    pub fn send_key(
-        &self,
-        window: Window,
-        key: &Key,
-    ) -> Result<(), NonZeroI32> {
+    fn send_key(&self, window: Window, key: &Key) -> Result<(), String> {
         if window == 0 {
             return Ok(());
         }
@@ -108,10 +99,38 @@ impl Xdo {
             )
         };
 
-        if let Some(code) = NonZeroI32::new(res) {
-            Err(code)
-        } else {
-            Ok(())
+        xdo_result(res)
+    }
+
+    fn send_click(&self, click: &Click) -> Result<(), String> {
+        if click.window == 0 {
+            return Ok(());
+        }
+
+        // xdo clicks wherever the pointer is, so the pointer has to be moved
+        // into place first, and then moved back to where the user left it.
+        let (mut x, mut y, mut screen): (c_int, c_int, c_int) = (0, 0, 0);
+        unsafe {
+            xdo_result(libxdo_sys::xdo_get_mouse_location(
+                self.handle,
+                &mut x,
+                &mut y,
+                &mut screen,
+            ))?;
+            xdo_result(libxdo_sys::xdo_move_mouse_relative_to_window(
+                self.handle,
+                click.window,
+                click.x,
+                click.y,
+            ))?;
+            let res = libxdo_sys::xdo_click_window(
+                self.handle,
+                click.window,
+                click.button as c_int,
+            );
+            xdo_result(libxdo_sys::xdo_move_mouse(self.handle, x, y, screen))?;
+
+            xdo_result(res)
         }
     }
 }
@@ -124,6 +143,15 @@ impl Drop for Xdo {
     }
 }
 
+#[inline(always)]
+fn xdo_result(res: i32) -> Result<(), String> {
+    if let Some(code) = NonZeroI32::new(res) {
+        Err(format!("xdo returned code {}", code))
+    } else {
+        Ok(())
+    }
+}
+
 /// Return value has the same lifetime as `gstring`.
 #[inline(always)]
 fn gstring_as_ptr(gstring: &GString) -> *const c_char {
@@ -0,0 +1,188 @@
+//! An output backend that uses nothing but plain Xlib `XSendEvent`s.
+
+use crate::output::{BackendKind, Click, OutputBackend};
+use gdk::keys::Key;
+use std::ptr;
+use x11::xlib::{self, Window};
+
+#[derive(Debug)]
+pub struct XSendEvent {
+    display: *mut xlib::Display,
+    root: Window,
+}
+
+impl XSendEvent {
+    /// Returns `None` iff we cannot open a connection to the X server.
+    pub fn new() -> Option<Self> {
+        let display = unsafe { xlib::XOpenDisplay(ptr::null()) };
+        if display.is_null() {
+            None
+        } else {
+            Some(Self {
+                display,
+                root: unsafe { xlib::XDefaultRootWindow(display) },
+            })
+        }
+    }
+
+    fn send(
+        &self,
+        window: Window,
+        key: &Key,
+        press: bool,
+    ) -> Result<(), String> {
+        if window == 0 {
+            return Ok(());
+        }
+
+        let keycode = unsafe {
+            xlib::XKeysymToKeycode(self.display, **key as xlib::KeySym)
+        };
+        if keycode == 0 {
+            return Err(format!(
+                "no keycode is mapped to keysym {:#x}",
+                **key,
+            ));
+        }
+
+        let (type_, mask) = if press {
+            (xlib::KeyPress, xlib::KeyPressMask)
+        } else {
+            (xlib::KeyRelease, xlib::KeyReleaseMask)
+        };
+        let mut event = xlib::XEvent::from(xlib::XKeyEvent {
+            type_,
+            serial: 0,
+            send_event: xlib::True,
+            display: self.display,
+            window,
+            root: self.root,
+            subwindow: 0,
+            time: xlib::CurrentTime,
+            x: 1,
+            y: 1,
+            x_root: 1,
+            y_root: 1,
+            state: 0,
+            keycode: keycode.into(),
+            same_screen: xlib::True,
+        });
+
+        let status = unsafe {
+            let status = xlib::XSendEvent(
+                self.display,
+                window,
+                xlib::True,
+                mask,
+                &mut event,
+            );
+            xlib::XFlush(self.display);
+
+            status
+        };
+
+        if status == 0 {
+            Err("XSendEvent failed to convert the event".to_owned())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn send_button(&self, click: &Click, press: bool) -> Result<(), String> {
+        let (mut x_root, mut y_root) = (0, 0);
+        let mut child = 0;
+        unsafe {
+            xlib::XTranslateCoordinates(
+                self.display,
+                click.window,
+                self.root,
+                click.x,
+                click.y,
+                &mut x_root,
+                &mut y_root,
+                &mut child,
+            );
+        }
+
+        let (type_, mask) = if press {
+            (xlib::ButtonPress, xlib::ButtonPressMask)
+        } else {
+            (xlib::ButtonRelease, xlib::ButtonReleaseMask)
+        };
+        let mut event = xlib::XEvent::from(xlib::XButtonEvent {
+            type_,
+            serial: 0,
+            send_event: xlib::True,
+            display: self.display,
+            window: click.window,
+            root: self.root,
+            subwindow: 0,
+            time: xlib::CurrentTime,
+            x: click.x,
+            y: click.y,
+            x_root,
+            y_root,
+            // While the button is held down, it shows up in the state.
+            state: if press {
+                0
+            } else {
+                xlib::Button1Mask << (click.button.saturating_sub(1))
+            },
+            button: click.button,
+            same_screen: xlib::True,
+        });
+
+        let status = unsafe {
+            let status = xlib::XSendEvent(
+                self.display,
+                click.window,
+                xlib::True,
+                mask,
+                &mut event,
+            );
+            xlib::XFlush(self.display);
+
+            status
+        };
+
+        if status == 0 {
+            Err("XSendEvent failed to convert the event".to_owned())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl OutputBackend for XSendEvent {
+    #[inline(always)]
+    fn kind(&self) -> BackendKind {
+        BackendKind::XSendEvent
+    }
+
+    #[inline]
+    fn send_key_down(&self, window: Window, key: &Key) -> Result<(), String> {
+        self.send(window, key, true)
+    }
+
+    #[inline]
+    fn send_key_up(&self, window: Window, key: &Key) -> Result<(), String> {
+        self.send(window, key, false)
+    }
+
+    fn send_click(&self, click: &Click) -> Result<(), String> {
+        if click.window == 0 {
+            return Ok(());
+        }
+
+        self.send_button(click, true)?;
+        self.send_button(click, false)
+    }
+}
+
+impl Drop for XSendEvent {
+    fn drop(&mut self) {
+        unsafe {
+            xlib::XCloseDisplay(self.display);
+        }
+    }
+}
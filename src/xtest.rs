@@ -0,0 +1,234 @@
+//! An output backend that uses the XTEST extension.  XTEST fakes input at the
+//! level of the physical devices, so events always go to whichever window has
+//! keyboard focus; we therefore move focus to the target window first, wait
+//! for the server to have handled the fake event, and then give focus back.
+//! For the same reason, global key grabs would catch our
+//! own fake keys, so this backend can't be used along with global key capture
+//! (see `State::set_capturing`).
+
+use crate::{
+    grab::with_error_trap,
+    output::{BackendKind, Click, OutputBackend},
+};
+use gdk::keys::Key;
+use std::{
+    os::raw::{c_int, c_uint},
+    ptr,
+};
+use x11::{
+    xlib::{self, Window},
+    xtest,
+};
+
+#[derive(Debug)]
+pub struct XTest {
+    display: *mut xlib::Display,
+}
+
+impl XTest {
+    /// Returns `None` iff we cannot open a connection to the X server, or the
+    /// server does not support XTEST.
+    pub fn new() -> Option<Self> {
+        let display = unsafe { xlib::XOpenDisplay(ptr::null()) };
+        if display.is_null() {
+            return None;
+        }
+
+        let (mut event_base, mut error_base, mut major, mut minor) =
+            (0, 0, 0, 0);
+        let supported = unsafe {
+            xtest::XTestQueryExtension(
+                display,
+                &mut event_base,
+                &mut error_base,
+                &mut major,
+                &mut minor,
+            )
+        };
+        if supported == 0 {
+            unsafe {
+                xlib::XCloseDisplay(display);
+            }
+
+            None
+        } else {
+            Some(Self { display })
+        }
+    }
+
+    fn send(
+        &self,
+        window: Window,
+        key: &Key,
+        press: bool,
+    ) -> Result<(), String> {
+        if window == 0 {
+            return Ok(());
+        }
+
+        let keycode = unsafe {
+            xlib::XKeysymToKeycode(self.display, **key as xlib::KeySym)
+        };
+        if keycode == 0 {
+            return Err(format!(
+                "no keycode is mapped to keysym {:#x}",
+                **key,
+            ));
+        }
+
+        let mut focused = 0;
+        let mut revert_to: c_int = 0;
+        unsafe {
+            xlib::XGetInputFocus(self.display, &mut focused, &mut revert_to);
+        }
+        if focused != window {
+            // Focusing e.g. a window that has just been closed fails, in
+            // which case the fake event would go to whoever has focus.
+            with_error_trap(self.display, || unsafe {
+                xlib::XSetInputFocus(
+                    self.display,
+                    window,
+                    xlib::RevertToParent,
+                    xlib::CurrentTime,
+                );
+            })
+            .map_err(|_| format!("failed to focus window {:#x}", window))?;
+        }
+
+        let status = unsafe {
+            let status = xtest::XTestFakeKeyEvent(
+                self.display,
+                keycode.into(),
+                if press { xlib::True } else { xlib::False },
+                xlib::CurrentTime,
+            );
+            // The fake event is only delivered once the server gets around
+            // to processing it, and it goes to whoever has focus at that
+            // point, so we have to wait for that before giving focus back.
+            xlib::XSync(self.display, xlib::False);
+
+            status
+        };
+
+        // Giving focus back (usually to the toonmux window) is best-effort,
+        // as whoever had it may be gone by now.
+        if focused != window
+            && with_error_trap(self.display, || unsafe {
+                xlib::XSetInputFocus(
+                    self.display,
+                    focused,
+                    revert_to,
+                    xlib::CurrentTime,
+                );
+            })
+            .is_err()
+        {
+            eprintln!("xtest: failed to give focus back to {:#x}", focused);
+        }
+
+        if status == 0 {
+            Err("XTestFakeKeyEvent failed".to_owned())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl OutputBackend for XTest {
+    #[inline(always)]
+    fn kind(&self) -> BackendKind {
+        BackendKind::XTest
+    }
+
+    #[inline]
+    fn send_key_down(&self, window: Window, key: &Key) -> Result<(), String> {
+        self.send(window, key, true)
+    }
+
+    #[inline]
+    fn send_key_up(&self, window: Window, key: &Key) -> Result<(), String> {
+        self.send(window, key, false)
+    }
+
+    /// Like keys, fake clicks go wherever the pointer is, so the pointer is
+    /// warped into place first, and then warped back to where the user left
+    /// it.
+    fn send_click(&self, click: &Click) -> Result<(), String> {
+        if click.window == 0 {
+            return Ok(());
+        }
+
+        let status = unsafe {
+            let root = xlib::XDefaultRootWindow(self.display);
+            let (mut root_ret, mut child) = (0, 0);
+            let (mut root_x, mut root_y): (c_int, c_int) = (0, 0);
+            let (mut win_x, mut win_y): (c_int, c_int) = (0, 0);
+            let mut mask: c_uint = 0;
+            xlib::XQueryPointer(
+                self.display,
+                root,
+                &mut root_ret,
+                &mut child,
+                &mut root_x,
+                &mut root_y,
+                &mut win_x,
+                &mut win_y,
+                &mut mask,
+            );
+
+            xlib::XWarpPointer(
+                self.display,
+                0,
+                click.window,
+                0,
+                0,
+                0,
+                0,
+                click.x,
+                click.y,
+            );
+            let status = xtest::XTestFakeButtonEvent(
+                self.display,
+                click.button,
+                xlib::True,
+                xlib::CurrentTime,
+            ) & xtest::XTestFakeButtonEvent(
+                self.display,
+                click.button,
+                xlib::False,
+                xlib::CurrentTime,
+            );
+            // As with keys, the clicks have to have been delivered before
+            // the pointer moves away.
+            xlib::XSync(self.display, xlib::False);
+            xlib::XWarpPointer(
+                self.display,
+                0,
+                root,
+                0,
+                0,
+                0,
+                0,
+                root_x,
+                root_y,
+            );
+            xlib::XFlush(self.display);
+
+            status
+        };
+
+        if status == 0 {
+            Err("XTestFakeButtonEvent failed".to_owned())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Drop for XTest {
+    fn drop(&mut self) {
+        unsafe {
+            xlib::XCloseDisplay(self.display);
+        }
+    }
+}